@@ -0,0 +1,42 @@
+use std::path::PathBuf;
+use clap::Parser;
+use tokio::time::Duration;
+use finnhub_ws::mock::{load_script, MockServer};
+use finnhub_ws::recorder::read_recording;
+use finnhub_ws::replay::ReplaySpeed;
+
+/// A mock finnhub server which replays a script of trade, ping and error messages,
+/// or a recorded session, to every client that subscribes to it.
+#[derive(Parser, Debug)]
+#[clap(name = "mock_server", version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
+struct MockOptions {
+    /// The address the server should listen to
+    #[clap(short, long, default_value = "127.0.0.1:8080")]
+    address: String,
+    /// The file containing one finnhub message per line
+    #[clap(forbid_empty_values = true, required_unless_present = "recording", conflicts_with = "recording", short, long)]
+    script: Option<String>,
+    /// The milliseconds to wait between two consecutive messages of the script
+    #[clap(short, long, default_value = "100")]
+    interval: u64,
+    /// The session recorded with --record to replay byte for byte instead of a script
+    #[clap(short, long)]
+    recording: Option<PathBuf>,
+    /// How fast the recorded session gets replayed, a multiple of the speed the frames got
+    /// received at such as 1x or 10x, or max to send them all at once
+    #[clap(long, default_value = "1x")]
+    speed: ReplaySpeed,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let opts = MockOptions::parse();
+    let server = match (&opts.recording, &opts.script) {
+        (Some(recording), _) => MockServer::bind_recording(&opts.address, &read_recording(recording)?, opts.speed).await?,
+        (None, Some(script)) => MockServer::bind(&opts.address, load_script(script)?, Duration::from_millis(opts.interval)).await?,
+        (None, None) => unreachable!("clap requires a script unless a recording is given"),
+    };
+    println!("Mock server listening on {}", server.url());
+    server.wait().await;
+    Ok(())
+}
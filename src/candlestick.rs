@@ -4,21 +4,34 @@
 //! This contains the necessary structure and functions to manage
 //! all the needs the program has with regard to candlestick
 //! information for a stock
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::File;
-use chrono::{DateTime, SubsecRound, Utc};
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use crate::clock::{Clock, SystemClock};
+use crate::error;
+use crate::interval::Interval;
 use crate::{RollingData};
 
+/// The number of intervals after being written during which a candlestick
+/// can still be corrected with late trades
+const CORRECTABLE_INTERVALS: i32 = 60;
+
 /// `Candlestick` is a struct containing the necessary information
-/// to represent a stock candlestick graph entry.
+/// to represent a stock candlestick graph entry. The candlestick files
+/// have no headers, and the columns after `Transactions` got added over
+/// time, so the rows written before them default these to false or zero.
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Candlestick{
     /// stock_symbol: represents the stock symbol
     #[serde(rename = "Symbol")]
     pub stock_symbol: String,
     #[serde(rename = "MinuteOfDay")]
-    /// minute_of_hour: represents the minute for which
-    /// the candlestick is being calculated
+    /// minute_of_hour: represents the start of the interval for
+    /// which the candlestick is being calculated, according to
+    /// the exchange timestamp of the trades
     pub minute_of_hour: DateTime<Utc>,
     #[serde(rename = "OpenPrice")]
     /// open_price: represents the first entry available
@@ -40,11 +53,44 @@ pub struct Candlestick{
     /// total_transactions: represents the number of
     /// transactions made for the given stock at the
     /// given minute of hour
-    pub total_transactions: u64
+    pub total_transactions: u64,
+    #[serde(rename = "Partial", default)]
+    /// partial: represents whether the candlestick covers only
+    /// part of the minute, as the program shut down before
+    /// the minute was over
+    pub partial: bool,
+    #[serde(rename = "Volume", default)]
+    /// volume: represents the number of stocks traded
+    /// for the given stock at the given minute of hour
+    pub volume: f64,
+    #[serde(rename = "Vwap", default)]
+    /// vwap: represents the average price weighted by the
+    /// volume of each transaction. It is zero if none of the
+    /// transactions had a volume
+    pub vwap: f64,
+    #[serde(rename = "Turnover", default)]
+    /// turnover: represents the total value traded, which is
+    /// the sum of the price times the volume of each transaction
+    pub turnover: f64,
+    #[serde(rename = "Corrected", default)]
+    /// corrected: represents whether the candlestick got written
+    /// again, replacing the one written before if any, as trades
+    /// belonging to it arrived after the allowed lateness
+    pub corrected: bool,
+    #[serde(rename = "LateTrades", default)]
+    /// late_trades: represents the number of trades which arrived
+    /// after the candlestick they belong to was written and were left
+    /// out of it, since the previous candlestick of the stock
+    pub late_trades: u64,
+    #[serde(rename = "News", default)]
+    /// news: represents whether news related to the stock got
+    /// received in the interval. Candlestick files written before
+    /// the news were tracked don't have this column, so it defaults to false
+    pub news: bool,
 }
 
-impl Candlestick{
-    pub fn default() -> Self {
+impl Default for Candlestick {
+    fn default() -> Self {
         Candlestick {
             open_price: 0.0,
             close_price: 0.0,
@@ -52,84 +98,557 @@ impl Candlestick{
             lowest_price: 0.0,
             total_transactions: 0,
             stock_symbol: "".parse().unwrap(),
-            minute_of_hour: Utc::now(),
+            minute_of_hour: SystemClock.now(),
+            partial: false,
+            volume: 0.0,
+            vwap: 0.0,
+            turnover: 0.0,
+            corrected: false,
+            late_trades: 0,
+            news: false,
+        }
+    }
+}
+
+impl Candlestick{
+    /// `write_to_file`: serializes the struct instance and writes it the given file
+    pub fn write_to_file(&self, file: &File) -> error::Result<()> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.serialize(self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `LatePolicy` decides what happens to the trades which arrive after the candlestick
+/// they belong to was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LatePolicy {
+    /// The trades get left out and counted in the LateTrades column of the next candlestick
+    #[default]
+    Count,
+    /// The trades get merged into their candlestick, which gets written again marked as
+    /// corrected. Candlesticks older than 60 intervals can't be corrected anymore, so the
+    /// trades belonging to them get counted instead.
+    Merge,
+}
+
+impl FromStr for LatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(LatePolicy::Count),
+            "merge" => Ok(LatePolicy::Merge),
+            _ => Err(format!("invalid late trade policy {}, expected count or merge", s)),
+        }
+    }
+}
+
+impl fmt::Display for LatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LatePolicy::Count => write!(f, "count"),
+            LatePolicy::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+/// `Bucket` holds the running state of the candlestick of a single interval
+#[derive(Debug, Clone)]
+struct Bucket {
+    /// open: the price of the earliest trade of the interval
+    open: f64,
+    /// open_time: the exchange timestamp of the earliest trade of the interval
+    open_time: DateTime<Utc>,
+    /// close: the price of the latest trade of the interval
+    close: f64,
+    /// close_time: the exchange timestamp of the latest trade of the interval
+    close_time: DateTime<Utc>,
+    /// high: the maximum price of the interval so far
+    high: f64,
+    /// low: the minimum price of the interval so far
+    low: f64,
+    /// count: the number of trades of the interval so far
+    count: u64,
+    /// volume: the number of stocks traded in the interval so far
+    volume: f64,
+    /// turnover: the value traded in the interval so far
+    turnover: f64,
+}
+
+impl Bucket {
+    fn new(trade: &RollingData) -> Self {
+        Bucket {
+            open: trade.price,
+            open_time: trade.timestamp,
+            close: trade.price,
+            close_time: trade.timestamp,
+            high: trade.price,
+            low: trade.price,
+            count: 1,
+            volume: trade.volume,
+            turnover: trade.price * trade.volume,
+        }
+    }
+
+    /// Adds a trade to the interval. Trades may arrive out of order, so the open and close
+    /// prices are the ones of the trades with the earliest and latest exchange timestamps.
+    fn update(&mut self, trade: &RollingData) {
+        if trade.timestamp < self.open_time {
+            self.open = trade.price;
+            self.open_time = trade.timestamp;
+        }
+        if trade.timestamp >= self.close_time {
+            self.close = trade.price;
+            self.close_time = trade.timestamp;
         }
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.count += 1;
+        self.volume += trade.volume;
+        self.turnover += trade.price * trade.volume;
     }
 
-    fn new(open: f64, close: f64, high: f64, low: f64, count: u64, minute: DateTime<Utc>, symbol: String) -> Self {
+    fn to_candlestick(&self, start: DateTime<Utc>, symbol: &str) -> Candlestick {
         Candlestick {
-            open_price: open,
-            close_price: close,
-            highest_price: high,
-            lowest_price: low,
-            total_transactions: count,
-            stock_symbol: symbol,
-            minute_of_hour: minute,
+            stock_symbol: symbol.to_string(),
+            minute_of_hour: start,
+            open_price: self.open,
+            close_price: self.close,
+            highest_price: self.high,
+            lowest_price: self.low,
+            total_transactions: self.count,
+            partial: false,
+            volume: self.volume,
+            vwap: if self.volume > 0.0 { self.turnover / self.volume } else { 0.0 },
+            turnover: self.turnover,
+            corrected: false,
+            late_trades: 0,
+            news: false,
         }
     }
+}
 
-    /// `write_to_file`: serializes the struct instance and writes it the given file
-    pub fn write_to_file(&self, file: &File){
-        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
-        writer.serialize(self).unwrap();
-        writer.flush().unwrap();
+/// `CandlestickAggregator` builds the candlesticks of a stock for one interval incrementally.
+/// Trades get bucketed by the interval their exchange timestamp falls in, rather than by the
+/// time they arrived. It gets updated on every trade in constant time and emits the candlesticks
+/// of the intervals which ended when it gets closed, instead of keeping all the trades around
+/// and going over them again. The trades which arrive after their candlestick was emitted are
+/// handled according to the `LatePolicy` of the aggregator. The intervals news got received
+/// in get their candlesticks flagged.
+///
+/// # Example
+/// ```
+/// use chrono::{TimeZone, Utc};
+/// use finnhub_ws::candlestick::{CandlestickAggregator, LatePolicy};
+/// use finnhub_ws::RollingData;
+/// let mut aggregator = CandlestickAggregator::new("APPL", "1m".parse().unwrap(), LatePolicy::Count);
+/// aggregator.update(&RollingData{
+///     price: 172.5,
+///     symbol: "APPL".parse().unwrap(),
+///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 8, 0, 794),
+///     volume: 0.5,
+///     conditions: vec![]
+/// });
+/// let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+/// assert_eq!(cs[0].minute_of_hour, Utc.ymd(2022, 7, 21).and_hms(22, 7, 0));
+/// assert_eq!(cs[0].total_transactions, 1);
+/// assert_eq!(cs[0].vwap, 172.5);
+/// assert!(aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 9, 0)).is_empty());
+/// ```
+#[derive(Debug)]
+pub struct CandlestickAggregator {
+    /// symbol: the stock symbol of the candlesticks
+    symbol: String,
+    /// interval: the interval each candlestick covers
+    interval: Interval,
+    /// policy: what happens to the trades arriving after their candlestick was emitted
+    policy: LatePolicy,
+    /// open: the intervals which weren't emitted yet, by their start
+    open: BTreeMap<DateTime<Utc>, Bucket>,
+    /// closed: the emitted intervals which can still be corrected, by their start
+    closed: BTreeMap<DateTime<Utc>, Bucket>,
+    /// corrected: the start of the closed intervals which got late trades merged
+    /// into them since they were last emitted
+    corrected: BTreeSet<DateTime<Utc>>,
+    /// closed_until: the intervals starting before this were emitted already
+    closed_until: Option<DateTime<Utc>>,
+    /// late: the number of late trades left out since the last candlestick was emitted
+    late: u64,
+    /// news: the start of the intervals news got received in, which weren't emitted yet
+    /// or can still be corrected
+    news: BTreeSet<DateTime<Utc>>,
+}
+
+impl CandlestickAggregator {
+    /// Given the stock symbol, the interval of the candlesticks and what to do with late trades,
+    /// creates and returns an aggregator without any trades
+    pub fn new(symbol: &str, interval: Interval, policy: LatePolicy) -> Self {
+        CandlestickAggregator {
+            symbol: symbol.to_string(),
+            interval,
+            policy,
+            open: BTreeMap::new(),
+            closed: BTreeMap::new(),
+            corrected: BTreeSet::new(),
+            closed_until: None,
+            late: 0,
+            news: BTreeSet::new(),
+        }
+    }
+
+    /// Adds a trade to the interval its exchange timestamp falls in
+    pub fn update(&mut self, trade: &RollingData) {
+        let start = self.interval.start_of(trade.timestamp);
+        let closed_until = match self.closed_until {
+            Some(closed_until) if start < closed_until => closed_until,
+            _ => {
+                self.open.entry(start)
+                    .and_modify(|bucket| bucket.update(trade))
+                    .or_insert_with(|| Bucket::new(trade));
+                return;
+            }
+        };
+        let correctable = start >= closed_until - self.interval.duration() * CORRECTABLE_INTERVALS;
+        if self.policy == LatePolicy::Merge && correctable {
+            self.closed.entry(start)
+                .and_modify(|bucket| bucket.update(trade))
+                .or_insert_with(|| Bucket::new(trade));
+            self.corrected.insert(start);
+        } else {
+            self.late += 1;
+        }
+    }
+
+    /// Flags the candlestick of the interval the given time news arrived at falls in.
+    /// The candlesticks which were emitted already only carry the flag if they get corrected.
+    pub fn mark_news(&mut self, received: DateTime<Utc>) {
+        self.news.insert(self.interval.start_of(received));
+    }
+
+    /// Returns the candlesticks of the intervals which ended at or before `end`, labeled with
+    /// the start of their interval, preceded by the corrected candlesticks of the ones emitted
+    /// before. Intervals without trades are left out. Late trades arriving afterwards for the
+    /// returned intervals are handled according to the `LatePolicy` of the aggregator.
+    pub fn close(&mut self, end: DateTime<Utc>) -> Vec<Candlestick> {
+        let boundary = self.interval.start_of(end);
+        let mut candlesticks = self.take_corrected();
+        let in_progress = self.open.split_off(&boundary);
+        for (start, bucket) in std::mem::replace(&mut self.open, in_progress) {
+            candlesticks.push(self.candlestick(start, &bucket));
+            if self.policy == LatePolicy::Merge {
+                self.closed.insert(start, bucket);
+            }
+        }
+        if !matches!(self.closed_until, Some(closed_until) if closed_until >= boundary) {
+            self.closed_until = Some(boundary);
+        }
+        let oldest = boundary - self.interval.duration() * CORRECTABLE_INTERVALS;
+        self.closed = self.closed.split_off(&oldest);
+        self.news = self.news.split_off(&oldest);
+        self.attach_late(&mut candlesticks);
+        candlesticks
+    }
+
+    /// Returns the candlesticks of every interval which has trades, like `close` does for the
+    /// ones which ended at or before `now`, and marks the ones still in progress as partial.
+    /// This is meant to be called on shutdown, as no further trades are expected.
+    pub fn flush(&mut self, now: DateTime<Utc>) -> Vec<Candlestick> {
+        let mut candlesticks = self.close(now);
+        for (start, bucket) in std::mem::take(&mut self.open) {
+            let mut cs = self.candlestick(start, &bucket);
+            cs.partial = true;
+            candlesticks.push(cs);
+        }
+        self.attach_late(&mut candlesticks);
+        candlesticks
+    }
+
+    /// Returns the candlesticks of the closed intervals which got late trades merged into them
+    fn take_corrected(&mut self) -> Vec<Candlestick> {
+        std::mem::take(&mut self.corrected).into_iter()
+            .filter_map(|start| self.closed.get(&start).map(|bucket| (start, bucket)))
+            .map(|(start, bucket)| {
+                let mut cs = self.candlestick(start, bucket);
+                cs.corrected = true;
+                cs
+            })
+            .collect()
+    }
+
+    /// Returns the candlestick of the interval starting at `start`, flagged if news got received in it
+    fn candlestick(&self, start: DateTime<Utc>, bucket: &Bucket) -> Candlestick {
+        let mut cs = bucket.to_candlestick(start, &self.symbol);
+        cs.news = self.news.contains(&start);
+        cs
+    }
+
+    /// Adds the late trades left out so far to the first of the candlesticks, if there is any
+    fn attach_late(&mut self, candlesticks: &mut [Candlestick]) {
+        if let Some(cs) = candlesticks.first_mut() {
+            cs.late_trades += self.late;
+            self.late = 0;
+        }
     }
 }
 
-/// `calculate_candlestick` given a reference to a slice of RollingData,
+/// `calculate_candlestick` given a reference to a slice of RollingData belonging to the same interval,
 /// if the slice is not empty, it calculates the candlestick by assigning the opening price
-/// to the first element of the slice, the closing price to the last, and by comparing the
-/// elements, it calculates the min and max values. If the slice is empty, None is returned.
+/// to the element with the earliest exchange timestamp, the closing price to the one with the latest,
+/// and by comparing the elements, it calculates the min and max values. The candlestick is labeled
+/// with the start of the interval the earliest element falls in. If the slice is empty, None is returned.
+/// This is the reference implementation of `CandlestickAggregator`.
 ///
 /// # Arguments
 ///
-/// `data` - a slice of RollingData for which the mean data calculation should be made
+/// `data` - a slice of RollingData for which the candlestick calculation should be made
+/// `interval` - the interval the candlestick covers
 ///
 /// # Example
 /// ```
 /// use finnhub_ws::candlestick::calculate_candlestick;
 /// use finnhub_ws::RollingData;
 /// let items: Vec<RollingData> = Vec::new();
-/// let rolling = calculate_candlestick(&items);
+/// let rolling = calculate_candlestick(&items, "1m".parse().unwrap());
 /// assert_eq!(rolling, None)
 /// ```
 ///
 /// ```
 /// use finnhub_ws::RollingData;
-/// use chrono::{SubsecRound, TimeZone, Utc};
+/// use chrono::{TimeZone, Utc};
 /// use finnhub_ws::candlestick::{calculate_candlestick, Candlestick};
 /// let mut items: Vec<RollingData> = Vec::new();
 /// let r1 = RollingData{
 ///     price: 172.5,
 ///     symbol: "APPL".parse().unwrap(),
 ///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
-///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794)
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
+///     volume: 0.5,
+///     conditions: vec![]
 /// };
 /// let r2 = RollingData{
 ///     price: 173.5,
 ///     symbol: "APPL".parse().unwrap(),
 ///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197),
-///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 798)
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 798),
+///     volume: 0.5,
+///     conditions: vec![]
 /// };
 /// items.push(r1);
 /// items.push(r2);
-/// let rolling = calculate_candlestick(&items);
+/// let rolling = calculate_candlestick(&items, "1m".parse().unwrap());
 /// assert_eq!(rolling, Some(Candlestick{
 ///     stock_symbol: "APPL".parse().unwrap(),
 ///     total_transactions: 2,
-///     close_price: 173.5,
-///     open_price: 172.5,
+///     close_price: 172.5,
+///     open_price: 173.5,
 ///     highest_price: 173.5,
 ///     lowest_price: 172.5,
-///     minute_of_hour: Utc::now().round_subsecs(0)
+///     minute_of_hour: Utc.ymd(2022, 7, 21).and_hms(22, 7, 0),
+///     partial: false,
+///     volume: 1.0,
+///     vwap: 173.0,
+///     turnover: 173.0,
+///     corrected: false,
+///     late_trades: 0,
+///     news: false,
 ///  }));
 /// ```
-pub fn calculate_candlestick(data: &[RollingData]) -> Option<Candlestick> {
+pub fn calculate_candlestick(data: &[RollingData], interval: Interval) -> Option<Candlestick> {
     if !data.is_empty(){
         let max_price = data.iter().map(|x| { x.price }).max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
         let min_price = data.iter().map(|x| { x.price }).min_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
-        return Some(Candlestick::new(data[0].price, data[data.len()-1].price, max_price, min_price, data.len() as u64, Utc::now().round_subsecs(0), data[0].symbol.parse().unwrap()));
+        // min_by_key returns the first of the earliest elements and max_by_key the last of the latest
+        let first = data.iter().min_by_key(|x| x.timestamp).unwrap();
+        let last = data.iter().max_by_key(|x| x.timestamp).unwrap();
+        let volume = data.iter().fold(0.0, |volume, i| volume + i.volume);
+        let turnover = data.iter().fold(0.0, |turnover, i| turnover + i.price * i.volume);
+        return Some(Candlestick {
+            stock_symbol: data[0].symbol.parse().unwrap(),
+            minute_of_hour: interval.start_of(first.timestamp),
+            open_price: first.price,
+            close_price: last.price,
+            highest_price: max_price,
+            lowest_price: min_price,
+            total_transactions: data.len() as u64,
+            partial: false,
+            volume,
+            vwap: if volume > 0.0 { turnover / volume } else { 0.0 },
+            turnover,
+            corrected: false,
+            late_trades: 0,
+            news: false,
+        });
     }
     None
 }
 
+
+
+#[cfg(test)]
+mod candlestick_test {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::candlestick::{calculate_candlestick, Candlestick, CandlestickAggregator, LatePolicy};
+    use crate::interval::Interval;
+    use crate::RollingData;
+
+    fn minute() -> Interval {
+        "1m".parse().unwrap()
+    }
+
+    fn trades() -> Vec<RollingData> {
+        [23061.05, 23060.16, 23061.04, 23060.88, 23061.05, 23060.89, 23058.59, 23061.79].iter()
+            .enumerate()
+            .map(|(i, price)| RollingData {
+                symbol: "BINANCE:BTCUSDT".parse().unwrap(),
+                price: *price,
+                timestamp: Utc.ymd(2022, 7, 21).and_hms(22, 7, 38) + Duration::seconds(i as i64),
+                // arriving in the next minute, which shouldn't matter for the bucketing
+                write_timestamp: Utc.ymd(2022, 7, 21).and_hms(22, 7, 58) + Duration::seconds(i as i64),
+                // trade sizes varying by orders of magnitude, like they do for crypto symbols
+                volume: 10f64.powi(i as i32 % 4 - 2),
+                conditions: vec![],
+            })
+            .collect()
+    }
+
+    fn trade_at(price: f64, minute: u32, second: u32) -> RollingData {
+        RollingData {
+            symbol: "BINANCE:BTCUSDT".parse().unwrap(),
+            price,
+            timestamp: Utc.ymd(2022, 7, 21).and_hms(22, minute, second),
+            write_timestamp: Utc.ymd(2022, 7, 21).and_hms(22, minute, second),
+            volume: 1.0,
+            conditions: vec![],
+        }
+    }
+
+    #[test]
+    fn aggregator_should_match_the_reference_implementation() {
+        let data = trades();
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        data.iter().for_each(|t| aggregator.update(t));
+        let expected = calculate_candlestick(&data, minute()).unwrap();
+        assert_eq!(expected.minute_of_hour, Utc.ymd(2022, 7, 21).and_hms(22, 7, 0));
+        assert_eq!(aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0)), vec![expected]);
+    }
+
+    #[test]
+    fn aggregator_should_order_trades_by_their_exchange_timestamp() {
+        let data = trades();
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        data.iter().rev().for_each(|t| aggregator.update(t));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+        assert_eq!(cs[0].open_price, data[0].price);
+        assert_eq!(cs[0].close_price, data[data.len() - 1].price);
+    }
+
+    #[test]
+    fn aggregator_should_only_emit_the_intervals_which_ended() {
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        aggregator.update(&trade_at(1.0, 7, 10));
+        aggregator.update(&trade_at(2.0, 8, 10));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 30));
+        assert_eq!(cs.len(), 1);
+        assert_eq!(cs[0].open_price, 1.0);
+        let cs = aggregator.flush(Utc.ymd(2022, 7, 21).and_hms(22, 8, 40));
+        assert_eq!(cs.len(), 1);
+        assert_eq!(cs[0].open_price, 2.0);
+        assert!(cs[0].partial);
+    }
+
+    #[test]
+    fn given_the_count_policy_late_trades_should_be_counted_in_the_next_candlestick() {
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        aggregator.update(&trade_at(1.0, 7, 10));
+        aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+        aggregator.update(&trade_at(5.0, 7, 50));
+        aggregator.update(&trade_at(2.0, 8, 10));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 9, 0));
+        assert_eq!(cs.len(), 1);
+        assert_eq!(cs[0].total_transactions, 1);
+        assert_eq!(cs[0].late_trades, 1);
+        assert!(!cs[0].corrected);
+    }
+
+    #[test]
+    fn given_the_merge_policy_late_trades_should_correct_their_candlestick() {
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Merge);
+        aggregator.update(&trade_at(1.0, 7, 10));
+        aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+        aggregator.update(&trade_at(5.0, 7, 50));
+        aggregator.update(&trade_at(2.0, 8, 10));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 9, 0));
+        assert_eq!(cs.len(), 2);
+        assert!(cs[0].corrected);
+        assert_eq!(cs[0].minute_of_hour, Utc.ymd(2022, 7, 21).and_hms(22, 7, 0));
+        assert_eq!(cs[0].total_transactions, 2);
+        assert_eq!(cs[0].close_price, 5.0);
+        assert_eq!(cs[0].late_trades, 0);
+        assert!(!cs[1].corrected);
+        assert_eq!(cs[1].minute_of_hour, Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+    }
+
+    #[test]
+    fn given_the_merge_policy_too_old_trades_should_be_counted() {
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Merge);
+        aggregator.update(&trade_at(2.0, 30, 10));
+        aggregator.close(Utc.ymd(2022, 7, 21).and_hms(23, 31, 0));
+        aggregator.update(&trade_at(1.0, 7, 10));
+        aggregator.update(&trade_at(3.0, 59, 10));
+        let cs = aggregator.flush(Utc.ymd(2022, 7, 21).and_hms(23, 31, 10));
+        assert_eq!(cs.len(), 1);
+        assert!(cs[0].corrected);
+        assert_eq!(cs[0].late_trades, 1);
+    }
+
+    #[test]
+    fn candlesticks_of_the_intervals_news_got_received_in_should_be_flagged() {
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        // news received at 22:08:45
+        aggregator.mark_news(Utc.ymd(2022, 7, 21).and_hms(22, 8, 45));
+        aggregator.update(&trade_at(1.0, 7, 10));
+        aggregator.update(&trade_at(2.0, 8, 10));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 9, 0));
+        assert_eq!(cs.len(), 2);
+        assert!(!cs[0].news);
+        assert!(cs[1].news);
+        // the flag doesn't carry over to the next interval
+        aggregator.update(&trade_at(3.0, 9, 10));
+        assert!(!aggregator.flush(Utc.ymd(2022, 7, 21).and_hms(22, 9, 20))[0].news);
+    }
+
+    #[test]
+    fn given_trades_without_volume_vwap_should_be_zero() {
+        let mut data = trades();
+        data.iter_mut().for_each(|t| t.volume = 0.0);
+        let cs = calculate_candlestick(&data, minute()).unwrap();
+        assert_eq!(cs.volume, 0.0);
+        assert_eq!(cs.vwap, 0.0);
+    }
+
+    #[test]
+    fn aggregator_should_weight_the_vwap_by_volume() {
+        let data = trades();
+        let mut aggregator = CandlestickAggregator::new("BINANCE:BTCUSDT", minute(), LatePolicy::Count);
+        data[..2].iter().for_each(|t| aggregator.update(t));
+        let cs = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0)).remove(0);
+        // 0.01 at 23061.05 and 0.1 at 23060.16
+        assert!((cs.volume - 0.11).abs() < 1e-9);
+        assert!((cs.turnover - (23061.05 * 0.01 + 23060.16 * 0.1)).abs() < 1e-6);
+        assert!((cs.vwap - cs.turnover / cs.volume).abs() < 1e-6);
+        assert!(cs.vwap < (23061.05 + 23060.16) / 2.0);
+    }
+
+    #[test]
+    fn given_a_row_written_before_the_later_columns_it_should_default_them() {
+        let row = "BINANCE:BTCUSDT,2022-07-21T22:07:00Z,23061.05,23060.88,23061.05,23060.16,4\n";
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(row.as_bytes());
+        let cs: Candlestick = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(cs.total_transactions, 4);
+        assert!(!cs.partial && !cs.corrected && !cs.news);
+        assert_eq!((cs.volume, cs.vwap, cs.turnover, cs.late_trades), (0.0, 0.0, 0.0, 0));
+    }
+}
@@ -1,12 +1,265 @@
-use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+use url::Url;
+use crate::candlestick::LatePolicy;
+use crate::clock::SystemClock;
+use crate::condition::ConditionFilter;
+use crate::interval::Interval;
+use crate::journal::StorageFormat;
+use crate::replay::ReplaySpeed;
+use crate::rotation::RetentionPolicy;
+use crate::stock_handle::{MapperOptions, DATA_DIR};
+use crate::store::FsyncPolicy;
 
 #[derive(Parser, Debug)]
 #[clap(name = env!("CARGO_PKG_NAME"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
+#[clap(subcommand_negates_reqs = true)]
 pub struct CLIOptions {
     #[clap(short, long)]
     pub verbose: bool,
+    /// The finnhub token. It is only optional when running one of the commands
     #[clap(forbid_empty_values = true, required = true, short, long)]
-    pub token: String,
+    pub token: Option<String>,
     #[clap(forbid_empty_values = true, required = true, short, long)]
     pub stocks: Vec<String>,
+    /// The websocket endpoint to connect to. Plain ws:// endpoints are
+    /// allowed so that a local mock server can be used instead of finnhub
+    #[clap(forbid_empty_values = true, long, default_value = "wss://ws.finnhub.io")]
+    pub endpoint: String,
+    /// The comma separated intervals to calculate candlesticks for, such as 1m,5m,1h.
+    /// Each interval gets written to its own data/candlestick/{interval} directory
+    #[clap(long, value_delimiter = ',', default_value = "1m")]
+    pub candle_intervals: Vec<Interval>,
+    /// The comma separated lengths of the windows to calculate the mean price over,
+    /// such as 5m,15m,60m. All of them get written to data/mean with a window column,
+    /// unless only the default 15m is given
+    #[clap(long, value_delimiter = ',', default_value = "15m")]
+    pub mean_windows: Vec<Interval>,
+    /// The comma separated trade condition codes of which a trade should have at least one
+    /// to count toward the candlestick and mean data. Every trade counts if none are given
+    #[clap(long, value_delimiter = ',')]
+    pub include_conditions: Vec<String>,
+    /// The comma separated trade condition codes which keep a trade from counting toward
+    /// the candlestick and mean data, such as odd-lot trades. Every trade is still written
+    /// to the rolling file
+    #[clap(long, value_delimiter = ',')]
+    pub exclude_conditions: Vec<String>,
+    /// How many seconds to wait after the end of a minute for its trades to arrive before
+    /// writing the candlesticks ending at it. Trades are bucketed by their exchange timestamp
+    #[clap(long, default_value = "5")]
+    pub allowed_lateness: u32,
+    /// What happens to the trades arriving after their candlestick was written, either count
+    /// them in the LateTrades column or merge them into a corrected candlestick
+    #[clap(long, default_value = "count")]
+    pub late_trades: LatePolicy,
+    /// The format the rolling data get written in, either csv, a compact bincode journal
+    /// which the convert command turns back into csv, or sqlite, which writes the trades,
+    /// candlesticks and mean data to the tables of data/finnhub.sqlite
+    #[clap(long, default_value = "csv")]
+    pub storage_format: StorageFormat,
+    /// The size in megabytes after which the rolling, candlestick and mean files get rotated
+    /// to a new numbered file. They are always rotated at UTC midnight to a new date directory
+    #[clap(long)]
+    pub max_file_size_mb: Option<u64>,
+    /// How many days to keep the date directories of the data files for before deleting them
+    #[clap(long)]
+    pub retain_days: Option<u32>,
+    /// How many days after which the files of the date directories get compressed with gzip
+    #[clap(long)]
+    pub compress_after_days: Option<u32>,
+    /// How many milliseconds to buffer the trades for before writing them. By default, the
+    /// trades of each message get written at once. The processing latency then only covers
+    /// the time until the trades got buffered
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_interval_ms: Option<u64>,
+    /// How often the data written get synced to the disk, either never, leaving it to the
+    /// operating system, every --fsync-interval-ms milliseconds, or always, on every write
+    #[clap(long, default_value = "never")]
+    pub fsync: FsyncPolicy,
+    /// How many milliseconds to sync the data to the disk after, with --fsync interval
+    #[clap(long, default_value = "1000", value_parser = clap::value_parser!(u64).range(1..))]
+    pub fsync_interval_ms: u64,
+    /// Subscribes to the news of the stocks besides their trades. The headlines get written to
+    /// data/news/{symbol}.csv and the candlesticks of the intervals they got received in get flagged
+    #[clap(long)]
+    pub news: bool,
+    /// Path of the Unix socket to listen for the commands adding and removing stocks while
+    /// the program runs, such as `subscribe AAPL`, `unsubscribe AAPL` and `list`
+    #[clap(long)]
+    pub control_socket: Option<PathBuf>,
+    /// How many seconds the latencies of the trades get summarized over. The summary of each
+    /// period gets written to data/latency/{symbol}.csv
+    #[clap(long, default_value = "60")]
+    pub latency_interval_secs: u64,
+    /// Path of the file to record every raw frame read from the websocket to, along with the
+    /// time it got received, so that the session can be played back by the mock server
+    #[clap(long)]
+    pub record: Option<PathBuf>,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// `Command` holds the tools which run instead of tracking the stocks
+///
+/// # Example
+/// ```
+/// use clap::Parser;
+/// use finnhub_ws::cli::cmd::{CLIOptions, Command};
+/// let opts = CLIOptions::parse_from(["finnhub_ws", "convert", "data/rolling/AAPL.bin"]);
+/// assert_eq!(opts.command, Some(Command::Convert { input: "data/rolling/AAPL.bin".into(), output: None }));
+/// let opts = CLIOptions::parse_from(["finnhub_ws", "--candle-intervals", "1m,5m", "replay", "--speed", "10x"]);
+/// assert_eq!(opts.command, Some(Command::Replay { input: "data/rolling".into(), output: "replay".into(), speed: "10x".parse().unwrap() }));
+/// ```
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Converts a bincode journal to the csv layout of the rolling files
+    Convert {
+        /// The journal to convert, such as data/rolling/AAPL.bin
+        input: PathBuf,
+        /// The csv file to write. Defaults to the path of the journal followed by .csv, so
+        /// that the csv rolling file of the same stock doesn't get overwritten
+        output: Option<PathBuf>,
+    },
+    /// Reports on the data already written
+    Report {
+        #[clap(subcommand)]
+        report: Report,
+    },
+    /// Replays the trades of the rolling files through the pipeline, writing the rolling,
+    /// candlestick and mean data again under a separate directory. The stocks, the intervals
+    /// and the other settings of the pipeline are the ones given before the command
+    Replay {
+        /// The rolling file to replay, or the directory of the rolling files, which gets
+        /// walked recursively
+        #[clap(default_value = "data/rolling")]
+        input: PathBuf,
+        /// The directory the data of the replay get written to, laid out like data
+        #[clap(long, default_value = "replay")]
+        output: PathBuf,
+        /// How fast the trades get replayed, a multiple of the speed they got written at
+        /// such as 1x or 10x, or max to replay them as fast as they can be processed
+        #[clap(long, default_value = "max")]
+        speed: ReplaySpeed,
+    },
+}
+
+/// `Report` holds the reports which can be run on the data already written
+///
+/// # Example
+/// ```
+/// use clap::Parser;
+/// use finnhub_ws::cli::cmd::{CLIOptions, Command, Report};
+/// let opts = CLIOptions::parse_from(["finnhub_ws", "report", "latency"]);
+/// assert_eq!(opts.command, Some(Command::Report { report: Report::Latency { dir: "data/rolling".into() } }));
+/// ```
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Report {
+    /// Reports the min, p50, p95, p99 and max of the time between the exchange timestamp
+    /// and the write timestamp of the trades of the rolling files, for each symbol
+    Latency {
+        /// The directory holding the rolling files, which gets walked recursively
+        #[clap(long, default_value = "data/rolling")]
+        dir: PathBuf,
+    },
+}
+
+impl CLIOptions {
+    /// Returns the url to connect to, which is the endpoint with the token
+    /// appended as a query parameter. Only ws:// and wss:// endpoints are valid.
+    ///
+    /// # Example
+    /// ```
+    /// use clap::Parser;
+    /// use finnhub_ws::cli::cmd::CLIOptions;
+    /// let opts = CLIOptions::parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--endpoint", "ws://127.0.0.1:8080"]);
+    /// assert_eq!(opts.connect_url().unwrap().as_str(), "ws://127.0.0.1:8080/?token=abc");
+    /// ```
+    pub fn connect_url(&self) -> Result<Url, String> {
+        let mut url = Url::parse(&self.endpoint).map_err(|e| e.to_string())?;
+        match url.scheme() {
+            "ws" | "wss" => {
+                url.query_pairs_mut().append_pair("token", self.token.as_deref().unwrap_or_default());
+                Ok(url)
+            }
+            scheme => Err(format!("unsupported scheme {}", scheme)),
+        }
+    }
+
+    /// Returns the settings to create the stock handles with
+    ///
+    /// # Example
+    /// ```
+    /// use clap::Parser;
+    /// use finnhub_ws::cli::cmd::CLIOptions;
+    /// let opts = CLIOptions::parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--candle-intervals", "1m,5m,1h"]);
+    /// let intervals: Vec<String> = opts.mapper_options().candle_intervals.iter().map(|i| i.to_string()).collect();
+    /// assert_eq!(intervals, vec!["1m", "5m", "1h"]);
+    /// assert_eq!(opts.mapper_options().mean_windows, vec!["15m".parse().unwrap()]);
+    /// ```
+    pub fn mapper_options(&self) -> MapperOptions {
+        let mut candle_intervals = self.candle_intervals.clone();
+        candle_intervals.sort();
+        candle_intervals.dedup();
+        let mut mean_windows = self.mean_windows.clone();
+        mean_windows.sort();
+        mean_windows.dedup();
+        MapperOptions {
+            candle_intervals,
+            mean_windows,
+            condition_filter: ConditionFilter::new(&self.include_conditions, &self.exclude_conditions),
+            late_policy: self.late_trades,
+            storage_format: self.storage_format,
+            max_file_size: self.max_file_size_mb.map(|mb| mb * 1024 * 1024),
+            flush_interval: self.flush_interval_ms.map(Duration::from_millis),
+            fsync: self.fsync,
+            news: self.news,
+            data_dir: PathBuf::from(DATA_DIR),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Returns how often the latencies of the trades get written
+    pub fn latency_interval(&self) -> Duration {
+        Duration::from_secs(self.latency_interval_secs.max(1))
+    }
+
+    /// Returns how often the data written get synced to the disk with the interval fsync policy.
+    /// The intervals of zero milliseconds get rejected, as the timers need a period.
+    ///
+    /// # Example
+    /// ```
+    /// use clap::Parser;
+    /// use finnhub_ws::cli::cmd::CLIOptions;
+    /// let opts = CLIOptions::parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--fsync-interval-ms", "250"]);
+    /// assert_eq!(opts.fsync_interval().as_millis(), 250);
+    /// assert!(CLIOptions::try_parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--fsync-interval-ms", "0"]).is_err());
+    /// assert!(CLIOptions::try_parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--flush-interval-ms", "0"]).is_err());
+    /// ```
+    pub fn fsync_interval(&self) -> Duration {
+        Duration::from_millis(self.fsync_interval_ms)
+    }
+
+    /// Returns what happens to the date directories of the data files as they get older
+    ///
+    /// # Example
+    /// ```
+    /// use clap::Parser;
+    /// use finnhub_ws::cli::cmd::CLIOptions;
+    /// let opts = CLIOptions::parse_from(["finnhub_ws", "-t", "abc", "-s", "AAPL", "--retain-days", "30"]);
+    /// assert_eq!(opts.retention_policy().retain_days, Some(30));
+    /// assert_eq!(opts.retention_policy().compress_after_days, None);
+    /// ```
+    pub fn retention_policy(&self) -> RetentionPolicy {
+        RetentionPolicy {
+            compress_after_days: self.compress_after_days,
+            retain_days: self.retain_days,
+        }
+    }
+
+    /// Returns how long to wait after the end of a minute for its trades to arrive
+    pub fn allowed_lateness(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.allowed_lateness as i64)
+    }
 }
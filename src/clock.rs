@@ -0,0 +1,68 @@
+//! Clock primitives
+//! # clock
+//!
+//! This contains the `Clock` trait, which is where the library gets the current time from:
+//! the write timestamps of the trades, the time the messages got received at and the deadlines
+//! of the windows. The `SystemClock` reads the wall clock, while the `ManualClock` only moves
+//! when it is told to, so that whole minutes of candlestick and mean data can be tested
+//! deterministically without sleeping or comparing against the truncated wall time.
+//!
+//! # Example
+//! ```
+//! use chrono::{Duration, TimeZone, Utc};
+//! use finnhub_ws::clock::{Clock, ManualClock};
+//! let clock = ManualClock::new(Utc.ymd(2022, 7, 21).and_hms(22, 7, 30));
+//! clock.advance(Duration::seconds(35));
+//! assert_eq!(clock.now(), Utc.ymd(2022, 7, 21).and_hms(22, 8, 5));
+//! ```
+use std::fmt;
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+
+/// `Clock` is where the current time gets read from. It can be shared between threads.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Returns the current time
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// `SystemClock` reads the current time from the wall clock
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// `ManualClock` holds a time which only changes when it gets set or advanced
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    /// Given the time to start at, creates and returns a new instance of ManualClock
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    /// Sets the current time, which can be earlier than the previous one
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+
+    /// Moves the current time forward by the given duration
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
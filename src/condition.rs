@@ -0,0 +1,127 @@
+//! Trade condition primitives
+//! # condition
+//!
+//! This contains the filter deciding which trades count toward the candlestick
+//! and mean data according to their condition codes. Some trades, like the odd-lot,
+//! out-of-sequence or average-price ones reported for US equities, don't reflect the
+//! market price at the time and shouldn't set the high or low of a candlestick.
+//! A list of the possible codes can be found
+//! [here](https://docs.google.com/spreadsheets/d/1PUxiSWPHSODbaTaoL2Vef6DgU-yFtlRGZf19oBb9Hp0/edit#gid=0)
+use serde::{Deserialize, Deserializer, Serializer};
+use crate::RollingData;
+
+/// The separator of the condition codes of a trade when written to a single csv column
+const SEPARATOR: char = ';';
+
+/// `ConditionFilter` decides whether a trade gets aggregated by looking at its condition codes.
+/// A trade is aggregated if it has none of the excluded codes and, when any included codes are
+/// given, at least one of them. The default filter aggregates every trade.
+///
+/// # Example
+/// ```
+/// use chrono::Utc;
+/// use finnhub_ws::condition::ConditionFilter;
+/// use finnhub_ws::{RollingData, TickerInfo};
+/// let filter = ConditionFilter::new(&[], &["2".to_string()]);
+/// let odd_lot = TickerInfo::new("AAPL", 172.5, 1.0, &Utc::now(), &["2".to_string()]);
+/// let regular = TickerInfo::new("AAPL", 172.5, 100.0, &Utc::now(), &["1".to_string()]);
+/// assert!(!filter.accepts(&RollingData::from(&odd_lot)));
+/// assert!(filter.accepts(&RollingData::from(&regular)));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConditionFilter {
+    /// include: the codes of which a trade should have at least one. Empty to allow any
+    include: Vec<String>,
+    /// exclude: the codes a trade should have none of
+    exclude: Vec<String>,
+}
+
+impl ConditionFilter {
+    /// Given the condition codes to include and exclude, creates and returns a filter
+    pub fn new(include: &[String], exclude: &[String]) -> Self {
+        ConditionFilter {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }
+    }
+
+    /// Returns true if the trade should count toward the candlestick and mean data
+    pub fn accepts(&self, trade: &RollingData) -> bool {
+        let included = self.include.is_empty() || trade.conditions.iter().any(|c| self.include.contains(c));
+        included && !trade.conditions.iter().any(|c| self.exclude.contains(c))
+    }
+}
+
+/// Serializes the condition codes of a trade to a single string, separated by semicolons
+pub(crate) fn serialize_codes<S: Serializer>(codes: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&join_codes(codes))
+}
+
+/// Deserializes the condition codes of a trade from a single string, separated by semicolons
+pub(crate) fn deserialize_codes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    Ok(split_codes(&s))
+}
+
+/// Splits the condition codes of a trade from the string written to the csv files
+pub(crate) fn split_codes(s: &str) -> Vec<String> {
+    s.split(SEPARATOR).filter(|c| !c.is_empty()).map(|c| c.to_string()).collect()
+}
+
+/// Joins the condition codes of a trade to the string written to the csv files
+pub(crate) fn join_codes(codes: &[String]) -> String {
+    codes.join(&SEPARATOR.to_string())
+}
+
+
+#[cfg(test)]
+mod condition_test {
+    use chrono::Utc;
+    use crate::condition::ConditionFilter;
+    use crate::{RollingData, TickerInfo};
+
+    fn trade(conditions: &[&str]) -> RollingData {
+        let conditions: Vec<String> = conditions.iter().map(|c| c.to_string()).collect();
+        RollingData::from(&TickerInfo::new("AAPL", 172.5, 1.0, &Utc::now(), &conditions))
+    }
+
+    fn codes(codes: &[&str]) -> Vec<String> {
+        codes.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn the_default_filter_should_accept_every_trade() {
+        let filter = ConditionFilter::default();
+        assert!(filter.accepts(&trade(&[])));
+        assert!(filter.accepts(&trade(&["2", "12"])));
+    }
+
+    #[test]
+    fn given_excluded_codes_it_should_reject_trades_having_any_of_them() {
+        let filter = ConditionFilter::new(&[], &codes(&["2", "12"]));
+        assert!(filter.accepts(&trade(&[])));
+        assert!(filter.accepts(&trade(&["1"])));
+        assert!(!filter.accepts(&trade(&["1", "12"])));
+    }
+
+    #[test]
+    fn given_included_codes_it_should_only_accept_trades_having_one_of_them() {
+        let filter = ConditionFilter::new(&codes(&["1"]), &codes(&["12"]));
+        assert!(!filter.accepts(&trade(&[])));
+        assert!(filter.accepts(&trade(&["1"])));
+        assert!(!filter.accepts(&trade(&["1", "12"])));
+        assert!(!filter.accepts(&trade(&["8"])));
+    }
+
+    #[test]
+    fn it_should_write_and_read_back_the_conditions_of_a_trade() {
+        let record = trade(&["1", "12"]);
+        let mut writer = csv::WriterBuilder::new().has_headers(true).from_writer(vec![]);
+        writer.serialize(&record).unwrap();
+        let data = writer.into_inner().unwrap();
+        assert!(String::from_utf8(data.clone()).unwrap().trim_end().ends_with(",1;12"));
+        let mut reader = csv::ReaderBuilder::new().from_reader(data.as_slice());
+        let got: RollingData = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(got.conditions, record.conditions);
+    }
+}
@@ -0,0 +1,185 @@
+//! Connection supervision primitives
+//! # connection
+//!
+//! This contains the necessary structure and functions to keep the
+//! websocket connection to finnhub alive. Whenever the connection drops,
+//! the supervisor waits according to a jittered exponential backoff before
+//! reconnecting, and every reconnection gets written to a dedicated file
+//! so that gaps in the collected data can be audited later on.
+use std::fs::File;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+use crate::error::Result;
+use crate::utils::{append_csv, open_log};
+
+/// `Backoff` calculates how long the supervisor should wait before trying to
+/// reconnect. The delay doubles on each consecutive failure up to `max` and
+/// a random jitter is applied so that many clients don't reconnect in lockstep.
+///
+/// # Example
+/// ```
+/// use tokio::time::Duration;
+/// use finnhub_ws::connection::Backoff;
+/// let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(60));
+/// let delay = backoff.next_delay();
+/// assert!(delay <= Duration::from_millis(500));
+/// assert_eq!(backoff.attempts(), 1);
+/// ```
+#[derive(Debug)]
+pub struct Backoff {
+    /// base: the delay before the first retry
+    base: Duration,
+    /// max: the upper bound of the delay between two retries
+    max: Duration,
+    /// attempts: the number of consecutive retries since the last reset
+    attempts: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(500), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    /// Given the base and the max delay, creates and returns a new instance of Backoff
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Backoff {
+            base,
+            max,
+            attempts: 0,
+        }
+    }
+
+    /// Returns the delay to wait before the next retry and increments the number of attempts.
+    /// The delay is uniformly distributed between half and the whole of the exponential delay.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self.base.saturating_mul(2u32.saturating_pow(self.attempts.min(16)));
+        let capped = exp.min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+        let half = capped / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// Returns the number of consecutive retries since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Resets the backoff after a successful connection
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// `ReconnectEvent` represents a single reconnection to the finnhub api and
+/// holds the information needed to audit the data gap it caused.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct ReconnectEvent {
+    /// the total number of reconnections since the program started
+    pub reconnects: u64,
+    /// the number of attempts it took to reconnect
+    pub attempts: u32,
+    /// the time the connection was lost
+    pub disconnected_at: DateTime<Utc>,
+    /// the time the connection was re-established
+    pub reconnected_at: DateTime<Utc>,
+    /// the time in milliseconds no data were being ingested
+    pub downtime_millis: i64,
+    /// the reason the connection was lost
+    pub reason: String,
+}
+
+impl ReconnectEvent {
+    /// Given the reconnect counters, the time the connection was lost and re-established and the
+    /// reason, creates and returns a new instance of ReconnectEvent
+    pub fn new(reconnects: u64, attempts: u32, disconnected_at: DateTime<Utc>, reconnected_at: DateTime<Utc>, reason: &str) -> Self {
+        ReconnectEvent {
+            reconnects,
+            attempts,
+            disconnected_at,
+            reconnected_at,
+            downtime_millis: (reconnected_at - disconnected_at).num_milliseconds(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// `write_to_file`: serializes the struct instance and writes it the given file.
+    /// The headers are written only when the file is empty.
+    pub fn write_to_file(&self, file: &File) -> Result<()> {
+        append_csv(file, [self])
+    }
+}
+
+/// Returns a file descriptor for the file where the reconnection events get written to.
+/// The file will be located under data/connection directory and be named reconnects.csv
+///
+/// # Example
+/// ```
+/// use finnhub_ws::connection::create_connection_log;
+/// use finnhub_ws::utils::create_dirs;
+/// let _ = create_dirs("data/connection");
+/// let f = create_connection_log().unwrap();
+/// ```
+pub fn create_connection_log() -> Option<File> {
+    open_log(Path::new("data/connection/reconnects.csv"))
+}
+
+
+#[cfg(test)]
+mod connection_test {
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom};
+    use chrono::{TimeZone, Utc};
+    use tokio::time::Duration;
+    use crate::connection::{Backoff, ReconnectEvent};
+
+    #[test]
+    fn backoff_should_grow_exponentially_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        for expected in [1, 2, 4, 8, 8, 8] {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(expected) / 2);
+            assert!(delay <= Duration::from_secs(expected));
+        }
+        assert_eq!(backoff.attempts(), 6);
+    }
+
+    #[test]
+    fn backoff_should_start_over_after_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.attempts(), 0);
+        assert!(backoff.next_delay() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn given_a_reconnect_event_it_should_write_headers_once() {
+        let file_name = "given_a_reconnect_event_it_should_write_headers_once.csv";
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .read(true)
+            .open(file_name).unwrap();
+        let event = ReconnectEvent::new(1, 3,
+                                        Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
+                                        Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 40, 376),
+                                        "stream ended");
+        assert_eq!(event.downtime_millis, 2000);
+        event.write_to_file(&file).unwrap();
+        event.write_to_file(&file).unwrap();
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut data).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("Reconnects,Attempts,DisconnectedAt"));
+        remove_file(file_name).unwrap();
+    }
+}
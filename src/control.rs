@@ -0,0 +1,343 @@
+//! Control socket primitives
+//! # control
+//!
+//! This contains the local control socket, a Unix domain socket through which the stocks
+//! being tracked can be changed while the program runs, without restarting it and losing
+//! the windows in flight. Each line sent to the socket is a command, which gets answered
+//! with a single line starting with either `ok` or `error`:
+//! - `subscribe <SYMBOL>` starts tracking a stock and subscribes to it over the live connection
+//! - `unsubscribe <SYMBOL>` writes the partial windows of a stock, stops tracking it and
+//!   unsubscribes from it
+//! - `list` answers with the symbols of the stocks being tracked, separated by spaces
+//!
+//! # Example
+//! ```
+//! use finnhub_ws::control::ControlCommand;
+//! assert_eq!("subscribe AAPL".parse(), Ok(ControlCommand::Subscribe("AAPL".to_string())));
+//! assert_eq!("list".parse(), Ok(ControlCommand::List));
+//! assert!("subscribe".parse::<ControlCommand>().is_err());
+//! ```
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc::{self, UnboundedSender}, watch};
+use tokio::task::JoinHandle;
+use crate::pipeline::spawn_workers;
+use crate::stock_handle::{Mapper, WindowSignal};
+use crate::SubscribeInfo;
+
+/// `ControlCommand` represents the commands which can be sent to the control socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Starts tracking the stock with the given symbol
+    Subscribe(String),
+    /// Stops tracking the stock with the given symbol
+    Unsubscribe(String),
+    /// Lists the stocks being tracked
+    List,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        match words.as_slice() {
+            ["subscribe", symbol] => Ok(ControlCommand::Subscribe(symbol.to_string())),
+            ["unsubscribe", symbol] => Ok(ControlCommand::Unsubscribe(symbol.to_string())),
+            ["list"] => Ok(ControlCommand::List),
+            _ => Err(format!("invalid command {}, expected subscribe <SYMBOL>, unsubscribe <SYMBOL> or list", s.trim())),
+        }
+    }
+}
+
+/// `SubscriptionChange` is sent from the control socket to the connection supervisor,
+/// so that the subscription messages get sent over the live connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscriptionChange {
+    /// The stock with the given symbol started being tracked
+    Subscribe(String),
+    /// The stock with the given symbol stopped being tracked
+    Unsubscribe(String),
+}
+
+impl SubscriptionChange {
+    /// Returns the messages to send to finnhub for the change, including the ones
+    /// for the news of the stock if `news` is set
+    ///
+    /// # Example
+    /// ```
+    /// use finnhub_ws::control::SubscriptionChange;
+    /// let change = SubscriptionChange::Unsubscribe("AAPL".to_string());
+    /// let messages = change.messages(true);
+    /// let types: Vec<&str> = messages.iter().map(|m| m.message_type).collect();
+    /// assert_eq!(types, vec!["unsubscribe", "unsubscribe-news"]);
+    /// ```
+    pub fn messages(&self, news: bool) -> Vec<SubscribeInfo<'_>> {
+        let mut messages = match self {
+            SubscriptionChange::Subscribe(symbol) => vec![SubscribeInfo::new(symbol)],
+            SubscriptionChange::Unsubscribe(symbol) => vec![SubscribeInfo::unsubscribe(symbol)],
+        };
+        if news {
+            messages.push(match self {
+                SubscriptionChange::Subscribe(symbol) => SubscribeInfo::news(symbol),
+                SubscriptionChange::Unsubscribe(symbol) => SubscribeInfo::unsubscribe_news(symbol),
+            });
+        }
+        messages
+    }
+}
+
+/// Binds the control socket to the given path. A socket left behind by a previous run gets
+/// removed first, while the path is left alone if it isn't a socket or if another instance of
+/// the program is still listening to it.
+pub fn bind_control(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} isn't a socket", path.display())));
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is used by another instance", path.display()))),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e),
+        },
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// `serve_control` answers the commands sent to the control socket until `shutdown` is set.
+/// Each client gets served by its own task, so that a client which stays connected doesn't
+/// keep the others waiting. The stocks added get their workers started and the changes get
+/// sent to the connection supervisor through `changes`. It returns the join handles of the
+/// workers it started, which finish once they get sent a `WindowSignal::Shutdown`.
+///
+/// # Arguments
+/// listener: the control socket, bound with `bind_control`
+/// mapper: the stock handles to add the stocks to and retire them from
+/// changes: the sender of the subscription changes to the connection supervisor
+/// shutdown: a watch receiver which is set once the program shuts down
+pub async fn serve_control(listener: UnixListener, mapper: Arc<Mapper>, changes: UnboundedSender<SubscriptionChange>, mut shutdown: watch::Receiver<bool>) -> Vec<JoinHandle<()>> {
+    let (workers_tx, mut workers_rx) = mpsc::unbounded_channel();
+    let mut clients: Vec<JoinHandle<()>> = Vec::new();
+    while !*shutdown.borrow() {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    clients.retain(|client| !client.is_finished());
+                    let (mapper, changes, workers, shutdown) = (Arc::clone(&mapper), changes.clone(), workers_tx.clone(), shutdown.clone());
+                    clients.push(tokio::spawn(async move {
+                        if let Err(e) = serve_client(stream, &mapper, &changes, &workers, shutdown).await {
+                            eprintln!("Control client failed: {}", e);
+                        }
+                    }));
+                }
+                Err(e) => eprintln!("Couldn't accept a control client: {}", e),
+            },
+            _ = shutdown.changed() => {}
+        }
+    }
+    // the clients return on shutdown, after which no more workers get started
+    for client in clients {
+        if let Err(e) = client.await {
+            eprintln!("Control client failed: {}", e);
+        }
+    }
+    drop(workers_tx);
+    let mut workers = Vec::new();
+    while let Ok(worker) = workers_rx.try_recv() {
+        workers.push(worker);
+    }
+    workers
+}
+
+/// Answers the commands of a single client until it goes away or the program shuts down
+async fn serve_client(stream: UnixStream, mapper: &Arc<Mapper>, changes: &UnboundedSender<SubscriptionChange>, workers: &UnboundedSender<JoinHandle<()>>, mut shutdown: watch::Receiver<bool>) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while !*shutdown.borrow() {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        let line = match line {
+            Some(line) if line.trim().is_empty() => continue,
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let reply = match line.parse() {
+            Ok(command) => execute(command, mapper, changes, workers).await,
+            Err(e) => format!("error {}", e),
+        };
+        write.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Executes a command, returning the reply to send to the client
+async fn execute(command: ControlCommand, mapper: &Arc<Mapper>, changes: &UnboundedSender<SubscriptionChange>, workers: &UnboundedSender<JoinHandle<()>>) -> String {
+    match command {
+        ControlCommand::Subscribe(symbol) => {
+            if mapper.get(&symbol).is_some() {
+                return format!("error already tracking {}", symbol);
+            }
+            // creating the files of the stock blocks, so it happens off the runtime
+            let (adding, added) = (Arc::clone(mapper), symbol.clone());
+            let handle = match tokio::task::spawn_blocking(move || adding.add(&added)).await {
+                Ok(Some(handle)) => handle,
+                _ => return format!("error couldn't start tracking {}", symbol),
+            };
+            for worker in spawn_workers(&handle) {
+                if workers.send(worker).is_err() {
+                    eprintln!("Couldn't keep track of a worker of {}", symbol);
+                }
+            }
+            if changes.send(SubscriptionChange::Subscribe(symbol.clone())).is_err() {
+                eprintln!("Couldn't subscribe to {}: the connection is closed", symbol);
+            }
+            println!("Started tracking {}", symbol);
+            "ok".to_string()
+        }
+        ControlCommand::Unsubscribe(symbol) => {
+            let handle = match mapper.remove(&symbol) {
+                Some(handle) => handle,
+                None => return format!("error not tracking {}", symbol),
+            };
+            if changes.send(SubscriptionChange::Unsubscribe(symbol.clone())).is_err() {
+                eprintln!("Couldn't unsubscribe from {}: the connection is closed", symbol);
+            }
+            // the workers write the partial windows of the stock and return
+            let signal = WindowSignal::Shutdown(mapper.clock().now().timestamp_millis());
+            for (tx, _) in [&handle.stock_channel, &handle.rolling_mean_channel] {
+                if tx.send(signal).is_err() {
+                    eprintln!("Couldn't send {:?} to a worker of {}", signal, symbol);
+                }
+            }
+            if handle.errors.total() > 0 {
+                eprintln!("Errors of {}: {}", symbol, handle.errors);
+            }
+            println!("Stopped tracking {}", symbol);
+            "ok".to_string()
+        }
+        ControlCommand::List => {
+            let symbols = mapper.symbols();
+            match symbols.is_empty() {
+                true => "ok".to_string(),
+                false => format!("ok {}", symbols.join(" ")),
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod control_test {
+    use std::fs::{read_to_string, remove_file, write};
+    use std::io::ErrorKind;
+    use std::path::Path;
+    use std::time::Duration;
+    use chrono::Utc;
+    use serial_test::serial;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::timeout;
+    use crate::control::{bind_control, serve_control, SubscriptionChange};
+    use crate::stock_handle::{initialize_mapper, MapperOptions, WindowSignal};
+    use crate::utils::create_dirs;
+
+    #[tokio::test]
+    #[serial]
+    async fn it_should_add_and_retire_stocks_at_runtime() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/control.sock");
+        let listener = bind_control(path).unwrap();
+        let mapper = initialize_mapper(&["MOCK:KEEP".to_string()], &MapperOptions::default());
+        let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server_mapper = mapper.clone();
+        let server = tokio::spawn(async move {
+            serve_control(listener, server_mapper, changes_tx, shutdown_rx).await
+        });
+        let (read, mut write) = UnixStream::connect(path).await.unwrap().into_split();
+        let mut replies = BufReader::new(read).lines();
+        for (command, expected) in [
+            ("subscribe MOCK:ADD", "ok"),
+            ("subscribe MOCK:ADD", "error already tracking MOCK:ADD"),
+            ("list", "ok MOCK:KEEP MOCK:ADD"),
+            ("unsubscribe MOCK:KEEP", "ok"),
+            ("unsubscribe MOCK:KEEP", "error not tracking MOCK:KEEP"),
+            ("list", "ok MOCK:ADD"),
+        ] {
+            write.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+            assert_eq!(replies.next_line().await.unwrap().unwrap(), expected);
+        }
+        write.write_all(b"drop MOCK:ADD\n").await.unwrap();
+        assert!(replies.next_line().await.unwrap().unwrap().starts_with("error invalid command"));
+        assert_eq!(changes_rx.recv().await, Some(SubscriptionChange::Subscribe("MOCK:ADD".to_string())));
+        assert_eq!(changes_rx.recv().await, Some(SubscriptionChange::Unsubscribe("MOCK:KEEP".to_string())));
+        assert_eq!(mapper.symbols(), vec!["MOCK:ADD".to_string()]);
+        // the workers of the added stock return once they get the shutdown signal
+        shutdown_tx.send(true).unwrap();
+        let workers = server.await.unwrap();
+        assert_eq!(workers.len(), 2);
+        let added = mapper.get("MOCK:ADD").unwrap();
+        for (tx, _) in [&added.stock_channel, &added.rolling_mean_channel] {
+            tx.send(WindowSignal::Shutdown(Utc::now().timestamp_millis())).unwrap();
+        }
+        for worker in workers {
+            worker.await.unwrap();
+        }
+        let date = Utc::now().format("%Y-%m-%d");
+        for symbol in ["MOCK_KEEP", "MOCK_ADD"] {
+            assert!(read_to_string(format!("data/rolling/{}/{}.csv", date, symbol)).unwrap().starts_with("Symbol,"));
+            remove_file(format!("data/rolling/{}/{}.csv", date, symbol)).unwrap();
+            remove_file(format!("data/candlestick/1m/{}/{}.csv", date, symbol)).unwrap();
+            remove_file(format!("data/mean/{}/{}.csv", date, symbol)).unwrap();
+        }
+        remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn given_an_idle_client_it_should_keep_answering_the_others() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/control_clients.sock");
+        let listener = bind_control(path).unwrap();
+        let mapper = initialize_mapper(&[], &MapperOptions::default());
+        let (changes_tx, _changes_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let server = tokio::spawn(serve_control(listener, mapper, changes_tx, shutdown_rx));
+        let _idle = UnixStream::connect(path).await.unwrap();
+        let (read, mut write) = UnixStream::connect(path).await.unwrap().into_split();
+        write.write_all(b"list\n").await.unwrap();
+        let reply = timeout(Duration::from_secs(5), BufReader::new(read).lines().next_line()).await;
+        assert_eq!(reply.unwrap().unwrap().unwrap(), "ok");
+        shutdown_tx.send(true).unwrap();
+        assert!(server.await.unwrap().is_empty());
+        remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn given_a_path_in_use_it_should_leave_it_alone() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/control_in_use.sock");
+        let _ = remove_file(path);
+        // a socket nothing listens to anymore gets replaced
+        drop(bind_control(path).unwrap());
+        let listener = bind_control(path).unwrap();
+        assert_eq!(bind_control(path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+        remove_file(path).unwrap();
+        let file = Path::new("tmp/control_in_use.txt");
+        write(file, "not a socket").unwrap();
+        assert_eq!(bind_control(file).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(read_to_string(file).unwrap(), "not a socket");
+        remove_file(file).unwrap();
+    }
+}
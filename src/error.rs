@@ -0,0 +1,170 @@
+//! Error primitives
+//! # error
+//!
+//! This contains the error type returned by the functions of the library, along with
+//! the counters of the errors the pipeline ran into. The pipeline doesn't stop on an
+//! error: a malformed message gets skipped, a failed write gets retried with the next
+//! one and a stock whose worker failed doesn't stop the workers of the other stocks,
+//! so each error gets logged and counted instead.
+//!
+//! # Example
+//! ```
+//! use finnhub_ws::error::{Error, ErrorCounts};
+//! let counts = ErrorCounts::default();
+//! let error = Error::from(serde_json::from_str::<u32>("{").unwrap_err());
+//! counts.record(&error);
+//! assert_eq!(counts.malformed_messages(), 1);
+//! assert_eq!(counts.total(), 1);
+//! ```
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// `Error` represents the reasons a function of the library may fail
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// A record couldn't be written to or read from a csv file
+    Csv(csv::Error),
+    /// A websocket message isn't valid json or doesn't match any of the expected messages
+    Json(serde_json::Error),
+    /// The channel a worker of the stock receives its window signals from got disconnected
+    Disconnected(String),
+    /// A thread panicked while holding the lock of an aggregator of the stock
+    Poisoned(String),
+    /// A worker of the stock panicked. It holds the panic message
+    Panicked(String, String),
+}
+
+/// The result type of the functions of the library
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Csv(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "malformed message: {}", e),
+            Error::Disconnected(stock) => write!(f, "the window signals of {} got disconnected", stock),
+            Error::Poisoned(stock) => write!(f, "an aggregator of {} got poisoned", stock),
+            Error::Panicked(stock, message) => write!(f, "a worker of {} panicked: {}", stock, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Csv(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    /// Lets the stores, which deal in io errors, write with the functions of the library
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// `ErrorCounts` counts the errors the pipeline ran into by kind. It can be
+/// shared between threads, as the counters are atomic.
+#[derive(Debug, Default)]
+pub struct ErrorCounts {
+    /// the websocket messages which got skipped as malformed
+    malformed_messages: AtomicU64,
+    /// the writes to the store which failed
+    failed_writes: AtomicU64,
+    /// the workers which failed and stopped
+    failed_workers: AtomicU64,
+}
+
+impl ErrorCounts {
+    /// Increments the counter of the kind of the error
+    pub fn record(&self, error: &Error) {
+        let counter = match error {
+            Error::Json(_) => &self.malformed_messages,
+            Error::Io(_) | Error::Csv(_) => &self.failed_writes,
+            Error::Disconnected(_) | Error::Poisoned(_) | Error::Panicked(..) => &self.failed_workers,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of websocket messages which got skipped as malformed
+    pub fn malformed_messages(&self) -> u64 {
+        self.malformed_messages.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of writes to the store which failed
+    pub fn failed_writes(&self) -> u64 {
+        self.failed_writes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of workers which failed and stopped
+    pub fn failed_workers(&self) -> u64 {
+        self.failed_workers.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of errors of every kind
+    pub fn total(&self) -> u64 {
+        self.malformed_messages() + self.failed_writes() + self.failed_workers()
+    }
+}
+
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} malformed messages, {} failed writes, {} failed workers",
+               self.malformed_messages(), self.failed_writes(), self.failed_workers())
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use std::io;
+    use crate::error::{Error, ErrorCounts};
+
+    #[test]
+    fn given_errors_of_each_kind_it_should_count_them_apart() {
+        let counts = ErrorCounts::default();
+        counts.record(&Error::from(io::Error::other("disk full")));
+        counts.record(&Error::from(io::Error::other("disk full")));
+        counts.record(&Error::Poisoned("AAPL".to_string()));
+        assert_eq!(counts.malformed_messages(), 0);
+        assert_eq!(counts.failed_writes(), 2);
+        assert_eq!(counts.failed_workers(), 1);
+        assert_eq!(counts.to_string(), "0 malformed messages, 2 failed writes, 1 failed workers");
+    }
+
+    #[test]
+    fn given_an_io_error_it_should_convert_back_to_it() {
+        let error: io::Error = Error::from(io::Error::new(io::ErrorKind::NotFound, "gone")).into();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error: io::Error = Error::Poisoned("AAPL".to_string()).into();
+        assert_eq!(error.to_string(), "an aggregator of AAPL got poisoned");
+    }
+}
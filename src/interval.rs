@@ -0,0 +1,144 @@
+//! Interval primitives
+//! # interval
+//!
+//! This contains the type representing the length of a candlestick or a mean
+//! window as given in the command line, such as `1m`, `15m`, `1h` or `1d`.
+use std::fmt;
+use std::str::FromStr;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// `Interval` is a length of time which is a whole number of minutes and divides a day
+/// evenly, so that its windows can be aligned to wall-clock boundaries in UTC.
+///
+/// # Example
+/// ```
+/// use finnhub_ws::interval::Interval;
+/// let interval: Interval = "5m".parse().unwrap();
+/// assert_eq!(interval.seconds(), 300);
+/// assert_eq!(interval.to_string(), "5m");
+/// assert!(interval.is_boundary(1658441400));
+/// assert!(!interval.is_boundary(1658441460));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Interval {
+    /// seconds: the length of the interval in seconds
+    seconds: i64,
+}
+
+impl Interval {
+    /// Given a number of minutes, returns the interval if it divides a day evenly
+    pub fn minutes(minutes: i64) -> Result<Self, String> {
+        match minutes.checked_mul(60) {
+            Some(seconds) if seconds > 0 && SECONDS_PER_DAY % seconds == 0 => Ok(Interval { seconds }),
+            _ => Err(format!("{}m doesn't divide a day evenly", minutes)),
+        }
+    }
+
+    /// Returns the length of the interval in seconds
+    pub fn seconds(&self) -> i64 {
+        self.seconds
+    }
+
+    /// Returns the length of the interval as a chrono Duration
+    pub fn duration(&self) -> Duration {
+        Duration::seconds(self.seconds)
+    }
+
+    /// Given a timestamp in seconds, returns true if an interval ends at it
+    pub fn is_boundary(&self, timestamp: i64) -> bool {
+        timestamp.rem_euclid(self.seconds) == 0
+    }
+
+    /// Given a datetime, returns the start of the interval it falls in
+    pub fn start_of(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let timestamp = time.timestamp();
+        Utc.timestamp(timestamp - timestamp.rem_euclid(self.seconds), 0)
+    }
+}
+
+impl FromStr for Interval {
+    type Err = String;
+
+    /// Parses an interval given as a number followed by one of the units `m`, `h` or `d`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (value, unit) = s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+        let value: i64 = value.parse().map_err(|_| format!("invalid interval {}", s))?;
+        let minutes = match unit {
+            "m" => Some(value),
+            "h" => value.checked_mul(60),
+            "d" => value.checked_mul(24 * 60),
+            _ => return Err(format!("invalid interval {}, expected a unit of m, h or d", s)),
+        };
+        minutes.ok_or_else(|| format!("{} doesn't divide a day evenly", s)).and_then(Interval::minutes)
+    }
+}
+
+impl fmt::Display for Interval {
+    /// Formats the interval using the largest unit that divides it evenly
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let minutes = self.seconds / 60;
+        if minutes % (24 * 60) == 0 {
+            write!(f, "{}d", minutes / (24 * 60))
+        } else if minutes % 60 == 0 {
+            write!(f, "{}h", minutes / 60)
+        } else {
+            write!(f, "{}m", minutes)
+        }
+    }
+}
+
+impl Serialize for Interval {
+    /// Serializes the interval the way it gets displayed, such as `15m`
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Interval {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+
+#[cfg(test)]
+mod interval_test {
+    use chrono::{TimeZone, Utc};
+    use crate::interval::Interval;
+
+    #[test]
+    fn given_valid_intervals_it_should_parse_them() {
+        for (s, seconds) in [("1m", 60), ("5m", 300), ("15m", 900), ("60m", 3600), ("1h", 3600), ("1d", 86400)] {
+            assert_eq!(s.parse::<Interval>().unwrap().seconds(), seconds);
+        }
+    }
+
+    #[test]
+    fn given_invalid_intervals_it_should_fail() {
+        for s in ["", "m", "0m", "-5m", "7m", "2d", "5s", "1w", "abc", "153722867280912931m", "9223372036854775807h", "-9223372036854775807d"] {
+            assert!(s.parse::<Interval>().is_err(), "{} should be invalid", s);
+        }
+    }
+
+    #[test]
+    fn it_should_display_intervals_using_the_largest_unit() {
+        assert_eq!("60m".parse::<Interval>().unwrap().to_string(), "1h");
+        assert_eq!("1440m".parse::<Interval>().unwrap().to_string(), "1d");
+        assert_eq!("90m".parse::<Interval>().unwrap().to_string(), "90m");
+    }
+
+    #[test]
+    fn it_should_align_to_wall_clock_boundaries() {
+        let hour: Interval = "1h".parse().unwrap();
+        let time = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+        assert_eq!(hour.start_of(time), Utc.ymd(2022, 7, 21).and_hms(22, 0, 0));
+        assert!(hour.is_boundary(Utc.ymd(2022, 7, 21).and_hms(23, 0, 0).timestamp()));
+        let day: Interval = "1d".parse().unwrap();
+        assert_eq!(day.start_of(time), Utc.ymd(2022, 7, 21).and_hms(0, 0, 0));
+    }
+}
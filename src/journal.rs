@@ -0,0 +1,326 @@
+//! Journal primitives
+//! # journal
+//!
+//! This contains the compact binary format the rolling data can be stored in instead
+//! of csv. Each record of the journal is a little-endian `u32` holding the length of the
+//! record, followed by the record itself serialized with bincode. A record is written
+//! with a single call, without going through a csv writer which gets built and flushed
+//! for every trade. The frames recorded by the `recorder` module get stored the same way.
+//!
+//! # Example
+//! ```
+//! use chrono::{TimeZone, Utc};
+//! use finnhub_ws::journal::{write_record, JournalReader};
+//! use finnhub_ws::RollingData;
+//! let record = RollingData{
+//!     price: 172.5,
+//!     symbol: "APPL".parse().unwrap(),
+//!     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
+//!     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
+//!     volume: 0.5,
+//!     conditions: vec!["1".to_string()]
+//! };
+//! let mut journal = Vec::new();
+//! write_record(&mut journal, &record).unwrap();
+//! let records: Vec<RollingData> = JournalReader::new(journal.as_slice()).map(|r| r.unwrap()).collect();
+//! assert_eq!(records, vec![record]);
+//! ```
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::FromStr;
+use serde::{de::DeserializeOwned, Serialize};
+use crate::RollingData;
+
+/// The length in bytes after which a record of a journal is considered corrupt
+const MAX_RECORD_LENGTH: u32 = 1 << 20;
+
+/// `StorageFormat` is the format the rolling data of the stocks get written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    /// One csv row per trade, in a file with the `csv` extension
+    #[default]
+    Csv,
+    /// The length-prefixed bincode journal, in a file with the `bin` extension
+    Bincode,
+    /// The tables of an SQLite database, which hold the candlestick and mean data too
+    Sqlite,
+}
+
+impl StorageFormat {
+    /// Returns the extension of the files the trades get written to in the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StorageFormat::Csv => "csv",
+            StorageFormat::Bincode => "bin",
+            StorageFormat::Sqlite => "sqlite",
+        }
+    }
+}
+
+impl FromStr for StorageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(StorageFormat::Csv),
+            "bincode" => Ok(StorageFormat::Bincode),
+            "sqlite" => Ok(StorageFormat::Sqlite),
+            _ => Err(format!("invalid storage format {}, expected csv, bincode or sqlite", s)),
+        }
+    }
+}
+
+impl fmt::Display for StorageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageFormat::Csv => write!(f, "csv"),
+            StorageFormat::Bincode => write!(f, "bincode"),
+            StorageFormat::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// Serializes the record and appends it, prefixed by its length, to the given writer
+/// with a single write. A record longer than the journals can be read back with fails
+/// to be written instead.
+///
+/// # Arguments
+/// - `writer` - the journal to append the record to, usually the rolling file of the stock
+/// - `record` - the record to append, usually a trade
+pub fn write_record<W: Write, T: Serialize>(mut writer: W, record: &T) -> io::Result<()> {
+    let payload = bincode::serialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let length = match u32::try_from(payload.len()) {
+        Ok(length) if length <= MAX_RECORD_LENGTH => length,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record of {} bytes is too long", payload.len()))),
+    };
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&length.to_le_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)
+}
+
+/// `JournalReader` iterates over the records of a journal, which are trades unless stated
+/// otherwise. A journal ending in the middle of a record, such as one written by a process
+/// which got killed, yields an `UnexpectedEof` error as its last item.
+pub struct JournalReader<R: Read, T = RollingData> {
+    /// reader: where the journal gets read from
+    reader: R,
+    /// done: whether the end of the journal or an error was reached
+    done: bool,
+    /// record: the type of the records of the journal
+    record: PhantomData<T>,
+}
+
+impl<R: Read, T: DeserializeOwned> JournalReader<R, T> {
+    /// Given where to read the journal from, creates and returns a reader at its first record
+    pub fn new(reader: R) -> Self {
+        JournalReader {
+            reader,
+            done: false,
+            record: PhantomData,
+        }
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<T>> {
+        let mut length = [0u8; 4];
+        let mut read = 0;
+        while read < length.len() {
+            match self.reader.read(&mut length[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let length = u32::from_le_bytes(length);
+        // a trade takes less than a hundred bytes and a frame of finnhub a few kilobytes,
+        // so a longer record can only be a corrupt length
+        if length > MAX_RECORD_LENGTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("record of {} bytes is too long", length)));
+        }
+        let mut payload = vec![0u8; length as usize];
+        self.reader.read_exact(&mut payload)?;
+        bincode::deserialize(&payload)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for JournalReader<R, T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Returns the length in bytes of the complete and valid records at the start of the journal,
+/// which is where a journal ending in the middle of a record should be truncated at. The records
+/// have to be walked from the start to find where each one begins, but only the ones ending in the
+/// last `tail` bytes get deserialized, the ones before them only get their length checked, the way
+/// only the tail of a csv file gets parsed. Any other error than the journal ending early gets returned.
+///
+/// # Arguments
+/// - `reader` - the journal, read from its start
+/// - `length` - the length of the journal in bytes
+/// - `tail` - how many bytes from the end of the journal get deserialized
+pub fn valid_length<R: Read + Seek>(reader: R, length: u64, tail: u64) -> io::Result<u64> {
+    let mut reader = BufReader::new(reader);
+    reader.seek(SeekFrom::Start(0))?;
+    let tail_start = length.saturating_sub(tail);
+    let mut valid = 0;
+    while length - valid >= 4 {
+        let mut prefix = [0u8; 4];
+        match reader.read_exact(&mut prefix) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        let record = u32::from_le_bytes(prefix);
+        let end = valid + 4 + record as u64;
+        if record > MAX_RECORD_LENGTH || end > length {
+            break;
+        }
+        if end > tail_start {
+            let mut payload = vec![0u8; record as usize];
+            match reader.read_exact(&mut payload) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            if bincode::deserialize::<RollingData>(&payload).is_err() {
+                break;
+            }
+        } else {
+            reader.seek_relative(record as i64)?;
+        }
+        valid = end;
+    }
+    Ok(valid)
+}
+
+/// Converts a journal to the csv layout of the rolling files, headers included, so that
+/// the tools reading the csv rolling files can be used on it. It returns the number of
+/// records converted.
+///
+/// # Arguments
+/// - `input` - the path of the journal
+/// - `output` - the path of the csv file to create. It gets overwritten if it exists
+pub fn convert(input: &Path, output: &Path) -> io::Result<u64> {
+    let reader = JournalReader::<_, RollingData>::new(BufReader::new(File::open(input)?));
+    let mut writer = csv::WriterBuilder::new().has_headers(true).from_writer(BufWriter::new(File::create(output)?));
+    let mut count = 0;
+    for record in reader {
+        writer.serialize(record?)?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+
+#[cfg(test)]
+mod journal_test {
+    use std::fs::{read_to_string, remove_file, File};
+    use std::io::{self, Cursor};
+    use std::path::Path;
+    use chrono::{Duration, TimeZone, Utc};
+    use serial_test::serial;
+    use crate::journal::{convert, valid_length, write_record, JournalReader, StorageFormat, MAX_RECORD_LENGTH};
+    use crate::utils::{create_dirs, find_items_between};
+    use crate::RollingData;
+
+    fn trades() -> Vec<RollingData> {
+        (0..3).map(|i| RollingData {
+            symbol: "BINANCE:BTCUSDT".parse().unwrap(),
+            price: 23061.05 + i as f64,
+            timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376) + Duration::seconds(i),
+            write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794) + Duration::seconds(i),
+            volume: 0.25 * i as f64,
+            conditions: (0..i).map(|c| c.to_string()).collect(),
+        }).collect()
+    }
+
+    #[test]
+    fn it_should_parse_the_storage_formats() {
+        assert_eq!("csv".parse::<StorageFormat>().unwrap(), StorageFormat::Csv);
+        assert_eq!("bincode".parse::<StorageFormat>().unwrap(), StorageFormat::Bincode);
+        assert_eq!("sqlite".parse::<StorageFormat>().unwrap(), StorageFormat::Sqlite);
+        assert!("json".parse::<StorageFormat>().is_err());
+        assert_eq!(StorageFormat::Bincode.to_string(), "bincode");
+    }
+
+    #[test]
+    fn given_a_truncated_journal_it_should_end_with_an_error() {
+        let mut journal = Vec::new();
+        trades().iter().for_each(|t| write_record(&mut journal, t).unwrap());
+        journal.truncate(journal.len() - 3);
+        let records: Vec<io::Result<RollingData>> = JournalReader::new(journal.as_slice()).collect();
+        assert_eq!(records.len(), 3);
+        assert!(records[..2].iter().all(|r| r.is_ok()));
+        assert_eq!(records[2].as_ref().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn given_an_oversized_length_it_should_fail_without_reading_the_record() {
+        let mut journal = Vec::new();
+        write_record(&mut journal, &trades()[0]).unwrap();
+        journal.extend_from_slice(&u32::MAX.to_le_bytes());
+        journal.extend_from_slice(&[0; 16]);
+        let records: Vec<io::Result<RollingData>> = JournalReader::new(journal.as_slice()).collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert_eq!(records[1].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let oversized = vec![0u8; MAX_RECORD_LENGTH as usize];
+        assert_eq!(write_record(Vec::new(), &oversized).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn given_a_corrupt_record_it_should_only_notice_it_in_the_tail() {
+        let mut journal = Vec::new();
+        trades().iter().for_each(|t| write_record(&mut journal, t).unwrap());
+        let valid = journal.len() as u64;
+        // a record too short to hold a trade
+        journal.extend_from_slice(&[3, 0, 0, 0, 255, 255, 255]);
+        let length = journal.len() as u64;
+        assert_eq!(valid_length(Cursor::new(&journal), length, 64).unwrap(), valid);
+        assert_eq!(valid_length(Cursor::new(&journal), length, 0).unwrap(), length);
+        // a length running past the end of the journal gets noticed anywhere
+        journal.extend_from_slice(&[200, 0, 0, 0, 1]);
+        assert_eq!(valid_length(Cursor::new(&journal), journal.len() as u64, 0).unwrap(), length);
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_convert_a_journal_to_the_csv_layout() {
+        create_dirs("tmp");
+        let mut journal = File::create("tmp/convert.bin").unwrap();
+        trades().iter().for_each(|t| write_record(&mut journal, t).unwrap());
+        drop(journal);
+        let count = convert(Path::new("tmp/convert.bin"), Path::new("tmp/convert.csv")).unwrap();
+        assert_eq!(count, 3);
+        let data = read_to_string("tmp/convert.csv").unwrap();
+        assert_eq!(data.lines().next().unwrap(), "Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions");
+        let mut records = Vec::new();
+        let mut file = File::open("tmp/convert.csv").unwrap();
+        find_items_between(&mut file, Utc.ymd(2022, 7, 21).and_hms(22, 0, 0), Utc.ymd(2022, 7, 21).and_hms(23, 0, 0), &mut records).unwrap();
+        assert_eq!(records, trades());
+        remove_file("tmp/convert.bin").unwrap();
+        remove_file("tmp/convert.csv").unwrap();
+    }
+}
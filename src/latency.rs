@@ -0,0 +1,393 @@
+//! Latency primitives
+//! # latency
+//!
+//! This contains the latency tracking of the stocks. Two latencies get measured for every
+//! trade written:
+//! - the exchange latency, the time between the exchange timestamp of the trade and the
+//!   time it got written, which is what the `Timestamp` and `WriteTimestamp` columns of the
+//!   rolling files hold
+//! - the processing latency, the time between the websocket frame holding the trade being
+//!   received and the trade being handed to the store of the stock. With `--flush-interval-ms`
+//!   the store buffers the trades, so it only covers the time until they got buffered, not
+//!   the time until they got written to the disk
+//!
+//! Their min, p50, p95, p99 and max get written periodically to a file for each stock under
+//! data/latency, while the exchange latency of the rolling files already written can be
+//! reported with `rolling_latency`. The latencies get counted in a `LatencyHistogram`, so that
+//! the memory they take doesn't grow with the number of trades.
+//!
+//! # Example
+//! ```
+//! use finnhub_ws::latency::LatencyStats;
+//! let stats = LatencyStats::from_samples(&mut vec![30, 10, 20, 40, 1000]).unwrap();
+//! assert_eq!((stats.min, stats.p50, stats.max), (10, 30, 1000));
+//! assert!(LatencyStats::from_samples(&mut vec![]).is_none());
+//! ```
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc, serde::ts_milliseconds};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::journal::StorageFormat;
+use crate::store::read_rolling_file;
+use crate::utils::{append_csv, create_dirs, open_append, sanitize_string};
+use crate::RollingData;
+
+/// The directory of the data directory the latency files get written to
+const LATENCY_DIR: &str = "latency";
+
+/// `LatencyStats` summarizes the latencies of a number of trades, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// the number of trades the latencies are of
+    pub count: usize,
+    /// the lowest latency
+    pub min: i64,
+    /// the median latency
+    pub p50: i64,
+    /// the latency 95% of the trades were at or below
+    pub p95: i64,
+    /// the latency 99% of the trades were at or below
+    pub p99: i64,
+    /// the highest latency
+    pub max: i64,
+}
+
+impl LatencyStats {
+    /// Given the latencies in milliseconds, which get sorted in place, returns their
+    /// summary with nearest-rank percentiles, or None if there aren't any
+    pub fn from_samples(samples: &mut [i64]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let rank = |percentile: usize| {
+            let index = (percentile * samples.len()).div_ceil(100);
+            samples[index.saturating_sub(1)]
+        };
+        Some(LatencyStats {
+            count: samples.len(),
+            min: samples[0],
+            p50: rank(50),
+            p95: rank(95),
+            p99: rank(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} trades, min {}ms, p50 {}ms, p95 {}ms, p99 {}ms, max {}ms",
+               self.count, self.min, self.p50, self.p95, self.p99, self.max)
+    }
+}
+
+/// `LatencyHistogram` counts latencies in fixed buckets, so that the percentiles of any number
+/// of trades can be calculated in bounded memory. The latencies under a second get a bucket of
+/// their own, while the longer ones get rounded towards zero to three significant digits, which
+/// keeps their percentiles within 1%. The min and the max are exact.
+///
+/// # Example
+/// ```
+/// use finnhub_ws::latency::{LatencyHistogram, LatencyStats};
+/// let mut histogram = LatencyHistogram::default();
+/// [30, 10, 20, 40, 123_456].into_iter().for_each(|latency| histogram.record(latency));
+/// let stats = histogram.stats().unwrap();
+/// // the percentile of the latency over a second got rounded, while the max is exact
+/// assert_eq!((stats.min, stats.p50, stats.p99, stats.max), (10, 30, 123_000, 123_456));
+/// assert!(LatencyHistogram::default().stats().is_none());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// the number of latencies in each bucket, by the value the bucket stands for
+    buckets: BTreeMap<i64, u64>,
+    /// the number of latencies recorded
+    count: u64,
+    /// the lowest latency recorded
+    min: i64,
+    /// the highest latency recorded
+    max: i64,
+}
+
+impl LatencyHistogram {
+    /// Counts a latency in milliseconds
+    pub fn record(&mut self, latency: i64) {
+        *self.buckets.entry(bucket(latency)).or_insert(0) += 1;
+        self.min = if self.count == 0 { latency } else { self.min.min(latency) };
+        self.max = if self.count == 0 { latency } else { self.max.max(latency) };
+        self.count += 1;
+    }
+
+    /// Returns the summary of the latencies recorded with nearest-rank percentiles, or None if
+    /// there aren't any
+    pub fn stats(&self) -> Option<LatencyStats> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = |percentile: u64| {
+            let index = (percentile * self.count).div_ceil(100).max(1);
+            let mut seen = 0;
+            let value = self.buckets.iter()
+                .find(|(_, count)| {
+                    seen += **count;
+                    seen >= index
+                })
+                .map_or(self.max, |(value, _)| *value);
+            value.clamp(self.min, self.max)
+        };
+        Some(LatencyStats {
+            count: self.count as usize,
+            min: self.min,
+            p50: rank(50),
+            p95: rank(95),
+            p99: rank(99),
+            max: self.max,
+        })
+    }
+}
+
+/// Returns the value of the bucket a latency gets counted in
+fn bucket(latency: i64) -> i64 {
+    let magnitude = latency.unsigned_abs();
+    if magnitude < 1000 {
+        return latency;
+    }
+    let scale = 10u64.pow(magnitude.ilog10() - 2);
+    (magnitude / scale * scale) as i64 * latency.signum()
+}
+
+/// `LatencyRow`: represents the row written to the latency file of a stock for each
+/// kind of latency at the end of every period
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct LatencyRow {
+    /// stock symbol the latencies are of
+    pub symbol: String,
+    /// timestamp with millisecond precision of the end of the period
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+    /// the kind of latency, either exchange or processing
+    pub kind: String,
+    /// the number of trades of the period
+    pub count: usize,
+    /// the lowest latency of the period in milliseconds
+    pub min_ms: i64,
+    /// the median latency of the period in milliseconds
+    pub p50_ms: i64,
+    /// the 95th percentile of the latencies of the period in milliseconds
+    pub p95_ms: i64,
+    /// the 99th percentile of the latencies of the period in milliseconds
+    pub p99_ms: i64,
+    /// the highest latency of the period in milliseconds
+    pub max_ms: i64,
+}
+
+impl LatencyRow {
+    /// Given the stock symbol, the end of the period, the kind of latency and its
+    /// summary, creates and returns the row to be written to the latency file
+    pub fn new(symbol: &str, timestamp: DateTime<Utc>, kind: &str, stats: &LatencyStats) -> Self {
+        LatencyRow {
+            symbol: symbol.to_string(),
+            timestamp,
+            kind: kind.to_string(),
+            count: stats.count,
+            min_ms: stats.min,
+            p50_ms: stats.p50,
+            p95_ms: stats.p95,
+            p99_ms: stats.p99,
+            max_ms: stats.max,
+        }
+    }
+}
+
+/// The latencies recorded since the last period got written
+#[derive(Debug, Default)]
+struct Samples {
+    exchange: LatencyHistogram,
+    processing: LatencyHistogram,
+}
+
+/// `LatencyTracker` collects the latencies of the trades of a stock until they get
+/// written at the end of the period. It has a mutex so that it can be shared between threads.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    samples: Mutex<Samples>,
+}
+
+impl LatencyTracker {
+    /// Records the latencies of the trades handed to the store at `written`, which arrived
+    /// in a websocket frame received at `received`. The store may only have buffered them by then.
+    pub fn record(&self, records: &[RollingData], received: DateTime<Utc>, written: DateTime<Utc>) {
+        let processing = (written - received).num_milliseconds();
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        for record in records {
+            samples.exchange.record((record.write_timestamp - record.timestamp).num_milliseconds());
+            samples.processing.record(processing);
+        }
+    }
+
+    /// Returns the rows summarizing the latencies recorded since the last call, one for
+    /// each kind of latency, and starts the next period. There are none if no trades got recorded.
+    pub fn take_rows(&self, symbol: &str, timestamp: DateTime<Utc>) -> Vec<LatencyRow> {
+        let samples = std::mem::take(&mut *self.samples.lock().unwrap_or_else(|e| e.into_inner()));
+        [("exchange", &samples.exchange), ("processing", &samples.processing)].into_iter()
+            .filter_map(|(kind, histogram)| histogram.stats().map(|stats| LatencyRow::new(symbol, timestamp, kind, &stats)))
+            .collect()
+    }
+
+    /// Writes the latencies recorded since the last call to the latency file of the stock,
+    /// which is located under {data_dir}/latency and named as {sanitized_stock_symbol}.csv.
+    /// The headers are written only when the file is empty.
+    pub fn write(&self, data_dir: &Path, symbol: &str, timestamp: DateTime<Utc>) -> Result<()> {
+        let rows = self.take_rows(symbol, timestamp);
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let dir = data_dir.join(LATENCY_DIR);
+        if !create_dirs(&dir.to_string_lossy()) {
+            return Err(io::Error::other(format!("couldn't create {}", dir.display())).into());
+        }
+        let file = open_append(&dir.join(format!("{}.csv", sanitize_string(symbol))))?;
+        append_csv(&file, rows)
+    }
+}
+
+/// Returns the format of a rolling file by its extension, compressed with gzip or not
+pub(crate) fn rolling_format(path: &Path) -> Option<StorageFormat> {
+    let name = path.file_name()?.to_str()?;
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    [StorageFormat::Csv, StorageFormat::Bincode].into_iter()
+        .find(|format| name.ends_with(&format!(".{}", format.extension())))
+}
+
+/// Walks the rolling files under the given directory, such as data/rolling, and returns
+/// the exchange latency of their trades by stock symbol. Both the csv and bincode files
+/// get read, compressed with gzip or not.
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use finnhub_ws::latency::rolling_latency;
+/// let latency = rolling_latency(Path::new("data/no-such-dir"));
+/// assert!(latency.is_err());
+/// ```
+pub fn rolling_latency(dir: &Path) -> io::Result<BTreeMap<String, LatencyStats>> {
+    let mut samples: BTreeMap<String, LatencyHistogram> = BTreeMap::new();
+    read_latency_dir(dir, &mut samples)?;
+    Ok(samples.into_iter()
+        .filter_map(|(symbol, histogram)| histogram.stats().map(|stats| (symbol, stats)))
+        .collect())
+}
+
+/// Counts the exchange latency of the trades of the rolling files under the directory to `samples`
+fn read_latency_dir(dir: &Path, samples: &mut BTreeMap<String, LatencyHistogram>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_latency_dir(&path, samples)?;
+            continue;
+        }
+        let format = match rolling_format(&path) {
+            Some(format) => format,
+            None => continue,
+        };
+        let read = read_rolling_file(&path, format, |record| {
+            let latency = (record.write_timestamp - record.timestamp).num_milliseconds();
+            samples.entry(record.symbol).or_default().record(latency);
+        });
+        if let Err(e) = read {
+            eprintln!("Couldn't read {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod latency_test {
+    use std::fs::{read_to_string, remove_dir_all};
+    use std::path::Path;
+    use chrono::{Duration, TimeZone, Utc};
+    use serial_test::serial;
+    use crate::latency::{rolling_latency, LatencyHistogram, LatencyStats, LatencyTracker};
+    use crate::utils::create_dirs;
+    use crate::RollingData;
+
+    fn trade(symbol: &str, latency: i64) -> RollingData {
+        let timestamp = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+        RollingData {
+            symbol: symbol.to_string(),
+            price: 172.5,
+            timestamp,
+            write_timestamp: timestamp + Duration::milliseconds(latency),
+            volume: 1.0,
+            conditions: vec![],
+        }
+    }
+
+    #[test]
+    fn given_a_hundred_samples_it_should_return_the_nearest_rank_percentiles() {
+        let mut samples: Vec<i64> = (1..=100).rev().collect();
+        let stats = LatencyStats::from_samples(&mut samples).unwrap();
+        assert_eq!(stats, LatencyStats { count: 100, min: 1, p50: 50, p95: 95, p99: 99, max: 100 });
+    }
+
+    #[test]
+    fn given_long_latencies_it_should_keep_the_percentiles_within_a_percent() {
+        let mut histogram = LatencyHistogram::default();
+        let mut samples: Vec<i64> = (0..100_000).map(|i| i * 37 % 250_000 - 1_000).collect();
+        samples.iter().for_each(|latency| histogram.record(*latency));
+        let (approximate, exact) = (histogram.stats().unwrap(), LatencyStats::from_samples(&mut samples).unwrap());
+        assert_eq!((approximate.count, approximate.min, approximate.max), (exact.count, exact.min, exact.max));
+        for (approximate, exact) in [(approximate.p50, exact.p50), (approximate.p95, exact.p95), (approximate.p99, exact.p99)] {
+            assert!((approximate - exact).abs() * 100 <= exact.abs(), "{} {}", approximate, exact);
+        }
+        // the buckets take a fraction of the samples
+        assert!(histogram.buckets.len() < 4_000);
+    }
+
+    #[test]
+    fn it_should_write_the_latencies_of_the_period_and_start_the_next_one() {
+        let tracker = LatencyTracker::default();
+        let received = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 40, 0);
+        tracker.record(&[trade("LAT:WRITE", 5), trade("LAT:WRITE", 15)], received, received + Duration::milliseconds(2));
+        let end = Utc.ymd(2022, 7, 21).and_hms(22, 8, 0);
+        let dir = Path::new("tmp/latency_write");
+        let _ = remove_dir_all(dir);
+        tracker.write(dir, "LAT:WRITE", end).unwrap();
+        // nothing got recorded since, so nothing gets written
+        tracker.write(dir, "LAT:WRITE", end).unwrap();
+        let data = read_to_string("tmp/latency_write/latency/LAT_WRITE.csv").unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines, vec![
+            "Symbol,Timestamp,Kind,Count,MinMs,P50Ms,P95Ms,P99Ms,MaxMs",
+            "LAT:WRITE,1658441280000,exchange,2,5,5,15,15,15",
+            "LAT:WRITE,1658441280000,processing,2,2,2,2,2,2",
+        ]);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn given_rolling_files_it_should_report_the_latency_of_each_symbol() {
+        let dir = "tmp/latency/2022-07-21";
+        let _ = create_dirs(dir);
+        let mut writer = csv::Writer::from_path(format!("{}/LAT_A.csv", dir)).unwrap();
+        for latency in [10, 20, 30] {
+            writer.serialize(trade("LAT:A", latency)).unwrap();
+        }
+        writer.serialize(trade("LAT:B", 7)).unwrap();
+        writer.flush().unwrap();
+        std::fs::write(format!("{}/notes.txt", dir), "not a rolling file").unwrap();
+        let latency = rolling_latency(Path::new("tmp/latency")).unwrap();
+        assert_eq!(latency.len(), 2);
+        assert_eq!((latency["LAT:A"].count, latency["LAT:A"].p50, latency["LAT:A"].max), (3, 20, 30));
+        assert_eq!(latency["LAT:B"].min, 7);
+        std::fs::remove_dir_all("tmp/latency").unwrap();
+    }
+}
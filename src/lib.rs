@@ -9,23 +9,51 @@
 //!
 //! This uses Tokio and Tungstenite to connect to finnhub.io using their websocket APIs, subscribes
 //! to stocks and whenever a new transaction is made and obtained by the listening channel,
-//! it gets written to the corresponding rolling file. Each minute and for each stock, a message is
-//! sent to other channels to calculate the candlestick for the last minute alongside mean price of
-//! each stock for the last fifteen minutes. The results are then written back to a file, a separate
-//! one for each stock.
+//! it gets written to the corresponding rolling file and fed to the incremental aggregators of
+//! the stock. Each minute and for each stock, a message is sent to other channels to close the
+//! window of the aggregators and get the candlesticks of the intervals that just ended (one minute
+//! by default) alongside the mean price of each stock over its mean windows (the last fifteen
+//! minutes by default). The results are then written back to a file, a separate one for each
+//! stock and candlestick interval. The news of the stocks can be subscribed to as well, in which
+//! case their headlines get written to a file of each stock and the candlesticks get flagged.
+//!
+//! The connection is supervised, so whenever it drops, the client reconnects using a jittered
+//! exponential backoff, subscribes again to the stocks and logs the downtime to a dedicated file.
 pub mod cli;
+pub mod error;
+pub mod clock;
 pub mod stock_handle;
 pub mod utils;
 pub mod candlestick;
+pub mod condition;
 pub mod mean;
+pub mod connection;
+pub mod pipeline;
+pub mod window;
+pub mod interval;
+pub mod journal;
+pub mod rotation;
+pub mod recovery;
+pub mod store;
+pub mod mock;
+pub mod news;
+pub mod control;
+pub mod latency;
+pub mod scheduler;
+pub mod replay;
+pub mod recorder;
 use std::{fs::{File, OpenOptions, create_dir_all}, path::{PathBuf}, io};
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use chrono::{DateTime, Utc, serde::{ts_milliseconds}};
 use csv::StringRecord;
+use crate::clock::{Clock, SystemClock};
+use crate::error::Result;
+use crate::news::NewsItem;
 
 /// ```
+/// use finnhub_ws::clock::Clock;
 /// trait CSVAble {
-///     fn vectorize(&self) -> Vec<String>;
+///     fn vectorize(&self, clock: &dyn Clock) -> Vec<String>;
 ///     fn get_headers(&self) -> Vec<String>;
 /// }
 /// ```
@@ -34,8 +62,8 @@ use csv::StringRecord;
 trait CSVAble {
     /// Given a struct that implements the `CSVAble` trait, this should convert
     /// the fields to be written to a csv file to String and return a vector with
-    /// those fields
-    fn vectorize(&self) -> Vec<String>;
+    /// those fields. The time of writing gets read from `clock`
+    fn vectorize(&self, clock: &dyn Clock) -> Vec<String>;
     /// Given a struct that implements the `CSVAble` trait, this should convert
     /// the field names of the fields to be written to a csv file to String
     /// and return a vector with those fields
@@ -85,17 +113,30 @@ pub struct TickerInfo {
 /// as it can be seen [here](https://finnhub.io/docs/api/websocket-trades).
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Response<'a> {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "trade_type")]
     pub transaction_type: &'a str,
     #[serde(rename = "data")]
     pub transaction_data: Vec<TickerInfo>,
+    /// The time the message got received, which the processing latency of its trades is measured from
+    #[serde(skip, default = "Utc::now")]
+    pub received: DateTime<Utc>,
+}
+
+/// `News` is a struct which represents the news messages of the finnhub api, which
+/// get sent for the stocks subscribed to with `subscribe-news`.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct News<'a> {
+    #[serde(rename = "type", deserialize_with = "news_type")]
+    pub message_type: &'a str,
+    #[serde(rename = "data")]
+    pub news_data: Vec<NewsItem>,
 }
 
 /// `Ping` is a struct which represents the ping action which may be the response
 /// obtained by the finnhub api.
 #[derive(Deserialize, Debug, Serialize)]
 pub struct Ping<'a> {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "ping_type")]
     pub action_type: &'a str
 }
 
@@ -106,8 +147,42 @@ pub struct WsError<'a> {
     pub message: &'a str
 }
 
-/// `WsMessage` represents the three possible response types from the finnhub api for the
-/// deserialization if the response to always be valid
+/// Accepts only the type of the trade messages, so that the other messages
+/// carrying data, such as news, don't get mistaken for trades
+fn trade_type<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<&'de str, D::Error> {
+    message_type(deserializer, "trade")
+}
+
+/// Accepts only the type of the news messages
+fn news_type<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<&'de str, D::Error> {
+    message_type(deserializer, "news")
+}
+
+/// Accepts only the type of the ping messages, so that any other message
+/// with a type doesn't get mistaken for a ping
+fn ping_type<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<&'de str, D::Error> {
+    message_type(deserializer, "ping")
+}
+
+fn message_type<'de, D: Deserializer<'de>>(deserializer: D, expected: &str) -> std::result::Result<&'de str, D::Error> {
+    let found = <&str>::deserialize(deserializer)?;
+    if found == expected {
+        Ok(found)
+    } else {
+        Err(de::Error::invalid_value(de::Unexpected::Str(found), &expected))
+    }
+}
+
+/// `WsMessage` represents the possible response types from the finnhub api for the
+/// deserialization of any valid json message to always succeed. The messages the
+/// program doesn't know about end up as `Unknown`.
+///
+/// # Example
+/// ```
+/// use finnhub_ws::WsMessage;
+/// let message: WsMessage = serde_json::from_str(r#"{"type":"subscribed","symbol":"AAPL"}"#).unwrap();
+/// assert!(matches!(message, WsMessage::Unknown(_)));
+/// ```
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged, rename_all = "lowercase")]
 pub enum WsMessage<'a> {
@@ -122,13 +197,51 @@ pub enum WsMessage<'a> {
     /// The response type be used when the finnhub api is just pinging to check
     /// if the client is still alive.
     #[serde(borrow = "'a")]
-    Ping(Ping<'a>)
+    Ping(Ping<'a>),
+    /// The response type to be used on getting the news of the stocks
+    /// subscribed to with `subscribe-news`
+    #[serde(borrow = "'a")]
+    News(News<'a>),
+    /// The response type to be used for any other message, such as
+    /// subscription acknowledgements. It keeps the json of the message, so
+    /// that it can be logged.
+    Unknown(serde_json::Value),
+}
+
+impl<'a> WsMessage<'a> {
+    /// Given the bytes of a websocket message, it deserializes them to a `WsMessage`. Unlike
+    /// deserializing them directly, a message of a type the program knows about, such as a trade,
+    /// whose payload doesn't match the type fails with the reason it doesn't, rather than
+    /// ending up as `Unknown`, so that it gets counted as malformed.
+    ///
+    /// # Example
+    /// ```
+    /// use finnhub_ws::WsMessage;
+    /// let ack = WsMessage::parse(br#"{"type":"subscribed","symbol":"AAPL"}"#).unwrap();
+    /// assert!(matches!(ack, WsMessage::Unknown(_)));
+    /// let error = WsMessage::parse(br#"{"type":"trade","data":[{"s":"AAPL"}]}"#).unwrap_err();
+    /// assert!(error.to_string().starts_with("missing field `p`"));
+    /// ```
+    pub fn parse(raw: &'a [u8]) -> serde_json::Result<Self> {
+        let message = serde_json::from_slice::<WsMessage>(raw)?;
+        let known = match &message {
+            WsMessage::Unknown(value) => value.get("type").and_then(|t| t.as_str()),
+            _ => None,
+        };
+        match known {
+            Some("trade") => serde_json::from_slice::<Response>(raw).map(WsMessage::Response),
+            Some("news") => serde_json::from_slice::<News>(raw).map(WsMessage::News),
+            Some("ping") => serde_json::from_slice::<Ping>(raw).map(WsMessage::Ping),
+            Some("error") => serde_json::from_slice::<WsError>(raw).map(WsMessage::Error),
+            _ => Ok(message),
+        }
+    }
 }
 
 /// `RollingData`: represents the data structure which is being used to serialize
 /// and deserialize the transaction data being written to file as they arrive
 /// from finnhub.io
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct RollingData {
     /// stock symbol of transaction being written
@@ -140,20 +253,29 @@ pub struct RollingData {
     pub timestamp: DateTime<Utc>,
     /// timestamp with millisecond precision of writing the transaction to file
     #[serde(with = "ts_milliseconds")]
-    pub write_timestamp: DateTime<Utc>
+    pub write_timestamp: DateTime<Utc>,
+    /// number of stocks of the transaction. Rolling files written before the
+    /// volume was persisted don't have this column, so it defaults to zero
+    #[serde(default)]
+    pub volume: f64,
+    /// condition codes of the transaction, written as a single column separated by semicolons
+    #[serde(default, serialize_with = "condition::serialize_codes", deserialize_with = "condition::deserialize_codes")]
+    pub conditions: Vec<String>,
 }
 
-impl TickerInfo {
-    pub fn default() -> Self {
+impl Default for TickerInfo {
+    fn default() -> Self {
         TickerInfo {
             symbol: "".parse().unwrap(),
             price: 0.0,
             volume: 0.0,
-            time: Utc::now(),
+            time: SystemClock.now(),
             conditions: Some(vec![]),
         }
     }
+}
 
+impl TickerInfo {
     /// Given the stock symbol, its price, the time of transaction and its conditions,
     /// creates and returns a new instance of TickerInfo
     /// # Arguments
@@ -185,6 +307,7 @@ impl TickerInfo {
     /// instance to string using the vectorize method and writes that to the csv.
     /// # Arguments
     /// - file: A reference to the rolling file of that stock symbol
+    /// - clock: The clock the write timestamp gets read from
     ///
     /// # Example
     /// ```
@@ -192,6 +315,7 @@ impl TickerInfo {
     /// use std::io::{Seek, SeekFrom};
     /// use chrono::{DateTime, TimeZone, Utc};
     /// use finnhub_ws::TickerInfo;
+    /// use finnhub_ws::clock::SystemClock;
     /// let mut file = OpenOptions::new()
     ///        .write(true)
     ///        .append(true)
@@ -201,12 +325,13 @@ impl TickerInfo {
     /// let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
     /// let conditions: Vec<String> = vec!["".parse().unwrap()];
     /// let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-    /// ticker.write_to_disk(&file);
+    /// ticker.write_to_disk(&file, &SystemClock).unwrap();
     /// std::fs::remove_file("BINANCE_BTCUSDT.csv").unwrap();
-    pub fn write_to_disk(&self, file: &File) {
+    pub fn write_to_disk(&self, file: &File, clock: &dyn Clock) -> Result<()> {
         let mut writer = csv::WriterBuilder::new().has_headers(true).from_writer(file);
-        writer.serialize(self.vectorize()).unwrap();
-        writer.flush().unwrap();
+        writer.serialize(self.vectorize(clock))?;
+        writer.flush()?;
+        Ok(())
     }
 
 
@@ -230,72 +355,136 @@ impl TickerInfo {
     /// let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
     /// let conditions: Vec<String> = vec!["".parse().unwrap()];
     /// let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-    /// assert_eq!(ticker.check_file_empty(&file), true);
+    /// assert_eq!(ticker.check_file_empty(&file).unwrap(), true);
     /// std::fs::remove_file("BINANCE_BTCUSDT.csv").unwrap();
-    pub fn check_file_empty(&self, file: &File) -> bool {
+    pub fn check_file_empty(&self, file: &File) -> Result<bool> {
         // has headers has been set to false as it will skip the first record
         let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(file);
         let mut rec = StringRecord::new();
-        !reader.read_record(&mut rec).unwrap()
+        Ok(!reader.read_record(&mut rec)?)
     }
 
-    fn check_file_exists(&self, path: &PathBuf) -> File {
-        create_dir_all(path.parent().unwrap()).unwrap();
+    #[allow(dead_code)]
+    fn check_file_exists(&self, path: &PathBuf) -> Result<File> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
         match OpenOptions::new()
-            .write(true)
             .append(true)
             .read(true)
             .open(path) {
-            Ok(f) => f,
+            Ok(f) => Ok(f),
             Err(err) => match err.kind() {
                 io::ErrorKind::NotFound => {
                     let f = OpenOptions::new()
-                        .write(true)
                         .append(true)
                         .read(true)
                         .create(true)
-                        .open(path)
-                        .unwrap();
-                    self.write_headers(&f);
-                    f
+                        .open(path)?;
+                    self.write_headers(&f)?;
+                    Ok(f)
                 }
-                _ => panic!("Problem opening the file")
+                _ => Err(err.into())
             }
         }
     }
 
-    pub fn write_headers(&self, f: &File) {
+    pub fn write_headers(&self, f: &File) -> Result<()> {
         let mut writer = csv::WriterBuilder::new().has_headers(true).from_writer(f);
-        writer.serialize(self.get_headers()).unwrap();
-        writer.flush().unwrap();
+        writer.serialize(self.get_headers())?;
+        writer.flush()?;
+        Ok(())
     }
 }
 
 impl CSVAble for TickerInfo {
     /// Method, implementation of CSVAble trait. Serializes the necessary TickerInfo fields
     /// and returns a vector of stringified fields.
-    fn vectorize(&self) -> Vec<String> {
-        return vec![self.symbol.clone(),
-                    self.price.to_string(),
-                    self.time.timestamp_millis().to_string(),
-                    Utc::now().timestamp_millis().to_string(),
-        ];
+    fn vectorize(&self, clock: &dyn Clock) -> Vec<String> {
+        vec![self.symbol.clone(),
+             self.price.to_string(),
+             self.time.timestamp_millis().to_string(),
+             clock.now().timestamp_millis().to_string(),
+             self.volume.to_string(),
+             condition::join_codes(self.conditions.as_deref().unwrap_or_default()),
+        ]
     }
 
     /// Returns a vector of strings containing the field names of the serialized data.
     fn get_headers(&self) -> Vec<String> {
-        return vec!["Symbol".to_string(),
-                    "Price".to_string(),
-                    "Timestamp".to_string(),
-                    "WriteTimestamp".to_string()];
+        vec!["Symbol".to_string(),
+             "Price".to_string(),
+             "Timestamp".to_string(),
+             "WriteTimestamp".to_string(),
+             "Volume".to_string(),
+             "Conditions".to_string()]
+    }
+}
+
+impl From<&TickerInfo> for RollingData {
+    /// Converts the ticker to the record that gets written to the rolling file,
+    /// using the time of the system clock as the write timestamp
+    fn from(ticker: &TickerInfo) -> Self {
+        RollingData::new(ticker, SystemClock.now())
+    }
+}
+
+impl RollingData {
+    /// Given the ticker and the time it gets written at, creates and returns the record
+    /// that gets written to the rolling file
+    ///
+    /// # Example
+    /// ```
+    /// use chrono::{TimeZone, Utc};
+    /// use finnhub_ws::{RollingData, TickerInfo};
+    /// let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+    /// let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &[]);
+    /// let record = RollingData::new(&ticker, date + chrono::Duration::milliseconds(40));
+    /// assert_eq!((record.write_timestamp - record.timestamp).num_milliseconds(), 40);
+    /// ```
+    pub fn new(ticker: &TickerInfo, write_timestamp: DateTime<Utc>) -> Self {
+        RollingData {
+            symbol: ticker.symbol.clone(),
+            price: ticker.price,
+            timestamp: ticker.time,
+            write_timestamp,
+            volume: ticker.volume,
+            conditions: ticker.conditions.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Method used to append the record to the rolling file of its stock symbol.
+    /// The headers are expected to be written already.
+    ///
+    /// # Example
+    /// ```
+    /// use std::fs::OpenOptions;
+    /// use chrono::{TimeZone, Utc};
+    /// use finnhub_ws::{RollingData, TickerInfo};
+    /// let file = OpenOptions::new()
+    ///        .append(true)
+    ///        .create(true)
+    ///        .read(true)
+    ///        .open("rolling_data_write_to_file.csv").unwrap();
+    /// let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+    /// let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &[]);
+    /// RollingData::from(&ticker).write_to_file(&file).unwrap();
+    /// std::fs::remove_file("rolling_data_write_to_file.csv").unwrap();
+    /// ```
+    pub fn write_to_file(&self, file: &File) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+        writer.serialize(self)?;
+        writer.flush()?;
+        Ok(())
     }
 }
 
-impl<'a> Response<'a> {
-    pub fn default() -> Self {
+impl<'a> Default for Response<'a> {
+    fn default() -> Self {
         Response {
             transaction_type: "trade",
             transaction_data: vec![],
+            received: SystemClock.now(),
         }
     }
 }
@@ -311,12 +500,67 @@ impl<'a> SubscribeInfo<'a> {
     /// let stock_info = SubscribeInfo::new("AAPL");
     /// assert_eq!(stock_info.stock_symbol, "AAPL");
     /// assert_eq!(stock_info.message_type, "subscribe");
+    /// ```
     pub fn new(symbol: &'a str) -> Self {
         SubscribeInfo {
             message_type: "subscribe",
             stock_symbol: symbol,
         }
     }
+
+    /// Given a string slice containing the stock to stop tracking,
+    /// this creates the necessary struct instance to send
+    /// to the finnhub api
+    ///
+    /// # Example
+    /// ```
+    /// use finnhub_ws::SubscribeInfo;
+    /// let stock_info = SubscribeInfo::unsubscribe("AAPL");
+    /// assert_eq!(stock_info.stock_symbol, "AAPL");
+    /// assert_eq!(stock_info.message_type, "unsubscribe");
+    /// ```
+    pub fn unsubscribe(symbol: &'a str) -> Self {
+        SubscribeInfo {
+            message_type: "unsubscribe",
+            stock_symbol: symbol,
+        }
+    }
+
+    /// Given a string slice containing the stock to get the news of,
+    /// this creates the necessary struct instance to send
+    /// to the finnhub api
+    ///
+    /// # Example
+    /// ```
+    /// use finnhub_ws::SubscribeInfo;
+    /// let stock_info = SubscribeInfo::news("AAPL");
+    /// assert_eq!(stock_info.stock_symbol, "AAPL");
+    /// assert_eq!(stock_info.message_type, "subscribe-news");
+    /// ```
+    pub fn news(symbol: &'a str) -> Self {
+        SubscribeInfo {
+            message_type: "subscribe-news",
+            stock_symbol: symbol,
+        }
+    }
+
+    /// Given a string slice containing the stock to stop getting the news of,
+    /// this creates the necessary struct instance to send
+    /// to the finnhub api
+    ///
+    /// # Example
+    /// ```
+    /// use finnhub_ws::SubscribeInfo;
+    /// let stock_info = SubscribeInfo::unsubscribe_news("AAPL");
+    /// assert_eq!(stock_info.stock_symbol, "AAPL");
+    /// assert_eq!(stock_info.message_type, "unsubscribe-news");
+    /// ```
+    pub fn unsubscribe_news(symbol: &'a str) -> Self {
+        SubscribeInfo {
+            message_type: "unsubscribe-news",
+            stock_symbol: symbol,
+        }
+    }
 }
 
 
@@ -324,9 +568,9 @@ impl<'a> SubscribeInfo<'a> {
 mod finnhub_ws_lib_test {
     use std::fs::OpenOptions;
     use std::io::{Read, Seek, SeekFrom};
-    use chrono::{DateTime, DurationRound, TimeZone, Utc};
-    use crate::{CSVAble, TickerInfo};
-    use serial_test::serial;
+    use chrono::{DateTime, TimeZone, Utc};
+    use crate::{CSVAble, TickerInfo, WsMessage};
+    use crate::clock::{ManualClock, SystemClock};
 
     #[test]
     fn given_arguments_should_create_valid_ticker_info(){
@@ -338,19 +582,18 @@ mod finnhub_ws_lib_test {
 
     #[test]
     fn should_create_default_ticker_info(){
+        let before = Utc::now();
         let ticker = TickerInfo::default();
-        println!("{}", ticker.time);
         assert_eq!(ticker.symbol, "");
         assert_eq!(ticker.conditions, Some(vec![]));
         assert_eq!(ticker.volume, 0.0);
         assert_eq!(ticker.price, 0.0);
-        assert_eq!(ticker.time.duration_trunc(chrono::Duration::minutes(1)).unwrap(), Utc::now().duration_trunc(chrono::Duration::minutes(1)).unwrap())
+        assert!(ticker.time >= before && ticker.time <= Utc::now());
     }
 
     #[test]
     fn given_a_ticker_info_instance_should_write_to_file(){
         let mut file = OpenOptions::new()
-                .write(true)
                 .append(true)
                 .create(true)
                 .read(true)
@@ -358,20 +601,21 @@ mod finnhub_ws_lib_test {
         let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
         let conditions: Vec<String> = vec![];
         let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-        ticker.write_to_disk(&file);
+        let clock = ManualClock::new(date + chrono::Duration::milliseconds(418));
+        ticker.write_to_disk(&file, &clock).unwrap();
         let mut data = String::new();
         file.seek(SeekFrom::Start(0)).unwrap();
         file.read_to_string(&mut data).unwrap();
         data.truncate(data.len() -1 );
         let got = data.split(',').collect::<Vec<&str>>();
-        assert_eq!(got[0..got.len()-1], ticker.vectorize()[0..got.len()-1]);
+        assert_eq!(got, ticker.vectorize(&clock));
+        assert_eq!(got, vec!["BINANCE:BTCUSDT", "23841.51", "1658441258376", "1658441258794", "1", ""]);
         std::fs::remove_file("given_a_ticker_info_instance_should_write_to_file.csv").unwrap();
     }
 
     #[test]
     fn given_a_non_empty_file_check_if_file_is_empty(){
         let mut file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .read(true)
@@ -379,16 +623,15 @@ mod finnhub_ws_lib_test {
         let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
         let conditions: Vec<String> = vec![];
         let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-        ticker.write_to_disk(&file);
+        ticker.write_to_disk(&file, &SystemClock).unwrap();
         file.seek(SeekFrom::Start(0)).unwrap();
-        assert_eq!(ticker.check_file_empty(&file), false);
+        assert!(!ticker.check_file_empty(&file).unwrap());
         std::fs::remove_file("given_a_non_empty_file_check_if_file_is_empty.csv").unwrap();
     }
 
     #[test]
     fn given_an_empty_file_check_if_file_is_empty(){
-        let mut file = OpenOptions::new()
-            .write(true)
+        let file = OpenOptions::new()
             .append(true)
             .create(true)
             .read(true)
@@ -396,14 +639,13 @@ mod finnhub_ws_lib_test {
         let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
         let conditions: Vec<String> = vec![];
         let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-        assert_eq!(ticker.check_file_empty(&file), true);
+        assert!(ticker.check_file_empty(&file).unwrap());
         std::fs::remove_file("given_an_empty_file_check_if_file_is_empty.csv").unwrap();
     }
 
     #[test]
     fn given_a_ticker_it_should_write_csv_headers_to_file(){
         let mut file = OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .read(true)
@@ -411,7 +653,7 @@ mod finnhub_ws_lib_test {
         let date: DateTime<Utc> = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
         let conditions: Vec<String> = vec![];
         let ticker = TickerInfo::new("BINANCE:BTCUSDT", 23841.51, 1.0, &date, &conditions );
-        ticker.write_headers(&file);
+        ticker.write_headers(&file).unwrap();
         file.seek(SeekFrom::Start(0)).unwrap();
         let mut data = String::new();
         file.read_to_string(&mut data).unwrap();
@@ -425,4 +667,42 @@ mod finnhub_ws_lib_test {
     fn given_a_ticker_it_should_serialize_it(){
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn given_the_messages_of_finnhub_it_should_tell_their_type_apart(){
+        let trade: WsMessage = serde_json::from_str(r#"{"type":"trade","data":[]}"#).unwrap();
+        assert!(matches!(trade, WsMessage::Response(_)));
+        let ping: WsMessage = serde_json::from_str(r#"{"type":"ping"}"#).unwrap();
+        assert!(matches!(ping, WsMessage::Ping(_)));
+        let error: WsMessage = serde_json::from_str(r#"{"type":"error","msg":"Invalid token"}"#).unwrap();
+        assert!(matches!(error, WsMessage::Error(_)));
+        let news = r#"{"type":"news","data":[{"related":"AAPL","datetime":1658441258,"headline":"AAPL beats estimates"}]}"#;
+        match serde_json::from_str::<WsMessage>(news).unwrap() {
+            WsMessage::News(news) => assert_eq!(news.news_data[0].headline, "AAPL beats estimates"),
+            other => panic!("expected a news message, got {:?}", other),
+        }
+        let unknown = r#"{"type":"news","data":[{"headline":"AAPL beats estimates"}]}"#;
+        match serde_json::from_str::<WsMessage>(unknown).unwrap() {
+            WsMessage::Unknown(raw) => assert_eq!(raw, serde_json::from_str::<serde_json::Value>(unknown).unwrap()),
+            other => panic!("expected an unknown message, got {:?}", other),
+        }
+        let ack: WsMessage = serde_json::from_str(r#"{"type":"subscribed","symbol":"AAPL"}"#).unwrap();
+        assert!(matches!(ack, WsMessage::Unknown(_)));
+        assert!(serde_json::from_str::<WsMessage>("not json").is_err());
+    }
+
+    #[test]
+    fn given_a_known_type_with_an_invalid_payload_it_should_fail_to_parse(){
+        assert!(matches!(WsMessage::parse(br#"{"type":"trade","data":[]}"#).unwrap(), WsMessage::Response(_)));
+        assert!(matches!(WsMessage::parse(br#"{"type":"subscribed","symbol":"AAPL"}"#).unwrap(), WsMessage::Unknown(_)));
+        assert!(matches!(WsMessage::parse(br#"{"data":[{"s":"AAPL"}]}"#).unwrap(), WsMessage::Unknown(_)));
+        for invalid in [
+            r#"{"type":"trade","data":[{"s":"AAPL"}]}"#,
+            r#"{"type":"trade","data":[{"s":"AAPL","p":"high","v":1,"t":1658441258376}]}"#,
+            r#"{"type":"news","data":[{"headline":"AAPL beats estimates"}]}"#,
+            r#"{"type":"error"}"#,
+        ] {
+            assert!(WsMessage::parse(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+    }
+}
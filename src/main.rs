@@ -1,20 +1,24 @@
+use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
-use chrono::{DurationRound};
-use futures_util::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
-use tokio::{net::TcpStream, time::{self, Duration}};
-use tokio_tungstenite::{connect_async, MaybeTlsStream, tungstenite::protocol::Message, WebSocketStream};
+use std::time::Instant;
 use finnhub_ws::{
-    cli::cmd::CLIOptions, Response, SubscribeInfo, WsMessage,
-    candlestick::calculate_candlestick,
-    stock_handle::{initialize_mapper, StockHandle},
-    mean::calculate_mean_data,
-    utils::{create_dirs, find_items},
-    RollingData
+    cli::cmd::{CLIOptions, Command, Report},
+    connection::create_connection_log,
+    error::ErrorCounts,
+    journal,
+    latency::rolling_latency,
+    recorder::FrameRecorder,
+    replay::{read_trades, replay, Replayed},
+    scheduler::create_tick_log,
+    control::{bind_control, serve_control},
+    pipeline::{enforce_retention, flush_stores, run_worker, shutdown_signal, supervise_connection, sync_stores, tick, wait_for_candlestick, wait_for_mean, write_latency},
+    stock_handle::{initialize_mapper, Mapper, MapperOptions, DATA_DIR},
+    utils::create_dirs,
 };
 use clap::Parser;
 use rayon::prelude::*;
-use crossbeam_channel::{Sender};
+use tokio::sync::{mpsc, watch};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
@@ -22,15 +26,20 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>
 async fn main() -> Result<()> {
     let opts = CLIOptions::parse();
 
-    let connect_addr = format!("wss://ws.finnhub.io?token={}", opts.token);
-
-    let url = url::Url::parse(&connect_addr).unwrap();
-
-    let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
+    if let Some(command) = &opts.command {
+        run_command(&opts, command);
+        return Ok(());
+    }
 
-    let (mut write, mut read) = ws_stream.split();
+    let url = match opts.connect_url() {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("Invalid endpoint {}: {}", opts.endpoint, e);
+            exit(1);
+        }
+    };
 
-    let dirs = vec!["data/rolling", "data/candlestick", "data/mean"];
+    let dirs = ["data/rolling", "data/candlestick", "data/mean", "data/connection", "data/news", "data/latency", "data/scheduler"];
     dirs.iter().for_each(|x| {
         if !create_dirs(x) {
             eprintln!("Couldn't create directories");
@@ -38,177 +47,219 @@ async fn main() -> Result<()> {
         }
     });
 
-    let mapper = initialize_mapper(&opts.stocks);
+    let options = opts.mapper_options();
+    let mapper = initialize_mapper(&opts.stocks, &options);
+    let connection_log = match create_connection_log() {
+        Some(log) => log,
+        None => {
+            eprintln!("Couldn't create the connection log");
+            exit(1);
+        }
+    };
+    let tick_log = match create_tick_log(Path::new(DATA_DIR)) {
+        Some(log) => log,
+        None => {
+            eprintln!("Couldn't create the tick log");
+            exit(1);
+        }
+    };
+    let stream_errors = Arc::new(ErrorCounts::default());
+    let recorder = match &opts.record {
+        Some(path) => match FrameRecorder::create(path) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                eprintln!("Couldn't create the recording {}: {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+    let control_listener = match &opts.control_socket {
+        Some(path) => match bind_control(path) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("Couldn't bind the control socket {}: {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let (flush_tx, flush_rx) = watch::channel(false);
 
     let mapper_a = Arc::clone(&mapper);
     let mapper_b = Arc::clone(&mapper);
     let mapper_c = Arc::clone(&mapper);
-    subscribe_to_stocks(&mut write, &opts.stocks).await;
-    let futures_vec = vec![
-        tokio::spawn(async move {
-            read_from_stream(&mut read, &mut write, &mapper_c).await;
-        }),
-        tokio::spawn(async move {
-        let candlestick_txs: Vec<Sender<i64>> = mapper_a.iter().map(|x| {
-            let (tx, _) = x.stock_channel.clone();
-            tx
-        }).collect();
-        let mean_txs: Vec<Sender<i64>> = mapper_a.iter().map(|x| {
-            let (tx, _) = x.rolling_mean_channel.clone();
-            tx
-        }).collect();
-        tick(&candlestick_txs, &mean_txs).await;
-    }), tokio::spawn(async move {
-        let cs_pool = rayon::ThreadPoolBuilder::new().num_threads(2 * mapper_b.len()).build().unwrap();
+    let mapper_d = Arc::clone(&mapper);
+    let mapper_e = Arc::clone(&mapper);
+    let mapper_f = Arc::clone(&mapper);
+    let lateness = opts.allowed_lateness();
+    let retention_policy = opts.retention_policy();
+    let reader_errors = Arc::clone(&stream_errors);
+    let retention_rx = shutdown_rx.clone();
+    let stores_rx = flush_rx.clone();
+    let latency_rx = flush_rx.clone();
+    let latency_interval = opts.latency_interval();
+    let (flush_interval, fsync, fsync_interval) = (options.flush_interval, options.fsync, opts.fsync_interval());
+    let control_rx = shutdown_rx.clone();
+    let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+    let news = opts.news;
+    let reader = tokio::spawn(async move {
+        supervise_connection(&url, news, &mapper_c, &mut changes_rx, &connection_log, &reader_errors, recorder.as_ref(), shutdown_rx).await;
+    });
+    // the workers of the stocks added through the control socket keep running until
+    // the ticker sends them the shutdown signal, so they get awaited after it
+    let control = tokio::spawn(async move {
+        match control_listener {
+            Some(listener) => serve_control(listener, mapper_e, changes_tx, control_rx).await,
+            None => Vec::new(),
+        }
+    });
+    let retention = tokio::spawn(async move {
+        enforce_retention(retention_policy, Path::new(DATA_DIR), retention_rx).await;
+    });
+    let stores = tokio::spawn(async move {
+        flush_stores(&mapper_d, flush_interval, fsync, fsync_interval, stores_rx).await;
+    });
+    let latency = tokio::spawn(async move {
+        write_latency(&mapper_f, latency_interval, latency_rx).await;
+    });
+    let ticker = tokio::spawn(async move {
+        tick(&mapper_a, lateness, &tick_log, flush_rx).await;
+    });
+    let workers = tokio::task::spawn_blocking(move || {
+        // the handles of the stocks given on the command line, without holding the
+        // lock of the mapper while the workers run
+        let handles = mapper_b.handles().to_vec();
+        if handles.is_empty() {
+            return;
+        }
+        let cs_pool = match rayon::ThreadPoolBuilder::new().num_threads(2 * handles.len()).build() {
+            Ok(pool) => pool,
+            Err(e) => {
+                eprintln!("Couldn't start the workers: {}", e);
+                return;
+            }
+        };
         cs_pool.install(|| {
-            mapper_b.par_iter().for_each(|x| {
-                rayon::join(|| wait_for_candlestick(x), || wait_for_mean(x));
+            handles.par_iter().for_each(|x| {
+                rayon::join(|| run_worker(x, wait_for_candlestick), || run_worker(x, wait_for_mean));
             });
         });
-    })];
+    });
 
-    futures::future::join_all(futures_vec).await;
+    shutdown_signal().await;
+    // stop reading first, so that every transaction received
+    // is on disk before the partial windows get flushed
+    shutdown_tx.send(true)?;
+    reader.await?;
+    let added_workers = control.await?;
+    retention.await?;
+    flush_tx.send(true)?;
+    stores.await?;
+    latency.await?;
+    ticker.await?;
+    workers.await?;
+    for worker in added_workers {
+        worker.await?;
+    }
+    sync_stores(&mapper, fsync);
+    if let Some(path) = &opts.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    if stream_errors.total() > 0 {
+        eprintln!("Errors of the stream: {}", stream_errors);
+    }
+    report_errors(&mapper);
     Ok(())
 }
 
-/// `tick` is being used to send a signal to threads waiting to calculate
-/// the candlestick and 15-minute mean data
-///
-/// # Arguments
-/// candlestick_txs : a reference to a vector of Sender which represents the threads calculating the
-///      candlestick
-/// mean_txs: a reference to a vector of Sender which represents the threads calculating the 15-minute
-///      mean data
-async fn tick(candlestick_txs: &[Sender<i64>], mean_txs: &[Sender<i64>]) {
-    let mut interval = time::interval(Duration::from_secs(60));
-    interval.tick().await;
-    loop {
-        interval.tick().await;
-        for (_, (cs_tx, me_tx)) in candlestick_txs.iter().zip(mean_txs.iter()).enumerate() {
-            let timestamp = chrono::Local::now().duration_trunc(chrono::Duration::minutes(1)).unwrap().timestamp();
-            cs_tx.send(timestamp).unwrap();
-            me_tx.send(timestamp).unwrap();
-        }
+/// Prints the trades of the symbols which weren't tracked and the errors of each stock
+fn report_errors(mapper: &Mapper) {
+    for (symbol, count) in mapper.unknown_trades() {
+        eprintln!("Trades of {}, which isn't tracked: {}", symbol, count);
+    }
+    for handle in mapper.handles().iter().filter(|x| x.errors.total() > 0) {
+        eprintln!("Errors of {}: {}", handle.stock_symbol, handle.errors);
     }
 }
 
-/// `read_from_stream` reads data from the websocket and converts a byte array to `WsMessage` enum instance
-async fn read_from_stream(read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>, write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>, mapper: &Vec<StockHandle>) {
-    while let Some(message) = read.next().await {
-        match message {
-            Ok(d) => {
-                let x = &*d.into_data();
-                let data = serde_json::from_slice::<WsMessage>(x).unwrap();
-                match data {
-                    WsMessage::Response(resp) => { parse_message(&resp, mapper) }
-                    WsMessage::Ping(ping) => {
-                        println!("{:?}", ping);
-                        write.send(Message::Pong("".into())).await.unwrap();
-                        println!("Pong sent");
-                    },
-                    WsMessage::Error(err) => println!("{:?}", err.message)
+/// Runs one of the tools of the command line instead of tracking the stocks
+fn run_command(opts: &CLIOptions, command: &Command) {
+    match command {
+        Command::Convert { input, output } => {
+            let output = output.clone().unwrap_or_else(|| {
+                let mut output = input.clone().into_os_string();
+                output.push(".csv");
+                output.into()
+            });
+            match journal::convert(input, &output) {
+                Ok(count) => println!("Converted {} records to {}", count, output.display()),
+                Err(e) => {
+                    eprintln!("Couldn't convert {}: {}", input.display(), e);
+                    exit(1);
                 }
             }
-            Err(ref e) => {
-                println!("{:?}", e);
-            }
         }
-    }
-}
-
-/// `wait_for_candlestick` blocks until data is retrieved from the channel.
-/// Then, it reads rolling data file and filters entries of the last minute,
-/// calculates the candlestick and writes it back to a file used for
-/// candlestick information
-fn wait_for_candlestick(handle: &StockHandle) {
-    let (_, rx) = handle.rolling_mean_channel.clone();
-    let mut items: Vec<RollingData> = Vec::with_capacity(1000);
-    loop {
-        // this blocks the thread
-        let timestamp = rx.recv().unwrap();
-        let mut rf = handle.rolling_file.lock().unwrap();
-        find_items(&mut rf, timestamp, 1, &mut items);
-        // rf would get dropped at the end of the iteration,
-        // but there is no need to keep the lock much longer than this point
-        drop(rf);
-        let cf = handle.candlestick_file.lock().unwrap();
-        match calculate_candlestick(&items) {
-            Some(cs) => {
-                cs.write_to_file(&cf);
+        Command::Report { report: Report::Latency { dir } } => match rolling_latency(dir) {
+            Ok(latency) if latency.is_empty() => println!("No trades under {}", dir.display()),
+            Ok(latency) => {
+                for (symbol, stats) in latency {
+                    println!("{}: {}", symbol, stats);
+                }
             }
-            None => {
+            Err(e) => {
+                eprintln!("Couldn't read {}: {}", dir.display(), e);
+                exit(1);
             }
-        };
-        // same as for rf, just a good practice
-        drop(cf);
-        items.clear();
-        items.shrink_to(1000);
-    }
-}
-
-/// `wait_for_mean` blocks until data is retrieved from the channel.
-/// Then, it reads rolling data file and filters entries of the 15 minutes,
-/// calculates the mean data and writes it back to a file used for
-/// mean information
-fn wait_for_mean(handle: &StockHandle) {
-    let (_, rx) = handle.stock_channel.clone();
-    let mut items: Vec<RollingData> = Vec::with_capacity(1000);
-    loop {
-        let timestamp = rx.recv().unwrap();
-        let mut rf = handle.rolling_file.lock().unwrap();
-        find_items(&mut rf, timestamp, 15, &mut items);
-        // rf would get dropped at the end of the iteration,
-        // but there is no need to keep the lock much longer than this point
-        drop(rf);
-        let mf = handle.mean_file.lock().unwrap();
-        match calculate_mean_data(&items) {
-            Some(md) => {
-                md.write_to_file(&mf);
+        },
+        Command::Replay { input, output, speed } => {
+            let trades = match read_trades(input) {
+                Ok(trades) if trades.is_empty() => {
+                    println!("No trades under {}", input.display());
+                    return;
+                }
+                Ok(trades) => trades,
+                Err(e) => {
+                    eprintln!("Couldn't read {}: {}", input.display(), e);
+                    exit(1);
+                }
+            };
+            match is_separate_output(input, output) {
+                Ok(true) => {}
+                Ok(false) => {
+                    eprintln!("The output directory {} should be separate from the data being replayed", output.display());
+                    exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't create {}: {}", output.display(), e);
+                    exit(1);
+                }
             }
-            None => {
+            // every symbol of the rolling files gets replayed unless stocks were given
+            let options = MapperOptions { data_dir: output.clone(), ..opts.mapper_options() };
+            let started = Instant::now();
+            match replay(trades, &opts.stocks, &options, opts.allowed_lateness(), *speed) {
+                Ok(Replayed { mapper, trades }) => {
+                    println!("Replayed {} trades of {} stocks to {} in {:.1?}", trades, mapper.len(), output.display(), started.elapsed());
+                    report_errors(&mapper);
+                }
+                Err(e) => {
+                    eprintln!("Couldn't replay {}: {}", input.display(), e);
+                    exit(1);
+                }
             }
-        };
-        // same as for rf, just a good practice
-        drop(mf);
-        items.clear();
-        items.shrink_to(1000);
-
-    }
-}
-
-/// `subscribe_to_stocks`: Given a channel and an array of strings containing the stock names,
-/// it sends a websocket message to finnhub to subscribe to that stock
-async fn subscribe_to_stocks(tx: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>, stocks: &[String]) {
-    let items = stocks.iter().map(|item| {
-        SubscribeInfo::new(item)
-    }).map(|x1| {
-        match serde_json::to_string(&x1) {
-            Ok(res) => res,
-            Err(_e) => "".parse().unwrap()
         }
-    }).collect::<Vec<String>>();
-    for item in items {
-        tx.send(Message::Text(item)).await.unwrap();
     }
 }
 
-/// `parse_message` given a response and a reference to a `StockHandle`,
-/// for each transaction in the response, it writes them to the rolling
-/// file keeping the file lock during the write operation. On first invocation
-/// it checks to see, if the rolling file exists, otherwise it creates it.
-fn parse_message(resp: &Response, mapper: &Vec<StockHandle>) {
-    resp.transaction_data.par_iter().for_each(|x| {
-        match mapper.iter().find(|s| s.stock_symbol == x.symbol) {
-            Some(handle) => {
-                handle.once_flag.call_once(|| {
-                    let rf = handle.rolling_file.lock().unwrap();
-                    if x.check_file_empty(&rf) {
-                        x.write_headers(&rf)
-                    }
-                });
-                x.write_to_disk(&handle.rolling_file.lock().unwrap())
-            }
-            None => {}
-        }
-    });
+/// Returns true if the output directory of a replay is neither the data directory nor overlapping
+/// with the input being replayed. The paths get compared once resolved, so that the same directory
+/// written in another way gets caught, which is why the output directory gets created first.
+fn is_separate_output(input: &Path, output: &Path) -> std::io::Result<bool> {
+    std::fs::create_dir_all(output)?;
+    let (input, output) = (input.canonicalize()?, output.canonicalize()?);
+    let data = Path::new(DATA_DIR).canonicalize().ok();
+    Ok(data.as_deref() != Some(output.as_path()) && !input.starts_with(&output) && !output.starts_with(&input))
 }
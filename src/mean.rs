@@ -2,16 +2,19 @@
 //! # mean
 //!
 //! This contains the necessary structure and functions to manage
-//! all the needs the program has with regard to 15 minute mean data
-//! information for a stock
+//! all the needs the program has with regard to the mean price
+//! information for a stock over one or more sliding windows
 use std::fs::File;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::interval::Interval;
 use crate::RollingData;
+use crate::window::TradeWindow;
 
 /// `MeanData` is a struct containing the necessary information
-/// to represent the average price of a stock for a 15-minute
-/// time period
+/// to represent the average price of a stock for a window
+/// of time, 15 minutes by default
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct MeanData {
     /// symbol: represents the stock symbol
@@ -23,47 +26,202 @@ pub struct MeanData {
     /// a transaction was made
     pub end_time: DateTime<Utc>,
     /// mean_price: represents the average stock price
-    /// of that 15-minute period for the given stock
+    /// of the window for the given stock
     pub mean_price: f64,
     /// transactions: represents the total number of
-    /// transactions made in the 15-minute period
+    /// transactions made in the window
     /// for the given stock
     pub transactions: u64,
+    /// partial: represents whether the mean data were
+    /// calculated on shutdown instead of at the end
+    /// of a minute. Mean files written before it was
+    /// added don't have this column, so it defaults to false
+    #[serde(default)]
+    pub partial: bool,
+    /// window: represents the length of the window
+    /// the mean price was calculated over, such as 5m.
+    /// The mean files written with only the default
+    /// window configured don't have this column, so
+    /// it defaults to the default window
+    #[serde(default = "default_window")]
+    pub window: Interval,
+}
+
+/// Returns the window the mean price gets calculated over unless others are configured, 15 minutes
+pub fn default_window() -> Interval {
+    Interval::minutes(15).unwrap()
 }
 
 impl MeanData {
-    fn new(start_time: DateTime<Utc>, end_time: DateTime<Utc>, mean_price: f64, transactions: u64, symbol: String) -> Self {
+    fn new(start_time: DateTime<Utc>, end_time: DateTime<Utc>, mean_price: f64, transactions: u64, symbol: String, window: Interval) -> Self {
         MeanData {
             symbol,
             transactions,
             mean_price,
             start_time,
             end_time,
+            partial: false,
+            window,
         }
     }
-    /// `write_to_file`: serializes the struct instance and writes it the given file
-    pub fn write_to_file(&self, file: &File) {
+    /// `write_to_file`: serializes the struct instance and writes it the given file,
+    /// ending with the window column if `window` is true. The choice should be the
+    /// same for every row of a file, so that all of them have the same columns.
+    pub fn write_to_file(&self, file: &File, window: bool) -> Result<()> {
         let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
-        writer.serialize(self).unwrap();
-        writer.flush().unwrap();
+        if window {
+            writer.serialize(self)?;
+        } else {
+            writer.serialize((&self.symbol, self.start_time, self.end_time, self.mean_price, self.transactions, self.partial))?;
+        }
+        writer.flush()?;
+        Ok(())
     }
 }
 
-/// `calculate_mean_data` given a reference to a slice of RollingData,
+/// `MeanWindow` is the state of one of the sliding windows of a `RollingMeanAggregator`
+#[derive(Debug)]
+struct MeanWindow {
+    /// interval: the length of the sliding window
+    interval: Interval,
+    /// start: the number of trades pushed to the aggregator before the oldest one in the window
+    start: usize,
+    /// sum: the sum of the prices of the trades in the window
+    sum: f64,
+}
+
+/// `RollingMeanAggregator` maintains the mean price of a stock over several sliding windows
+/// at once. It holds the trades of the longest window only, and the shorter ones are tracked
+/// as positions within it. It gets updated on every trade in constant time per window and,
+/// instead of summing up the prices of the whole window each time it closes, it subtracts
+/// the prices of the trades expiring from it.
+///
+/// # Example
+/// ```
+/// use chrono::{TimeZone, Utc};
+/// use finnhub_ws::mean::RollingMeanAggregator;
+/// use finnhub_ws::RollingData;
+/// let mut aggregator = RollingMeanAggregator::new("APPL", &["5m".parse().unwrap(), "15m".parse().unwrap()]);
+/// aggregator.update(&RollingData{
+///     price: 172.5,
+///     symbol: "APPL".parse().unwrap(),
+///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
+///     volume: 0.5,
+///     conditions: vec![]
+/// });
+/// let md = aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+/// assert_eq!(md.len(), 2);
+/// assert_eq!(md[0].mean_price, 172.5);
+/// assert_eq!(aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 13, 0)).len(), 1);
+/// assert!(aggregator.close(Utc.ymd(2022, 7, 21).and_hms(22, 23, 0)).is_empty());
+/// ```
+#[derive(Debug)]
+pub struct RollingMeanAggregator {
+    /// symbol: the stock symbol of the mean data
+    symbol: String,
+    /// windows: the sliding windows, from the shortest to the longest
+    windows: Vec<MeanWindow>,
+    /// trades: the trades currently in the longest sliding window
+    trades: TradeWindow,
+    /// popped: the number of trades which expired from the longest sliding window
+    popped: usize,
+}
+
+impl RollingMeanAggregator {
+    /// Given the stock symbol and the lengths of the sliding windows,
+    /// creates and returns an aggregator with empty windows
+    pub fn new(symbol: &str, intervals: &[Interval]) -> Self {
+        let mut intervals = intervals.to_vec();
+        intervals.sort();
+        intervals.dedup();
+        let span = intervals.last().map_or_else(Duration::zero, |i| i.duration());
+        RollingMeanAggregator {
+            symbol: symbol.to_string(),
+            windows: intervals.into_iter().map(|interval| MeanWindow { interval, start: 0, sum: 0.0 }).collect(),
+            trades: TradeWindow::new(span),
+            popped: 0,
+        }
+    }
+
+    /// Adds a trade to the sliding windows and expires the ones that fell out of them
+    pub fn update(&mut self, trade: &RollingData) {
+        // expiring before pushing, so that the trades have nothing left to evict
+        // on their own and every price leaving them gets subtracted from the sums
+        self.expire(trade.write_timestamp);
+        for window in &mut self.windows {
+            window.sum += trade.price;
+        }
+        self.trades.push(trade.clone());
+    }
+
+    /// Returns the mean data of each window ending at `end`, from the shortest window
+    /// to the longest. Trades written at or after `end` are left out, but stay in the
+    /// windows for the next ones. Windows without any trades are left out.
+    pub fn close(&mut self, end: DateTime<Utc>) -> Vec<MeanData> {
+        self.expire(end);
+        // only the few trades that arrived while the windows were closing are newer than `end`
+        let (newer, newer_sum) = self.trades.iter().rev()
+            .take_while(|t| t.write_timestamp >= end)
+            .fold((0, 0.0), |(count, sum), t| (count + 1, sum + t.price));
+        let last = self.trades.len() - newer;
+        self.windows.iter().filter_map(|window| {
+            let first = window.start - self.popped;
+            if first >= last {
+                return None;
+            }
+            let count = last - first;
+            let start_time = self.trades.get(first).unwrap().write_timestamp;
+            let end_time = self.trades.get(last - 1).unwrap().write_timestamp;
+            let mean_price = (window.sum - newer_sum) / count as f64;
+            Some(MeanData::new(start_time, end_time, mean_price, count as u64, self.symbol.clone(), window.interval))
+        }).collect()
+    }
+
+    /// Removes from each window the trades written before it began at `time`,
+    /// subtracting their prices from its sum
+    fn expire(&mut self, time: DateTime<Utc>) {
+        let total = self.popped + self.trades.len();
+        for window in &mut self.windows {
+            let oldest = time - window.interval.duration();
+            while let Some(trade) = self.trades.get(window.start - self.popped) {
+                if trade.write_timestamp >= oldest {
+                    break;
+                }
+                window.sum -= trade.price;
+                window.start += 1;
+            }
+            if window.start == total {
+                // avoids accumulating floating point errors over the day
+                window.sum = 0.0;
+            }
+        }
+        // the longest window is the last one, so no other window holds these trades anymore
+        if let Some(window) = self.windows.last() {
+            while self.trades.pop_before(time - window.interval.duration()).is_some() {
+                self.popped += 1;
+            }
+        }
+    }
+}
+
+/// `calculate_mean_data` given a reference to a slice of RollingData and the window it covers,
 /// if the slice is not empty, it calculates the mean_data by assigning the min date
 /// to the first element of the slice, the max data to the last, and calculates
 /// the average price among the data. If the slice is empty, None is returned.
+/// This is the reference implementation of `RollingMeanAggregator`.
 ///
 /// # Arguments
 ///
 /// `data` - a slice of RollingData for which the mean data calculation should be made
+/// `window` - the length of the window the data were taken from
 ///
 /// # Example
 /// ```
 /// use finnhub_ws::mean::calculate_mean_data;
 /// use finnhub_ws::RollingData;
 /// let items: Vec<RollingData> = Vec::new();
-/// let rolling = calculate_mean_data(&items);
+/// let rolling = calculate_mean_data(&items, "15m".parse().unwrap());
 /// assert_eq!(rolling, None)
 /// ```
 ///
@@ -76,31 +234,142 @@ impl MeanData {
 ///     price: 172.5,
 ///     symbol: "APPL".parse().unwrap(),
 ///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
-///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794)
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
+///     volume: 0.5,
+///     conditions: vec![]
 /// };
 /// let r2 = RollingData{
 ///     price: 173.5,
 ///     symbol: "APPL".parse().unwrap(),
 ///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197),
-///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 798)
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 798),
+///     volume: 0.5,
+///     conditions: vec![]
 /// };
 /// items.push(r1);
 /// items.push(r2);
-/// let rolling = calculate_mean_data(&items);
+/// let rolling = calculate_mean_data(&items, "15m".parse().unwrap());
 /// assert_eq!(rolling, Some(MeanData{
 ///     symbol: "APPL".parse().unwrap(),
 ///     transactions: 2,
 ///     start_time: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
 ///     end_time: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 798),
 ///     mean_price: 173.0,
+///     partial: false,
+///     window: "15m".parse().unwrap(),
 /// }));
 /// ```
-pub fn calculate_mean_data(data: &[RollingData]) -> Option<MeanData> {
+pub fn calculate_mean_data(data: &[RollingData], window: Interval) -> Option<MeanData> {
     if !data.is_empty() {
         let max_date = data.iter().map(|x| { x.write_timestamp }).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
         let min_date = data.iter().map(|x| { x.write_timestamp }).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
         let mean_price: f64 = data.iter().fold(0.0, |mean_price, i| mean_price + i.price) / (data.len() as f64);
-        return Some(MeanData::new(min_date, max_date, mean_price, data.len() as u64, data[0].symbol.parse().unwrap()));
+        return Some(MeanData::new(min_date, max_date, mean_price, data.len() as u64, data[0].symbol.parse().unwrap(), window));
     }
     None
 }
+
+
+#[cfg(test)]
+mod mean_test {
+    use std::fs::{read_to_string, remove_file, OpenOptions};
+    use chrono::{Duration, TimeZone, Utc};
+    use serial_test::serial;
+    use crate::interval::Interval;
+    use crate::mean::{calculate_mean_data, default_window, MeanData, RollingMeanAggregator};
+    use crate::utils::create_dirs;
+    use crate::RollingData;
+
+    fn trades() -> Vec<RollingData> {
+        (0..120).map(|i| RollingData {
+            symbol: "BINANCE:BTCUSDT".parse().unwrap(),
+            price: 23000.0 + (i * 7 % 13) as f64,
+            timestamp: Utc.ymd(2022, 7, 21).and_hms(22, 0, 0) + Duration::seconds(i * 45),
+            write_timestamp: Utc.ymd(2022, 7, 21).and_hms(22, 0, 0) + Duration::seconds(i * 45),
+            volume: 1.0,
+            conditions: vec![],
+        }).collect()
+    }
+
+    fn intervals() -> Vec<Interval> {
+        ["5m", "15m", "60m"].iter().map(|i| i.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn aggregator_should_match_the_reference_implementation_every_minute() {
+        let data = trades();
+        let mut aggregator = RollingMeanAggregator::new("BINANCE:BTCUSDT", &intervals());
+        let mut next = 0;
+        for minute in 1..=150 {
+            let end = Utc.ymd(2022, 7, 21).and_hms(22, 0, 0) + Duration::minutes(minute);
+            while next < data.len() && data[next].write_timestamp < end {
+                aggregator.update(&data[next]);
+                next += 1;
+            }
+            let got = aggregator.close(end);
+            let expected: Vec<_> = intervals().into_iter().filter_map(|interval| {
+                let window: Vec<RollingData> = data.iter()
+                    .filter(|t| t.write_timestamp >= end - interval.duration() && t.write_timestamp < end)
+                    .cloned()
+                    .collect();
+                calculate_mean_data(&window, interval)
+            }).collect();
+            assert_eq!(got.len(), expected.len());
+            for (got, expected) in got.iter().zip(expected.iter()) {
+                assert_eq!(got.window, expected.window);
+                assert_eq!(got.transactions, expected.transactions);
+                assert_eq!(got.start_time, expected.start_time);
+                assert_eq!(got.end_time, expected.end_time);
+                assert!((got.mean_price - expected.mean_price).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn aggregator_should_leave_out_trades_newer_than_the_window() {
+        let data = trades();
+        let interval: Interval = "15m".parse().unwrap();
+        let mut aggregator = RollingMeanAggregator::new("BINANCE:BTCUSDT", &[interval]);
+        data[..3].iter().for_each(|t| aggregator.update(t));
+        let got = aggregator.close(data[2].write_timestamp);
+        assert_eq!(got, vec![calculate_mean_data(&data[..2], interval).unwrap()]);
+    }
+
+    #[test]
+    #[serial]
+    fn given_several_windows_every_row_should_end_with_its_window() {
+        let _ = create_dirs("tmp");
+        let path = "tmp/mean_windows.csv";
+        let _ = remove_file(path);
+        let file = OpenOptions::new().append(true).create(true).open(path).unwrap();
+        let data = trades();
+        let windows = ["5m", "15m", "1h"];
+        let written: Vec<MeanData> = windows.iter()
+            .map(|w| calculate_mean_data(&data[..2], w.parse().unwrap()).unwrap())
+            .collect();
+        written.iter().for_each(|md| md.write_to_file(&file, true).unwrap());
+        let content = read_to_string(path).unwrap();
+        assert!(content.lines().zip(windows).all(|(line, w)| line.ends_with(&format!(",false,{}", w))), "{}", content);
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(content.as_bytes());
+        let got: Vec<MeanData> = reader.deserialize().map(|r| r.unwrap()).collect();
+        assert_eq!(got, written);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn given_the_default_window_only_it_should_keep_the_previous_columns() {
+        let _ = create_dirs("tmp");
+        let path = "tmp/mean_default_window.csv";
+        let _ = remove_file(path);
+        let file = OpenOptions::new().append(true).create(true).open(path).unwrap();
+        let md = calculate_mean_data(&trades()[..1], default_window()).unwrap();
+        md.write_to_file(&file, false).unwrap();
+        let content = read_to_string(path).unwrap();
+        assert!(content.ends_with(",false\n"), "{}", content);
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(content.as_bytes());
+        let got: MeanData = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(got, md);
+        remove_file(path).unwrap();
+    }
+}
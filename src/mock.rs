@@ -0,0 +1,224 @@
+//! Mock finnhub server primitives
+//! # mock
+//!
+//! This contains a small websocket server which speaks the same trade, ping and
+//! error json messages as finnhub does and replays them from a script. It can be
+//! used through the `mock_server` binary or straight from tests, so that the whole
+//! pipeline can be run without the live service and a real token.
+//!
+//! A script is a file containing one finnhub message per line. Empty lines and lines
+//! starting with `#` are ignored. The frames of a session recorded with the `recorder`
+//! module can be replayed instead, at the time they got received.
+//!
+//! # Example
+//! ```
+//! use finnhub_ws::mock::{ping_frame, MockServer};
+//! use tokio::time::Duration;
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let server = MockServer::start(vec![ping_frame()], Duration::from_millis(10)).await.unwrap();
+//! assert!(server.url().starts_with("ws://127.0.0.1:"));
+//! # });
+//! ```
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use crate::recorder::{offsets, RecordedFrame};
+use crate::replay::ReplaySpeed;
+use crate::{Ping, Response, TickerInfo, WsError};
+
+/// The frames a server replays, each with the time to send it at after the first subscription
+type Schedule = Vec<(Duration, Message)>;
+
+/// `MockServer` is a websocket server listening on localhost which replays the
+/// scripted frames to every client that connects to it.
+#[derive(Debug)]
+pub struct MockServer {
+    /// the address the server listens to
+    addr: SocketAddr,
+    /// the task accepting the connections
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts a server on a random localhost port which replays the given frames,
+    /// waiting `interval` between two consecutive ones.
+    pub async fn start(frames: Vec<String>, interval: Duration) -> io::Result<Self> {
+        MockServer::bind("127.0.0.1:0", frames, interval).await
+    }
+
+    /// Starts a server on the given address which replays the given frames,
+    /// waiting `interval` between two consecutive ones.
+    pub async fn bind(addr: &str, frames: Vec<String>, interval: Duration) -> io::Result<Self> {
+        let schedule = (1..).zip(frames).map(|(i, frame)| (interval * i, Message::Text(frame))).collect();
+        MockServer::listen(addr, schedule).await
+    }
+
+    /// Starts a server on a random localhost port which replays the recorded frames byte for
+    /// byte, each one at the time it got received after the first one, at the given speed.
+    pub async fn start_recording(frames: &[RecordedFrame], speed: ReplaySpeed) -> io::Result<Self> {
+        MockServer::bind_recording("127.0.0.1:0", frames, speed).await
+    }
+
+    /// Starts a server on the given address which replays the recorded frames byte for byte,
+    /// each one at the time it got received after the first one, at the given speed.
+    pub async fn bind_recording(addr: &str, frames: &[RecordedFrame], speed: ReplaySpeed) -> io::Result<Self> {
+        let schedule = offsets(frames, speed).into_iter().zip(frames.iter().map(|f| f.message())).collect();
+        MockServer::listen(addr, schedule).await
+    }
+
+    /// Starts a server on the given address which replays the schedule to every client
+    async fn listen(addr: &str, schedule: Schedule) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, schedule.clone()));
+            }
+        });
+        Ok(MockServer { addr, handle })
+    }
+
+    /// Returns the address the server listens to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the endpoint to be given to the client
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.addr)
+    }
+
+    /// Blocks until the server stops accepting connections
+    pub async fn wait(mut self) {
+        let _ = (&mut self.handle).await;
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// `serve` handles a single client. It waits for the first subscription before replaying
+/// the frames, as finnhub only sends trades after subscribing, and then keeps reading
+/// until the client goes away.
+async fn serve(stream: TcpStream, schedule: Schedule) {
+    let ws_stream = match accept_async(stream).await {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Mock handshake failed: {}", e);
+            return;
+        }
+    };
+    let (mut write, mut read) = ws_stream.split();
+    match read.next().await {
+        Some(Ok(Message::Text(_))) => {}
+        _ => return,
+    }
+    let subscribed = time::Instant::now();
+    for (offset, frame) in schedule {
+        time::sleep_until(subscribed + offset).await;
+        if write.send(frame).await.is_err() {
+            return;
+        }
+    }
+    while let Some(Ok(_)) = read.next().await {}
+}
+
+/// Given the path to a script, returns the frames it contains
+pub fn load_script<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/// Given a slice of trades, returns the trade message finnhub would send for them
+///
+/// # Example
+/// ```
+/// use chrono::{TimeZone, Utc};
+/// use finnhub_ws::{mock::trade_frame, TickerInfo};
+/// let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+/// let frame = trade_frame(vec![TickerInfo::new("AAPL", 172.5, 1.0, &date, &[])]);
+/// assert_eq!(frame, r#"{"type":"trade","data":[{"s":"AAPL","p":172.5,"v":1.0,"t":1658441258376,"c":[]}]}"#);
+/// ```
+pub fn trade_frame(trades: Vec<TickerInfo>) -> String {
+    let resp = Response {
+        transaction_data: trades,
+        ..Response::default()
+    };
+    serde_json::to_string(&resp).unwrap()
+}
+
+/// Returns the ping message finnhub sends to check if the client is still alive
+pub fn ping_frame() -> String {
+    serde_json::to_string(&Ping { action_type: "ping" }).unwrap()
+}
+
+/// Given a message, returns the error message finnhub would send for it
+pub fn error_frame(message: &str) -> String {
+    serde_json::to_string(&WsError { message }).unwrap()
+}
+
+
+#[cfg(test)]
+mod mock_test {
+    use std::fs::{remove_file, write};
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::time::Duration;
+    use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+    use chrono::{TimeZone, Utc};
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+    use crate::mock::{error_frame, load_script, ping_frame, MockServer};
+    use crate::recorder::RecordedFrame;
+    use crate::replay::ReplaySpeed;
+
+    #[test]
+    fn given_a_script_it_should_skip_comments_and_empty_lines() {
+        let file_name = "given_a_script_it_should_skip_comments_and_empty_lines.jsonl";
+        write(file_name, "# a comment\n{\"type\":\"ping\"}\n\n{\"msg\":\"error\"}\n").unwrap();
+        let frames = load_script(file_name).unwrap();
+        assert_eq!(frames, vec![ping_frame(), error_frame("error")]);
+        remove_file(file_name).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_replay_frames_after_subscribing() {
+        let server = MockServer::start(vec![ping_frame(), error_frame("error")], Duration::from_millis(1)).await.unwrap();
+        let (mut ws, _) = connect_async(server.url()).await.unwrap();
+        ws.send(Message::Text(r#"{"type":"subscribe","symbol":"AAPL"}"#.to_string())).await.unwrap();
+        let first = ws.next().await.unwrap().unwrap();
+        let second = ws.next().await.unwrap().unwrap();
+        assert_eq!(first, Message::Text(ping_frame()));
+        assert_eq!(second, Message::Text(error_frame("error")));
+    }
+
+    #[tokio::test]
+    async fn given_a_recording_it_should_replay_its_frames_byte_for_byte() {
+        let received = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+        let messages = vec![
+            Message::Text("{\"type\":\"trade\",\"data\":[ ]}".to_string()),
+            Message::Binary(vec![0, 159, 146, 150]),
+            Message::Close(Some(CloseFrame { code: CloseCode::Away, reason: "restarting".into() })),
+        ];
+        let frames: Vec<RecordedFrame> = messages.iter().map(|m| RecordedFrame::new(received, m).unwrap()).collect();
+        let server = MockServer::start_recording(&frames, ReplaySpeed::Max).await.unwrap();
+        let (mut ws, _) = connect_async(server.url()).await.unwrap();
+        ws.send(Message::Text(r#"{"type":"subscribe","symbol":"AAPL"}"#.to_string())).await.unwrap();
+        let mut played = Vec::new();
+        while let Some(Ok(message)) = ws.next().await {
+            played.push(message);
+        }
+        assert_eq!(played, messages);
+    }
+}
@@ -0,0 +1,155 @@
+//! News primitives
+//! # news
+//!
+//! This contains the news finnhub sends for the stocks subscribed to with `subscribe-news`,
+//! and the files their headlines get written to, one for each stock under data/news. The
+//! candlesticks of the intervals news about a stock arrived in get flagged, so that jumps
+//! in its price can be correlated with its headlines.
+//!
+//! # Example
+//! ```
+//! use finnhub_ws::news::NewsItem;
+//! let item: NewsItem = serde_json::from_str(r#"{"related":"AAPL,MSFT","datetime":1658441258,"headline":"Apple beats estimates"}"#).unwrap();
+//! assert!(item.is_related_to("MSFT"));
+//! assert!(!item.is_related_to("TSLA"));
+//! ```
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc, serde::{ts_milliseconds, ts_seconds}};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::utils::{append_csv, create_dirs, open_append, sanitize_string};
+
+/// The directory of the data directory the news files get written to
+const NEWS_DIR: &str = "news";
+
+/// `NewsItem` is a struct which represents a single news item of the news messages
+/// finnhub sends for the stocks subscribed to with `subscribe-news`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NewsItem {
+    /// related: represents the symbols the news are related to, separated by commas
+    #[serde(default)]
+    pub related: String,
+    /// datetime: represents the time the news got published and is a second epoch
+    #[serde(with = "ts_seconds")]
+    pub datetime: DateTime<Utc>,
+    /// headline: represents the headline of the news
+    pub headline: String,
+    /// source: represents the publisher of the news
+    #[serde(default)]
+    pub source: String,
+    /// url: represents the link to the full article
+    #[serde(default)]
+    pub url: String,
+}
+
+impl NewsItem {
+    /// Returns true if the given stock symbol is one of the symbols the news are related to
+    pub fn is_related_to(&self, symbol: &str) -> bool {
+        self.related.split(',').any(|s| s.trim() == symbol)
+    }
+}
+
+/// `Headline`: represents the row written to the news file of a stock for each
+/// news item related to it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Headline {
+    /// stock symbol the news are related to
+    pub symbol: String,
+    /// timestamp with millisecond precision of the time the news got published
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: DateTime<Utc>,
+    /// timestamp with millisecond precision of the time the news arrived
+    #[serde(with = "ts_milliseconds")]
+    pub receive_timestamp: DateTime<Utc>,
+    /// headline of the news
+    pub headline: String,
+    /// publisher of the news
+    pub source: String,
+    /// link to the full article
+    pub url: String,
+}
+
+impl Headline {
+    /// Given the stock symbol, the news item and the time it arrived, creates and
+    /// returns the row to be written to the news file of the stock
+    pub fn new(symbol: &str, item: &NewsItem, received: DateTime<Utc>) -> Self {
+        Headline {
+            symbol: symbol.to_string(),
+            timestamp: item.datetime,
+            receive_timestamp: received,
+            headline: item.headline.clone(),
+            source: item.source.clone(),
+            url: item.url.clone(),
+        }
+    }
+}
+
+/// `NewsLog` is the file the headlines of a stock get appended to, which is located
+/// under {data_dir}/news and named as {sanitized_stock_symbol}.csv. It has a mutex so that
+/// it can be shared between threads.
+#[derive(Debug)]
+pub struct NewsLog {
+    file: Mutex<File>,
+}
+
+impl NewsLog {
+    /// Given the data directory and a string slice containing the stock symbol, it opens
+    /// or creates the news file of the stock
+    ///
+    /// # Example
+    /// ```
+    /// use std::path::Path;
+    /// use finnhub_ws::news::NewsLog;
+    /// let log = NewsLog::open(Path::new("data"), "TSLA").unwrap();
+    /// std::fs::remove_file("data/news/TSLA.csv").unwrap();
+    /// ```
+    pub fn open(data_dir: &Path, stock: &str) -> io::Result<Self> {
+        let dir = data_dir.join(NEWS_DIR);
+        if !create_dirs(&dir.to_string_lossy()) {
+            return Err(io::Error::other(format!("couldn't create {}", dir.display())));
+        }
+        let file = open_append(&dir.join(format!("{}.csv", sanitize_string(stock))))?;
+        Ok(NewsLog { file: Mutex::new(file) })
+    }
+
+    /// Appends the headline to the file. The headers are written only when the file is empty.
+    /// It fails if a thread panicked while appending to it.
+    pub fn append(&self, headline: &Headline) -> Result<()> {
+        let file = self.file.lock().map_err(|_| io::Error::other("a thread panicked while writing the news"))?;
+        append_csv(&file, [headline])
+    }
+}
+
+
+#[cfg(test)]
+mod news_test {
+    use std::fs::{read_to_string, remove_file};
+    use std::path::Path;
+    use chrono::{TimeZone, Utc};
+    use crate::news::{Headline, NewsItem, NewsLog};
+
+    #[test]
+    fn given_headlines_it_should_write_them_with_headers_once() {
+        let item = NewsItem {
+            related: "NEWS:LOG".to_string(),
+            datetime: Utc.ymd(2022, 7, 21).and_hms(22, 7, 0),
+            headline: "Shares jump, after earnings".to_string(),
+            source: "Reuters".to_string(),
+            url: "https://example.com/news".to_string(),
+        };
+        let received = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 1, 500);
+        let log = NewsLog::open(Path::new("data"), "NEWS:LOG").unwrap();
+        log.append(&Headline::new("NEWS:LOG", &item, received)).unwrap();
+        log.append(&Headline::new("NEWS:LOG", &item, received)).unwrap();
+        let data = read_to_string("data/news/NEWS_LOG.csv").unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "Symbol,Timestamp,ReceiveTimestamp,Headline,Source,Url");
+        assert_eq!(lines[1], "NEWS:LOG,1658441220000,1658441221500,\"Shares jump, after earnings\",Reuters,https://example.com/news");
+        remove_file("data/news/NEWS_LOG.csv").unwrap();
+    }
+}
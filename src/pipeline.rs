@@ -0,0 +1,1040 @@
+//! Processing pipeline primitives
+//! # pipeline
+//!
+//! This contains the functions that make up the processing pipeline of the program,
+//! from keeping the websocket connection alive and reading from the stream, to writing
+//! the transactions to the rolling files and calculating the candlestick and mean data
+//! each minute. They live in the library so that the pipeline can be run against the
+//! mock server of the `mock` module and be tested offline.
+use std::fs::File;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{TimeZone, Utc};
+use futures_util::{Sink, SinkExt, Stream, StreamExt, stream::{SplitSink, SplitStream}};
+use rayon::prelude::*;
+use tokio::{net::TcpStream, sync::{mpsc::UnboundedReceiver, watch, Mutex as AsyncMutex}, task::JoinHandle, time::{self, Duration}};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, tungstenite::{self, protocol::Message}, WebSocketStream};
+use crate::{
+    News, Response, SubscribeInfo, WsMessage, RollingData,
+    connection::{Backoff, ReconnectEvent},
+    control::SubscriptionChange,
+    error::{Error, ErrorCounts, Result},
+    news::Headline,
+    recorder::FrameRecorder,
+    rotation::RetentionPolicy,
+    scheduler::MinuteScheduler,
+    stock_handle::{Mapper, StockHandle, WindowSignal},
+    store::{FsyncPolicy, Store},
+};
+
+/// The write half of the websocket connection
+pub type WsWrite = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+/// The read half of the websocket connection
+pub type WsRead = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// `supervise_connection` keeps the connection to finnhub alive. It connects, subscribes to the
+/// stocks of the mapper and reads from the stream until the connection drops, either because of a
+/// server close frame, a tungstenite error or the stream just ending. Then it waits according to a
+/// jittered exponential backoff and reconnects. The backoff starts over once a connection delivered
+/// a data frame, rather than as soon as it got accepted. Each reconnection gets logged to the
+/// connection log along with the downtime it caused. The malformed messages get counted to `errors`.
+/// The news of the stocks get subscribed to as well if `news` is set. The subscription changes received
+/// from `changes` get sent over the live connection, while the ones received while disconnected are
+/// covered by subscribing to the stocks of the mapper on reconnection. It returns once `shutdown` is
+/// set, after unsubscribing from the stocks and closing the connection. Every frame read gets appended
+/// to `recorder` if there is one.
+#[allow(clippy::too_many_arguments)]
+pub async fn supervise_connection(url: &url::Url, news: bool, mapper: &Mapper, changes: &mut UnboundedReceiver<SubscriptionChange>, log: &File, errors: &ErrorCounts, recorder: Option<&FrameRecorder>, mut shutdown: watch::Receiver<bool>) {
+    let mut backoff = Backoff::default();
+    let mut reconnects: u64 = 0;
+    // the time and the reason the connection got lost, None while connected
+    let mut disconnected: Option<(chrono::DateTime<Utc>, String)> = None;
+    loop {
+        match connect_async(url.clone()).await {
+            Ok((ws_stream, _)) => {
+                let (write, read) = ws_stream.split();
+                let write = AsyncMutex::new(write);
+                // the backoff only gets reset once the connection delivered data, so that a server
+                // closing the connections it accepts right away doesn't get reconnected to in a loop
+                let delivered = AtomicBool::new(false);
+                let mut read = read.inspect(|message| {
+                    if matches!(message, Ok(Message::Text(_)) | Ok(Message::Binary(_))) {
+                        delivered.store(true, Ordering::Relaxed);
+                    }
+                });
+                if let Some((since, reason)) = disconnected.take() {
+                    reconnects += 1;
+                    let event = ReconnectEvent::new(reconnects, backoff.attempts(), since, Utc::now(), &reason);
+                    println!("Reconnected after {}ms ({} attempts)", event.downtime_millis, event.attempts);
+                    if let Err(e) = event.write_to_file(log) {
+                        eprintln!("Couldn't log the reconnection: {}", e);
+                    }
+                }
+                while changes.try_recv().is_ok() {}
+                let subscribed = subscribe_to_stocks(&mut *write.lock().await, &mapper.symbols(), news).await;
+                let reason = match subscribed {
+                    Ok(_) => {
+                        let reading = read_from_stream(&mut read, &write, mapper, errors, recorder);
+                        tokio::pin!(reading);
+                        loop {
+                            tokio::select! {
+                                reason = &mut reading => break reason,
+                                Some(change) = changes.recv() => {
+                                    if let Err(e) = send_change(&mut *write.lock().await, &change, news).await {
+                                        eprintln!("Couldn't send {:?}: {}", change, e);
+                                    }
+                                }
+                                _ = shutdown.changed() => {
+                                    close_connection(&mut *write.lock().await, &mapper.symbols(), news).await;
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => format!("subscription failed: {}", e),
+                };
+                if delivered.load(Ordering::Relaxed) {
+                    backoff.reset();
+                }
+                eprintln!("Connection lost: {}", reason);
+                disconnected = Some((Utc::now(), reason));
+            }
+            Err(e) => {
+                eprintln!("Failed to connect: {}", e);
+                if disconnected.is_none() {
+                    disconnected = Some((Utc::now(), format!("connect failed: {}", e)));
+                }
+            }
+        }
+        let delay = backoff.next_delay();
+        eprintln!("Reconnecting in {}ms", delay.as_millis());
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/// Sends the subscription messages of a change to finnhub
+async fn send_change(write: &mut WsWrite, change: &SubscriptionChange, news: bool) -> tungstenite::Result<()> {
+    for message in change.messages(news) {
+        write.send(Message::Text(serde_json::to_string(&message).unwrap_or_default())).await?;
+    }
+    Ok(())
+}
+
+/// `close_connection` unsubscribes from the given stocks, and their news if `news` is set, and
+/// sends a close frame to finnhub. Errors are only logged, as the connection is going away anyway.
+async fn close_connection(write: &mut WsWrite, stocks: &[String], news: bool) {
+    let mut messages: Vec<SubscribeInfo> = stocks.iter().map(|stock| SubscribeInfo::unsubscribe(stock)).collect();
+    if news {
+        messages.extend(stocks.iter().map(|stock| SubscribeInfo::unsubscribe_news(stock)));
+    }
+    for message in messages {
+        let stock = message.stock_symbol;
+        let item = match serde_json::to_string(&message) {
+            Ok(item) => item,
+            Err(e) => {
+                eprintln!("Couldn't unsubscribe from {}: {}", stock, e);
+                continue;
+            }
+        };
+        if let Err(e) = write.send(Message::Text(item)).await {
+            eprintln!("Couldn't unsubscribe from {}: {}", stock, e);
+        }
+    }
+    if let Err(e) = write.send(Message::Close(None)).await {
+        eprintln!("Couldn't close the connection: {}", e);
+    }
+}
+
+/// `shutdown_signal` completes once the process receives either SIGINT or SIGTERM.
+/// If SIGTERM can't be listened for, it only waits for SIGINT.
+pub async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => Some(sigterm),
+        Err(e) => {
+            eprintln!("Couldn't listen for SIGTERM: {}", e);
+            None
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => println!("Received SIGINT, shutting down"),
+        _ = async { sigterm.as_mut().unwrap().recv().await }, if sigterm.is_some() => println!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// `tick` is being used to send a signal to threads waiting to calculate
+/// the candlestick and mean data at the end of each minute. The window of each UTC minute
+/// gets closed once `lateness` passed since it ended, so that the trades arriving late
+/// still make it into the candlestick of the minute of their exchange timestamp. The
+/// deadlines are aligned to the wall clock by a `MinuteScheduler`, which emits the windows
+/// missed while the runtime stalled in order, and every tick gets written to the tick log
+/// along with its jitter.
+///
+/// # Arguments
+/// mapper : the stock handles whose threads calculating the candlestick and mean data
+///      get the signals, including the ones added while the program runs
+/// lateness: how long to wait after the end of a minute for its trades to arrive
+/// log: the file the ticks get written to
+/// shutdown: a watch receiver which is set once the program shuts down. Then, a final
+///      `WindowSignal::Shutdown` gets sent to the threads and the function returns
+pub async fn tick(mapper: &Mapper, lateness: chrono::Duration, log: &File, mut shutdown: watch::Receiver<bool>) {
+    let mut scheduler = MinuteScheduler::new(mapper.clock().now(), lateness);
+    loop {
+        // the wait gets calculated from the clock every time, so that it doesn't drift
+        let wait = (scheduler.deadline() - mapper.clock().now()).to_std().unwrap_or(Duration::ZERO);
+        tokio::select! {
+            _ = time::sleep(wait) => send_due_windows(mapper, &mut scheduler, log),
+            _ = shutdown.changed() => {
+                send_signal(mapper, WindowSignal::Shutdown(mapper.clock().now().timestamp_millis()));
+                return;
+            }
+        }
+    }
+}
+
+/// `send_due_windows` sends the windows whose deadline passed by the time of the clock of the
+/// mapper to the threads calculating the candlestick and mean data, oldest first, and writes
+/// each tick to the tick log. It sends nothing if the deadline of the next window hasn't passed.
+pub fn send_due_windows(mapper: &Mapper, scheduler: &mut MinuteScheduler, log: &File) {
+    for tick in scheduler.due(mapper.clock().now()) {
+        if tick.caught_up {
+            eprintln!("Missed the window of {}, closing it {}ms late", tick.window, tick.jitter_millis);
+        }
+        if let Err(e) = tick.write_to_file(log) {
+            eprintln!("Couldn't log the tick: {}", e);
+        }
+        send_signal(mapper, WindowSignal::Window(tick.window.timestamp()));
+    }
+}
+
+/// `send_signal` sends the signal to the threads calculating the candlestick and mean data of
+/// every stock. A worker which failed doesn't keep the others from getting the signal, while
+/// the workers of a stock which failed only get sent the shutdown signal.
+pub fn send_signal(mapper: &Mapper, signal: WindowSignal) {
+    for handle in mapper.handles().iter().filter(|h| signal.is_shutdown() || !h.has_failed()) {
+        for (tx, _) in [&handle.stock_channel, &handle.rolling_mean_channel] {
+            if tx.send(signal).is_err() {
+                eprintln!("Couldn't send {:?} to a worker of {}", signal, handle.stock_symbol);
+            }
+        }
+    }
+}
+
+/// `enforce_retention` applies the retention policy to the date partitions of the data files
+/// under `root` right away and then once an hour, so that the disk doesn't fill up while the
+/// program runs for days. The policy gets applied on a blocking thread, as compressing the
+/// files of a partition can take a while. It returns once `shutdown` is set.
+///
+/// # Arguments
+/// policy: what happens to the date partitions as they get older
+/// root: the directory the data files get written to, such as data
+/// shutdown: a watch receiver which is set once the program shuts down
+pub async fn enforce_retention(policy: RetentionPolicy, root: &Path, mut shutdown: watch::Receiver<bool>) {
+    let mut interval = time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let root = root.to_path_buf();
+                if let Err(e) = tokio::task::spawn_blocking(move || policy.enforce(&root, Utc::now())).await {
+                    eprintln!("Couldn't enforce the retention policy: {}", e);
+                }
+            }
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+/// `read_from_stream` reads data from the websocket and converts a byte array to `WsMessage` enum instance.
+/// The data frames of a type the program doesn't know about get logged, while the ones which aren't
+/// json, or whose payload doesn't match their known type, get logged, counted to `errors` and skipped. The ping and pong control frames are left to
+/// tungstenite, which answers the pings of the server on its own. Every frame gets appended to
+/// `recorder` before being parsed, if there is one. It returns the reason the stream stopped, which
+/// can be a close frame sent by the server, a tungstenite error or the stream just ending. Besides
+/// the websocket, it can read the `Playback` of a recording, writing to the `discard` sink.
+pub async fn read_from_stream<R, W>(read: &mut R, write: &AsyncMutex<W>, mapper: &Mapper, errors: &ErrorCounts, recorder: Option<&FrameRecorder>) -> String
+where
+    R: Stream<Item = tungstenite::Result<Message>> + Unpin,
+    W: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    while let Some(message) = read.next().await {
+        let received = mapper.clock().now();
+        if let (Some(recorder), Ok(message)) = (recorder, &message) {
+            if let Err(e) = recorder.record(received, message) {
+                let e = Error::from(e);
+                errors.record(&e);
+                eprintln!("Couldn't record a frame: {}", e);
+            }
+        }
+        let x = match message {
+            Ok(Message::Close(frame)) => {
+                return match frame {
+                    Some(f) => format!("server closed the connection: {} {}", f.code, f.reason),
+                    None => "server closed the connection".to_string(),
+                };
+            }
+            Ok(Message::Ping(_)) | Ok(Message::Pong(_)) | Ok(Message::Frame(_)) => continue,
+            Ok(Message::Text(text)) => text.into_bytes(),
+            Ok(Message::Binary(data)) => data,
+            Err(ref e) => {
+                println!("{:?}", e);
+                return format!("{}", e);
+            }
+        };
+        let data = match WsMessage::parse(&x) {
+            Ok(data) => data,
+            Err(e) => {
+                let e = Error::from(e);
+                errors.record(&e);
+                eprintln!("Skipping a message: {}", e);
+                continue;
+            }
+        };
+        match data {
+            WsMessage::Response(mut resp) => {
+                resp.received = received;
+                parse_message(&resp, mapper)
+            }
+            WsMessage::News(news) => parse_news(&news, mapper),
+            WsMessage::Ping(ping) => {
+                println!("{:?}", ping);
+                if let Err(e) = write.lock().await.send(Message::Pong("".into())).await {
+                    return format!("{}", e);
+                }
+                println!("Pong sent");
+            },
+            WsMessage::Error(err) => eprintln!("finnhub sent an error: {}", err.message),
+            WsMessage::Unknown(message) => eprintln!("Unknown message: {}", message),
+        }
+    }
+    "stream ended".to_string()
+}
+
+/// `wait_for_candlestick` blocks until data is retrieved from the channel.
+/// Then, for each candlestick series, it closes the intervals of its aggregator
+/// which ended by the given minute, which has been updated with every trade
+/// of the intervals, and writes the candlesticks to the store of the stock.
+/// On shutdown, the candlesticks of the intervals in progress of every
+/// series get written marked as partial and the function returns.
+/// The writes which fail get logged and counted to the errors of the stock,
+/// while it fails if it can't receive the signals or lock an aggregator.
+pub fn wait_for_candlestick(handle: &StockHandle) -> Result<()> {
+    let (_, rx) = handle.rolling_mean_channel.clone();
+    loop {
+        // this blocks the thread
+        let signal = rx.recv().map_err(|_| Error::Disconnected(handle.stock_symbol.clone()))?;
+        for series in &handle.candlesticks {
+            let mut aggregator = lock(&series.aggregator, handle)?;
+            let candlesticks = match signal {
+                WindowSignal::Window(timestamp) => aggregator.close(Utc.timestamp(timestamp, 0)),
+                WindowSignal::Shutdown(millis) => aggregator.flush(Utc.timestamp_millis(millis)),
+            };
+            drop(aggregator);
+            for cs in candlesticks {
+                if let Err(e) = handle.store.append_candle(series.interval, &cs) {
+                    eprintln!("Couldn't write the candlestick of {}: {}", handle.stock_symbol, e);
+                    handle.errors.record(&e.into());
+                }
+            }
+        }
+        if signal.is_shutdown() {
+            return Ok(());
+        }
+    }
+}
+
+/// `wait_for_mean` blocks until data is retrieved from the channel.
+/// Then, it closes the sliding window of the mean aggregator at the given
+/// minute, which holds the trades of the last 15 minutes, and writes the
+/// mean data to the store of the stock. On shutdown, the
+/// mean data of the 15 minutes up to the time of the shutdown get written
+/// marked as partial and the function returns. It fails the way
+/// `wait_for_candlestick` does.
+pub fn wait_for_mean(handle: &StockHandle) -> Result<()> {
+    let (_, rx) = handle.stock_channel.clone();
+    loop {
+        let signal = rx.recv().map_err(|_| Error::Disconnected(handle.stock_symbol.clone()))?;
+        let end = match signal {
+            WindowSignal::Window(timestamp) => Utc.timestamp(timestamp, 0),
+            WindowSignal::Shutdown(millis) => Utc.timestamp_millis(millis),
+        };
+        let means = lock(&handle.mean, handle)?.close(end);
+        for mut md in means {
+            md.partial = signal.is_shutdown();
+            if let Err(e) = handle.store.append_mean(&md) {
+                eprintln!("Couldn't write the mean data of {}: {}", handle.stock_symbol, e);
+                handle.errors.record(&e.into());
+            }
+        }
+        if signal.is_shutdown() {
+            return Ok(());
+        }
+    }
+}
+
+/// `run_worker` runs a worker of the stock, such as `wait_for_candlestick`, until it returns.
+/// If the worker fails or panics, the failure gets logged and counted to the errors of the
+/// stock, which gets marked as failed, and the function returns, so that the workers of the
+/// other stocks keep going.
+///
+/// # Example
+/// ```
+/// use finnhub_ws::error::Error;
+/// use finnhub_ws::pipeline::run_worker;
+/// use finnhub_ws::stock_handle::{initialize_mapper, MapperOptions};
+/// let mapper = initialize_mapper(&["BINANCE:ETHUSDT".to_string()], &MapperOptions::default());
+/// let handle = mapper.get("BINANCE:ETHUSDT").unwrap();
+/// run_worker(&handle, |handle| Err(Error::Disconnected(handle.stock_symbol.clone())));
+/// assert_eq!(handle.errors.failed_workers(), 1);
+/// assert!(handle.has_failed());
+/// ```
+pub fn run_worker(handle: &StockHandle, worker: impl FnOnce(&StockHandle) -> Result<()>) {
+    let error = match panic::catch_unwind(AssertUnwindSafe(|| worker(handle))) {
+        Ok(Ok(())) => return,
+        Ok(Err(e)) => e,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Error::Panicked(handle.stock_symbol.clone(), message)
+        }
+    };
+    handle.errors.record(&error);
+    eprintln!("A worker of {} stopped: {}", handle.stock_symbol, error);
+    if handle.fail() {
+        eprintln!("Stopped aggregating the trades of {}", handle.stock_symbol);
+    }
+}
+
+/// `spawn_workers` starts the candlestick and mean workers of a stock on blocking threads of
+/// the runtime, the way the workers of the stocks added while the program runs get started,
+/// and returns their join handles. They return once they get sent a `WindowSignal::Shutdown`.
+pub fn spawn_workers(handle: &Arc<StockHandle>) -> Vec<JoinHandle<()>> {
+    let workers: [fn(&StockHandle) -> Result<()>; 2] = [wait_for_candlestick, wait_for_mean];
+    workers.into_iter().map(|worker| {
+        let handle = Arc::clone(handle);
+        tokio::task::spawn_blocking(move || run_worker(&handle, worker))
+    }).collect()
+}
+
+/// Locks an aggregator of the stock, failing if a thread panicked while holding it
+fn lock<'a, T>(mutex: &'a Mutex<T>, handle: &StockHandle) -> Result<MutexGuard<'a, T>> {
+    mutex.lock().map_err(|_| Error::Poisoned(handle.stock_symbol.clone()))
+}
+
+/// `subscribe_to_stocks`: Given a channel and an array of strings containing the stock names,
+/// it sends a websocket message to finnhub to subscribe to that stock, and another one to
+/// subscribe to its news if `news` is set
+pub async fn subscribe_to_stocks(tx: &mut WsWrite, stocks: &[String], news: bool) -> tungstenite::Result<()> {
+    let news_items = stocks.iter().filter(|_| news).map(|item| SubscribeInfo::news(item));
+    let items = stocks.iter().map(|item| {
+        SubscribeInfo::new(item)
+    }).chain(news_items).map(|x1| {
+        serde_json::to_string(&x1)
+    }).collect::<serde_json::Result<Vec<String>>>().map_err(|e| tungstenite::Error::Io(e.into()))?;
+    for item in items {
+        tx.send(Message::Text(item)).await?;
+    }
+    Ok(())
+}
+
+/// `parse_message` given a response and a reference to the `Mapper`, routes
+/// the transactions of the response to their stock by symbol and, for each stock, it
+/// writes them to its store at once and updates the candlestick and mean
+/// aggregators of the stock with the ones its condition filter accepts.
+/// The transactions of a stock keep the order they had in the response.
+/// A stock whose write or aggregators fail gets its error logged and
+/// counted, without keeping the other stocks from being handled. The
+/// transactions of the symbols which aren't tracked get counted by the mapper,
+/// while the latencies of the ones written get recorded to their stock.
+pub fn parse_message(resp: &Response, mapper: &Mapper) {
+    let written = mapper.clock().now();
+    // the handles of the stocks the trades are for get cloned, so that the lock of the
+    // mapper isn't held while writing them, keeping the stocks from being added or removed
+    let batches: Vec<(Arc<StockHandle>, Vec<RollingData>)> = {
+        let handles = mapper.handles();
+        let mut batches: Vec<Vec<RollingData>> = vec![Vec::new(); handles.len()];
+        for x in &resp.transaction_data {
+            match handles.position(&x.symbol) {
+                Some(index) => batches[index].push(RollingData::new(x, written)),
+                None => mapper.record_unknown(&x.symbol),
+            }
+        }
+        handles.iter().cloned().zip(batches).filter(|(_, records)| !records.is_empty()).collect()
+    };
+    batches.par_iter().for_each(|(handle, records)| {
+        match handle.store.append_trades(records) {
+            Ok(_) => handle.latency.record(records, resp.received, mapper.clock().now()),
+            Err(e) => {
+                eprintln!("Couldn't write the trades of {}: {}", handle.stock_symbol, e);
+                handle.errors.record(&e.into());
+            }
+        }
+        if handle.has_failed() {
+            return;
+        }
+        if let Err(e) = aggregate(handle, records) {
+            handle.errors.record(&e);
+            eprintln!("Couldn't aggregate the trades of {}: {}", handle.stock_symbol, e);
+            if handle.fail() {
+                eprintln!("Stopped aggregating the trades of {}", handle.stock_symbol);
+            }
+        }
+    });
+}
+
+/// `parse_news` given a news message and a reference to a `StockHandle`, writes the headline
+/// of each news item to the news file of every stock it is related to, and flags the candlesticks
+/// of the intervals it got received in. The time it got published at isn't used, as the interval
+/// it falls in can be closed already by the time the article arrives. The news of the stocks
+/// whose news aren't tracked get skipped, while the failures get logged and counted to the
+/// errors of the stock.
+pub fn parse_news(news: &News, mapper: &Mapper) {
+    let received = mapper.clock().now();
+    let handles = mapper.handles().to_vec();
+    for item in &news.news_data {
+        for handle in handles.iter().filter(|h| item.is_related_to(&h.stock_symbol)) {
+            let log = match &handle.news {
+                Some(log) => log,
+                None => continue,
+            };
+            if let Err(e) = log.append(&Headline::new(&handle.stock_symbol, item, received)) {
+                eprintln!("Couldn't write the news of {}: {}", handle.stock_symbol, e);
+                handle.errors.record(&e);
+            }
+            for series in handle.candlesticks.iter().filter(|_| !handle.has_failed()) {
+                match lock(&series.aggregator, handle) {
+                    Ok(mut aggregator) => aggregator.mark_news(received),
+                    Err(e) => {
+                        eprintln!("Couldn't flag the news of {}: {}", handle.stock_symbol, e);
+                        handle.errors.record(&e);
+                        handle.fail();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Updates the aggregators of the stock with the trades its condition filter accepts
+fn aggregate(handle: &StockHandle, records: &[RollingData]) -> Result<()> {
+    let accepted: Vec<&RollingData> = records.iter().filter(|r| handle.condition_filter.accepts(r)).collect();
+    for series in &handle.candlesticks {
+        let mut aggregator = lock(&series.aggregator, handle)?;
+        accepted.iter().for_each(|r| aggregator.update(r));
+    }
+    let mut mean = lock(&handle.mean, handle)?;
+    accepted.iter().for_each(|r| mean.update(r));
+    Ok(())
+}
+
+/// `write_latency` writes the latencies of the trades of each stock recorded since the last
+/// period to its latency file every `interval`. It returns once `shutdown` is set, after
+/// writing the latencies of the last period.
+pub async fn write_latency(mapper: &Mapper, interval: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut timer = time::interval(interval);
+    timer.tick().await;
+    loop {
+        tokio::select! {
+            _ = timer.tick() => write_latency_rows(mapper),
+            _ = shutdown.changed() => {
+                write_latency_rows(mapper);
+                return;
+            }
+        }
+    }
+}
+
+/// `write_latency_rows` writes the latencies of every stock recorded since the last call to their
+/// latency files under the data directory of the mapper, logging and counting the writes which fail
+pub fn write_latency_rows(mapper: &Mapper) {
+    let now = mapper.clock().now();
+    for handle in mapper.handles().to_vec() {
+        if let Err(e) = handle.latency.write(&mapper.options().data_dir, &handle.stock_symbol, now) {
+            eprintln!("Couldn't write the latency of {}: {}", handle.stock_symbol, e);
+            handle.errors.record(&e);
+        }
+    }
+}
+
+/// `flush_stores` writes the trades buffered by the stores of the stocks every `flush_interval`,
+/// if any, and syncs the stores to the disk every `fsync_interval` under the `Interval` fsync
+/// policy. It returns once `shutdown` is set, after writing the trades still buffered.
+///
+/// # Arguments
+/// mapper: the stock handles holding the stores
+/// flush_interval: how often the buffered trades get written. The stores write them once per
+///      message on their own if none
+/// fsync: how often the data written get synced to the disk
+/// fsync_interval: how often the stores get synced with the `Interval` fsync policy
+/// shutdown: a watch receiver which is set once no more trades get written to the stores
+///
+/// Both intervals should be longer than zero, which the command line makes sure of.
+pub async fn flush_stores(mapper: &Mapper, flush_interval: Option<Duration>, fsync: FsyncPolicy, fsync_interval: Duration, mut shutdown: watch::Receiver<bool>) {
+    let mut flush_timer = flush_interval.map(time::interval);
+    let mut sync_timer = (fsync == FsyncPolicy::Interval).then(|| time::interval(fsync_interval));
+    loop {
+        tokio::select! {
+            _ = async { flush_timer.as_mut().unwrap().tick().await }, if flush_timer.is_some() => {
+                for_each_store(mapper, "write the buffered trades of", |store| store.flush());
+            }
+            _ = async { sync_timer.as_mut().unwrap().tick().await }, if sync_timer.is_some() => {
+                for_each_store(mapper, "sync the data of", |store| store.sync());
+            }
+            _ = shutdown.changed() => {
+                for_each_store(mapper, "write the buffered trades of", |store| store.flush());
+                return;
+            }
+        }
+    }
+}
+
+/// `sync_stores` writes the trades buffered by the stores of the stocks and syncs the stores to
+/// the disk, unless the fsync policy is `Never`. It should be called once nothing gets written
+/// to the stores anymore, so that the data of the last window don't wait for the next sync.
+pub fn sync_stores(mapper: &Mapper, fsync: FsyncPolicy) {
+    match fsync {
+        FsyncPolicy::Never => for_each_store(mapper, "write the buffered trades of", |store| store.flush()),
+        _ => for_each_store(mapper, "sync the data of", |store| store.sync()),
+    }
+}
+
+/// Runs the operation on the store of each stock, logging the errors
+fn for_each_store(mapper: &Mapper, action: &str, operation: impl Fn(&dyn Store) -> std::io::Result<()>) {
+    for handle in mapper.handles().to_vec() {
+        if let Err(e) = operation(handle.store.as_ref()) {
+            eprintln!("Couldn't {} {}: {}", action, handle.stock_symbol, e);
+            handle.errors.record(&e.into());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod pipeline_test {
+    use std::fs::{read_to_string, remove_file, File};
+    use std::path::Path;
+    use std::sync::Arc;
+    use chrono::{TimeZone, Utc};
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+    use tokio::sync::{mpsc, watch, Mutex as AsyncMutex};
+    use tokio::time::{self, Duration};
+    use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+    use serial_test::serial;
+    use crate::connection::create_connection_log;
+    use crate::control::SubscriptionChange;
+    use crate::mock::{ping_frame, trade_frame, MockServer};
+    use crate::condition::ConditionFilter;
+    use crate::error::ErrorCounts;
+    use crate::news::NewsItem;
+    use crate::pipeline::{parse_message, parse_news, read_from_stream, run_worker, send_due_windows, send_signal, supervise_connection, wait_for_candlestick, wait_for_mean};
+    use crate::clock::{Clock, ManualClock};
+    use crate::recorder::{discard, playback, read_recording, FrameKind, FrameRecorder};
+    use crate::replay::ReplaySpeed;
+    use crate::scheduler::MinuteScheduler;
+    use crate::stock_handle::{initialize_mapper, MapperOptions, StockHandle, WindowSignal};
+    use crate::stock_handle::stock_handle_test::remove_handle_files;
+    use crate::utils::{create_dirs, sanitize_string};
+    use crate::{News, Response, RollingData, TickerInfo};
+
+    /// Returns the path of the file of the current date of a stock under the given directory
+    fn today(dir: &str, handle: &StockHandle) -> String {
+        format!("{}/{}/{}.csv", dir, Utc::now().format("%Y-%m-%d"), sanitize_string(&handle.stock_symbol))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn given_a_mock_server_it_should_write_the_trades_to_the_rolling_file() {
+        let _ = create_dirs("data/connection");
+        let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+        let frames = vec![
+            ping_frame(),
+            "not json".to_string(),
+            r#"{"type":"trade","data":[{"s":"MOCK:PIPE"}]}"#.to_string(),
+            r#"{"type":"subscribed","symbol":"MOCK:PIPE"}"#.to_string(),
+            trade_frame(vec![TickerInfo::new("MOCK:PIPE", 172.5, 1.0, &date, &[]),
+                             TickerInfo::new("MOCK:PIPE", 173.5, 2.0, &date, &[])]),
+            trade_frame(vec![TickerInfo::new("MOCK:OTHER", 10.0, 1.0, &date, &[])]),
+        ];
+        let server = MockServer::start(frames, Duration::from_millis(1)).await.unwrap();
+        let stocks = vec!["MOCK:PIPE".to_string()];
+        let mapper = initialize_mapper(&stocks, &MapperOptions::default());
+        let url = url::Url::parse(&server.url()).unwrap();
+        let log = create_connection_log().unwrap();
+        let reader_mapper = Arc::clone(&mapper);
+        let errors = Arc::new(ErrorCounts::default());
+        let reader_errors = Arc::clone(&errors);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (_changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            supervise_connection(&url, false, &reader_mapper, &mut changes_rx, &log, &reader_errors, None, shutdown_rx).await;
+        });
+        let handle = mapper.get("MOCK:PIPE").unwrap();
+        let rolling = today("data/rolling", &handle);
+        let mut rows = 0;
+        for _ in 0..100 {
+            rows = read_to_string(&rolling).unwrap().lines().count();
+            if rows == 3 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        shutdown_tx.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        assert_eq!(rows, 3);
+        // the message which isn't json and the trade missing its fields got skipped rather than
+        // stopping the reader, while the acknowledgement isn't an error
+        assert_eq!(errors.malformed_messages(), 2);
+        let data = read_to_string(&rolling).unwrap();
+        assert!(data.lines().skip(1).all(|l| l.starts_with("MOCK:PIPE,")));
+        // the trade of the symbol which isn't tracked got counted rather than dropped
+        assert_eq!(mapper.unknown_trades().get("MOCK:OTHER"), Some(&1));
+        remove_handle_files(&handle);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn given_a_recorded_session_it_should_reproduce_it_in_process() {
+        let _ = create_dirs("data/connection");
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/pipeline_session.bin");
+        let _ = remove_file(path);
+        let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+        let frames = vec![
+            "not json".to_string(),
+            trade_frame(vec![TickerInfo::new("MOCK:REC", 172.5, 1.0, &date, &["1".to_string()]),
+                             TickerInfo::new("MOCK:REC", 173.5, 2.0, &date, &[])]),
+        ];
+        let server = MockServer::start(frames, Duration::from_millis(1)).await.unwrap();
+        let url = url::Url::parse(&server.url()).unwrap();
+        let mapper = initialize_mapper(&["MOCK:REC".to_string()], &MapperOptions::default());
+        let reader_mapper = Arc::clone(&mapper);
+        let log = create_connection_log().unwrap();
+        let recorder = FrameRecorder::create(path).unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (_changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            let errors = ErrorCounts::default();
+            supervise_connection(&url, false, &reader_mapper, &mut changes_rx, &log, &errors, Some(&recorder), shutdown_rx).await;
+        });
+        let handle = mapper.get("MOCK:REC").unwrap();
+        let rolling = today("data/rolling", &handle);
+        for _ in 0..100 {
+            if read_to_string(&rolling).unwrap().lines().count() == 3 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        shutdown_tx.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        let live = read_to_string(&rolling).unwrap();
+        remove_handle_files(&handle);
+
+        let frames = read_recording(path).unwrap();
+        assert_eq!(frames.iter().map(|f| f.kind).collect::<Vec<_>>(), vec![FrameKind::Text, FrameKind::Text]);
+        assert_eq!(frames[0].payload, b"not json");
+        let replayed = initialize_mapper(&["MOCK:REC".to_string()], &MapperOptions::default());
+        let errors = ErrorCounts::default();
+        let reason = read_from_stream(&mut playback(&frames, ReplaySpeed::Max), &AsyncMutex::new(discard()), &replayed, &errors, None).await;
+        assert_eq!(reason, "stream ended");
+        assert_eq!(errors.malformed_messages(), 1);
+        let handle = replayed.get("MOCK:REC").unwrap();
+        // the trades are the same, apart from the time they got written at
+        let without_write_timestamp = |data: &str| data.lines()
+            .map(|l| l.split(',').enumerate().filter(|(i, _)| *i != 3).map(|(_, f)| f).collect::<Vec<_>>().join(","))
+            .collect::<Vec<_>>();
+        let played = read_to_string(today("data/rolling", &handle)).unwrap();
+        assert_eq!(without_write_timestamp(&played), without_write_timestamp(&live));
+        assert_eq!(played.lines().count(), 3);
+        remove_handle_files(&handle);
+        remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn it_should_send_the_subscription_changes_over_the_live_connection() {
+        let _ = create_dirs("data/connection");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        // a server recording the messages it receives, until the connection gets closed
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_async(stream).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(Message::Text(text))) = ws.next().await {
+                received.push(text);
+            }
+            received
+        });
+        let mapper = initialize_mapper(&["MOCK:LIVE".to_string()], &MapperOptions::default());
+        let reader_mapper = Arc::clone(&mapper);
+        let log = create_connection_log().unwrap();
+        let errors = Arc::new(ErrorCounts::default());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            supervise_connection(&url, false, &reader_mapper, &mut changes_rx, &log, &errors, None, shutdown_rx).await;
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        let added = mapper.add("MOCK:ADDED").unwrap();
+        changes_tx.send(SubscriptionChange::Subscribe("MOCK:ADDED".to_string())).unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        shutdown_tx.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        let received = time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(received, vec![
+            r#"{"type":"subscribe","symbol":"MOCK:LIVE"}"#.to_string(),
+            r#"{"type":"subscribe","symbol":"MOCK:ADDED"}"#.to_string(),
+            r#"{"type":"unsubscribe","symbol":"MOCK:LIVE"}"#.to_string(),
+            r#"{"type":"unsubscribe","symbol":"MOCK:ADDED"}"#.to_string(),
+        ]);
+        remove_handle_files(&mapper.get("MOCK:LIVE").unwrap());
+        remove_handle_files(&added);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn given_a_server_closing_right_away_it_should_keep_backing_off() {
+        let _ = create_dirs("tmp");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = url::Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        // a server accepting the connections and closing them before sending anything
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = accept_async(stream).await.unwrap();
+                let _ = ws.close(None).await;
+            }
+        });
+        let mapper = initialize_mapper(&[], &MapperOptions::default());
+        let path = "tmp/pipeline_reconnects.csv";
+        let log = File::create(path).unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (_changes_tx, mut changes_rx) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            supervise_connection(&url, false, &mapper, &mut changes_rx, &log, &ErrorCounts::default(), None, shutdown_rx).await;
+        });
+        let mut rows = Vec::new();
+        for _ in 0..150 {
+            rows = read_to_string(path).unwrap().lines().skip(1).map(String::from).collect::<Vec<_>>();
+            if rows.len() == 2 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        shutdown_tx.send(true).unwrap();
+        time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        // the attempts keep adding up, as none of the connections delivered data
+        let attempts: Vec<&str> = rows.iter().map(|r| r.split(',').nth(1).unwrap()).collect();
+        assert_eq!(attempts, vec!["1", "2"]);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn on_shutdown_it_should_write_a_partial_candlestick_and_return() {
+        let mapper = initialize_mapper(&["MOCK:PARTIAL".to_string()], &MapperOptions::default());
+        let handle = &mapper.get("MOCK:PARTIAL").unwrap();
+        let ticker = TickerInfo::new("MOCK:PARTIAL", 172.5, 1.0, &Utc::now(), &[]);
+        handle.candlesticks[0].aggregator.lock().unwrap().update(&RollingData::from(&ticker));
+        let (tx, _) = &handle.rolling_mean_channel;
+        tx.send(WindowSignal::Shutdown(Utc::now().timestamp_millis() + 1)).unwrap();
+        wait_for_candlestick(handle).unwrap();
+        let data = read_to_string(today("data/candlestick/1m", handle)).unwrap();
+        let last = data.lines().last().unwrap();
+        assert!(last.starts_with("MOCK:PARTIAL,"));
+        assert!(last.ends_with(",true,1.0,172.5,172.5,false,0,false"), "{}", last);
+        remove_handle_files(handle);
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_close_each_candlestick_series_at_its_own_boundary() {
+        let options = MapperOptions { candle_intervals: vec!["1m".parse().unwrap(), "5m".parse().unwrap()], ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["MOCK:SERIES".to_string()], &options);
+        let handle = &mapper.get("MOCK:SERIES").unwrap();
+        let (tx, _) = &handle.rolling_mean_channel;
+        let minute = Utc.ymd(2022, 7, 21).and_hms(22, 8, 30);
+        let ticker = TickerInfo::new("MOCK:SERIES", 172.5, 1.0, &minute, &[]);
+        for series in &handle.candlesticks {
+            series.aggregator.lock().unwrap().update(&RollingData::from(&ticker));
+        }
+        tx.send(WindowSignal::Window(Utc.ymd(2022, 7, 21).and_hms(22, 9, 0).timestamp())).unwrap();
+        tx.send(WindowSignal::Window(Utc.ymd(2022, 7, 21).and_hms(22, 10, 0).timestamp())).unwrap();
+        tx.send(WindowSignal::Shutdown(Utc.ymd(2022, 7, 21).and_hms(22, 11, 0).timestamp_millis())).unwrap();
+        wait_for_candlestick(handle).unwrap();
+        // the candlesticks go to the partition of their date, away from the placeholder row
+        // written on initialization
+        let one = read_to_string("data/candlestick/1m/2022-07-21/MOCK_SERIES.csv").unwrap();
+        let five = read_to_string("data/candlestick/5m/2022-07-21/MOCK_SERIES.csv").unwrap();
+        let one: Vec<&str> = one.lines().collect();
+        let five: Vec<&str> = five.lines().collect();
+        assert_eq!(one, vec!["MOCK:SERIES,2022-07-21T22:08:00Z,172.5,172.5,172.5,172.5,1,false,1.0,172.5,172.5,false,0,false"]);
+        assert_eq!(five, vec!["MOCK:SERIES,2022-07-21T22:05:00Z,172.5,172.5,172.5,172.5,1,false,1.0,172.5,172.5,false,0,false"]);
+        remove_file("data/candlestick/1m/2022-07-21/MOCK_SERIES.csv").unwrap();
+        remove_file("data/candlestick/5m/2022-07-21/MOCK_SERIES.csv").unwrap();
+        remove_handle_files(handle);
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_write_every_trade_but_only_aggregate_the_accepted_ones() {
+        let options = MapperOptions { condition_filter: ConditionFilter::new(&[], &["2".to_string()]), ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["MOCK:COND".to_string()], &options);
+        let handle = &mapper.get("MOCK:COND").unwrap();
+        let resp = Response {
+            transaction_data: vec![
+                TickerInfo::new("MOCK:COND", 172.5, 100.0, &Utc::now(), &["1".to_string()]),
+                TickerInfo::new("MOCK:COND", 180.0, 1.0, &Utc::now(), &["2".to_string()]),
+            ],
+            ..Response::default()
+        };
+        parse_message(&resp, &mapper);
+        let rolling = read_to_string(today("data/rolling", handle)).unwrap();
+        assert_eq!(rolling.lines().count(), 3);
+        assert!(rolling.lines().any(|l| l.ends_with(",2")));
+        // the latencies of every trade written got recorded, regardless of their conditions
+        let latency = handle.latency.take_rows("MOCK:COND", Utc::now());
+        assert_eq!(latency.iter().map(|r| (r.kind.as_str(), r.count)).collect::<Vec<_>>(), vec![("exchange", 2), ("processing", 2)]);
+        let cs = handle.candlesticks[0].aggregator.lock().unwrap().flush(Utc::now()).remove(0);
+        assert_eq!(cs.total_transactions, 1);
+        assert_eq!(cs.highest_price, 172.5);
+        let means = handle.mean.lock().unwrap().close(Utc::now() + chrono::Duration::seconds(1));
+        assert_eq!(means[0].transactions, 1);
+        remove_handle_files(handle);
+    }
+
+    #[test]
+    #[serial]
+    fn given_news_it_should_write_the_headlines_and_flag_the_candlestick() {
+        let received = Utc.timestamp_millis(Utc::now().timestamp_millis());
+        let minute = Utc.timestamp(received.timestamp() / 60 * 60, 0);
+        let clock = Arc::new(ManualClock::new(received));
+        let options = MapperOptions { news: true, clock, ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["MOCK:NEWS".to_string(), "MOCK:QUIET".to_string()], &options);
+        let (handle, quiet) = (&mapper.get("MOCK:NEWS").unwrap(), &mapper.get("MOCK:QUIET").unwrap());
+        // the article got published in a minute which closed already, so the candlestick
+        // of the minute it got received in is the one flagged
+        let news = News {
+            message_type: "news",
+            news_data: vec![NewsItem {
+                related: "MOCK:NEWS".to_string(),
+                datetime: minute - chrono::Duration::minutes(10),
+                headline: "MOCK:NEWS soars".to_string(),
+                source: "mock".to_string(),
+                url: "".to_string(),
+            }],
+        };
+        parse_news(&news, &mapper);
+        let resp = Response {
+            transaction_data: vec![TickerInfo::new("MOCK:NEWS", 172.5, 1.0, &received, &[])],
+            ..Response::default()
+        };
+        parse_message(&resp, &mapper);
+        let headlines = read_to_string("data/news/MOCK_NEWS.csv").unwrap();
+        let published = minute - chrono::Duration::minutes(10);
+        assert!(headlines.lines().nth(1).unwrap().starts_with(&format!("MOCK:NEWS,{},{},", published.timestamp_millis(), received.timestamp_millis())));
+        assert!(read_to_string("data/news/MOCK_QUIET.csv").unwrap().is_empty());
+        let cs = handle.candlesticks[0].aggregator.lock().unwrap().close(minute + chrono::Duration::minutes(1));
+        assert!(cs[0].news);
+        remove_file("data/news/MOCK_NEWS.csv").unwrap();
+        remove_file("data/news/MOCK_QUIET.csv").unwrap();
+        remove_handle_files(handle);
+        remove_handle_files(quiet);
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_failed_stock_the_other_stocks_should_keep_going() {
+        let mapper = initialize_mapper(&["MOCK:FAIL".to_string(), "MOCK:OK".to_string()], &MapperOptions::default());
+        let (failed, ok) = (&mapper.get("MOCK:FAIL").unwrap(), &mapper.get("MOCK:OK").unwrap());
+        // a worker of the first stock panics while holding its aggregator
+        run_worker(failed, |handle| {
+            let _aggregator = handle.mean.lock().unwrap();
+            panic!("boom");
+        });
+        assert_eq!(failed.errors.failed_workers(), 1);
+        let resp = Response {
+            transaction_data: vec![
+                TickerInfo::new("MOCK:FAIL", 172.5, 1.0, &Utc::now(), &[]),
+                TickerInfo::new("MOCK:OK", 10.0, 1.0, &Utc::now(), &[]),
+            ],
+            ..Response::default()
+        };
+        parse_message(&resp, &mapper);
+        parse_message(&resp, &mapper);
+        // the trades still got written, while the stock stopped being aggregated once its worker failed
+        assert_eq!(read_to_string(today("data/rolling", failed)).unwrap().lines().count(), 3);
+        assert_eq!(failed.errors.failed_workers(), 1);
+        assert!(failed.has_failed() && !ok.has_failed());
+        send_signal(&mapper, WindowSignal::Window(Utc::now().timestamp_millis()));
+        assert!(failed.stock_channel.1.is_empty());
+        assert_eq!(ok.stock_channel.1.len(), 1);
+        assert_eq!(ok.errors.total(), 0);
+        let means = ok.mean.lock().unwrap().close(Utc::now() + chrono::Duration::seconds(1));
+        assert_eq!(means[0].transactions, 2);
+        remove_handle_files(failed);
+        remove_handle_files(ok);
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_manual_clock_it_should_close_whole_minutes_deterministically() {
+        let _ = create_dirs("tmp");
+        let at = |h, m, s| Utc.ymd(2022, 7, 21).and_hms(h, m, s);
+        let clock = Arc::new(ManualClock::new(at(22, 7, 30)));
+        let options = MapperOptions { mean_windows: vec!["1m".parse().unwrap()], clock: clock.clone(), ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["MOCK:CLOCK".to_string()], &options);
+        let handle = mapper.get("MOCK:CLOCK").unwrap();
+        let mut scheduler = MinuteScheduler::new(clock.now(), chrono::Duration::seconds(5));
+        let log = File::create("tmp/clock_ticks.csv").unwrap();
+        let trade = |price, time| Response { transaction_data: vec![TickerInfo::new("MOCK:CLOCK", price, 1.0, &time, &[])], ..Response::default() };
+        parse_message(&trade(10.0, at(22, 7, 10)), &mapper);
+        parse_message(&trade(12.0, at(22, 7, 20)), &mapper);
+        clock.set(at(22, 7, 50));
+        parse_message(&trade(11.0, at(22, 7, 45)), &mapper);
+        // the window of 22:07 doesn't get closed before its lateness passed
+        clock.set(at(22, 8, 4));
+        send_due_windows(&mapper, &mut scheduler, &log);
+        assert!(handle.stock_channel.1.is_empty());
+        clock.set(at(22, 8, 5));
+        send_due_windows(&mapper, &mut scheduler, &log);
+        clock.set(at(22, 8, 20));
+        parse_message(&trade(20.0, at(22, 8, 15)), &mapper);
+        // the runtime stalls past the deadlines of two windows, which both get closed in order
+        clock.set(at(22, 10, 6));
+        send_due_windows(&mapper, &mut scheduler, &log);
+        for (tx, _) in [&handle.stock_channel, &handle.rolling_mean_channel] {
+            tx.send(WindowSignal::Shutdown(clock.now().timestamp_millis())).unwrap();
+        }
+        wait_for_candlestick(&handle).unwrap();
+        wait_for_mean(&handle).unwrap();
+        let candlesticks = read_to_string("data/candlestick/1m/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        assert_eq!(candlesticks.lines().skip(1).collect::<Vec<&str>>(), vec![
+            "MOCK:CLOCK,2022-07-21T22:07:00Z,10.0,11.0,12.0,10.0,3,false,3.0,11.0,33.0,false,0,false",
+            "MOCK:CLOCK,2022-07-21T22:08:00Z,20.0,20.0,20.0,20.0,1,false,1.0,20.0,20.0,false,0,false",
+        ]);
+        let means = read_to_string("data/mean/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        assert_eq!(means.lines().collect::<Vec<&str>>(), vec![
+            "MOCK:CLOCK,2022-07-21T22:07:30Z,2022-07-21T22:07:50Z,11.0,3,false,1m",
+            "MOCK:CLOCK,2022-07-21T22:08:20Z,2022-07-21T22:08:20Z,20.0,1,false,1m",
+        ]);
+        let ticks = read_to_string("tmp/clock_ticks.csv").unwrap();
+        assert_eq!(ticks.lines().skip(1).collect::<Vec<&str>>(), vec![
+            "1658441280000,1658441285000,1658441285000,false,0",
+            "1658441340000,1658441345000,1658441406000,true,61000",
+            "1658441400000,1658441405000,1658441406000,false,1000",
+        ]);
+        let rolling = read_to_string("data/rolling/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        assert_eq!(rolling.lines().nth(1).unwrap(), "MOCK:CLOCK,10.0,1658441230000,1658441250000,1.0,");
+        remove_file("data/rolling/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        remove_file("data/candlestick/1m/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        remove_file("data/mean/2022-07-21/MOCK_CLOCK.csv").unwrap();
+        remove_file("tmp/clock_ticks.csv").unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//! Recorder primitives
+//! # recorder
+//!
+//! This contains the recorder of the raw frames of the websocket session, and their playback.
+//! The rolling files only keep the trades of the stocks being tracked, while a recording keeps
+//! every frame read from the stream byte for byte along with the time it got received, so that
+//! the messages the parser stumbled on, the way the trades were grouped and the time they arrived
+//! at can be reproduced from a capture. Each frame gets stored the way the bincode journal stores
+//! the trades, prefixed by its length. A recording can be played back by the mock server or as a
+//! stream handed straight to `read_from_stream`.
+//!
+//! # Example
+//! ```
+//! use chrono::{TimeZone, Utc};
+//! use tokio_tungstenite::tungstenite::protocol::Message;
+//! use finnhub_ws::recorder::{FrameKind, RecordedFrame};
+//! let received = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+//! let frame = RecordedFrame::new(received, &Message::Text(r#"{"type":"ping"}"#.to_string())).unwrap();
+//! assert_eq!(frame.kind, FrameKind::Text);
+//! assert_eq!(frame.message(), Message::Text(r#"{"type":"ping"}"#.to_string()));
+//! ```
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use chrono::{DateTime, Utc, serde::ts_microseconds};
+use crossbeam_channel::{unbounded, Sender};
+use futures_util::{Sink, SinkExt, StreamExt, sink, stream::{self, BoxStream}};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::{self, protocol::{frame::coding::CloseCode, CloseFrame, Message}};
+use crate::journal::{write_record, JournalReader};
+use crate::replay::ReplaySpeed;
+
+/// The stream of the messages of a recording being played back, the way they get read
+/// from the websocket
+pub type Playback = BoxStream<'static, tungstenite::Result<Message>>;
+
+/// `FrameKind` is the kind of a websocket frame
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// A text frame, which is how finnhub sends its messages
+    Text,
+    /// A binary frame
+    Binary,
+    /// A ping control frame
+    Ping,
+    /// A pong control frame
+    Pong,
+    /// A close frame
+    Close,
+}
+
+/// `RecordedFrame`: represents a frame read from the websocket, as it got recorded
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// the time the frame got received, with microsecond precision
+    #[serde(with = "ts_microseconds")]
+    pub received: DateTime<Utc>,
+    /// the kind of the frame
+    pub kind: FrameKind,
+    /// the payload of the frame. The one of a close frame is its big-endian code followed
+    /// by its reason, the way it gets sent, or nothing if it had no code
+    pub payload: Vec<u8>,
+}
+
+impl RecordedFrame {
+    /// Given the time a message got received and the message, creates and returns the frame
+    /// to be recorded. There is none for the raw frames, which don't get read from the websocket.
+    pub fn new(received: DateTime<Utc>, message: &Message) -> Option<Self> {
+        let (kind, payload) = match message {
+            Message::Text(text) => (FrameKind::Text, text.as_bytes().to_vec()),
+            Message::Binary(data) => (FrameKind::Binary, data.clone()),
+            Message::Ping(data) => (FrameKind::Ping, data.clone()),
+            Message::Pong(data) => (FrameKind::Pong, data.clone()),
+            Message::Close(frame) => {
+                let payload = frame.as_ref().map(|f| {
+                    let mut payload = u16::from(f.code).to_be_bytes().to_vec();
+                    payload.extend_from_slice(f.reason.as_bytes());
+                    payload
+                });
+                (FrameKind::Close, payload.unwrap_or_default())
+            }
+            Message::Frame(_) => return None,
+        };
+        Some(RecordedFrame { received, kind, payload })
+    }
+
+    /// Returns the message the frame holds, as it got read from the websocket
+    pub fn message(&self) -> Message {
+        match self.kind {
+            FrameKind::Text => Message::Text(String::from_utf8_lossy(&self.payload).into_owned()),
+            FrameKind::Binary => Message::Binary(self.payload.clone()),
+            FrameKind::Ping => Message::Ping(self.payload.clone()),
+            FrameKind::Pong => Message::Pong(self.payload.clone()),
+            FrameKind::Close if self.payload.len() < 2 => Message::Close(None),
+            FrameKind::Close => Message::Close(Some(CloseFrame {
+                code: CloseCode::from(u16::from_be_bytes([self.payload[0], self.payload[1]])),
+                reason: String::from_utf8_lossy(&self.payload[2..]).into_owned().into(),
+            })),
+        }
+    }
+}
+
+/// `FrameRecorder` is the file the frames read from the websocket get appended to. The frames get
+/// handed to a writer thread, so that writing them doesn't block the loop reading the websocket,
+/// and each frame gets written with a single write, so that it is on disk even if the program
+/// crashes on it. The frames handed to it are all written once it gets dropped.
+#[derive(Debug)]
+pub struct FrameRecorder {
+    /// sender: where the frames get handed to the writer thread, None once dropped
+    sender: Option<Sender<RecordedFrame>>,
+    /// writer: the thread writing the frames to the file, None once dropped
+    writer: Option<JoinHandle<()>>,
+}
+
+impl FrameRecorder {
+    /// Given the path of the recording, it opens or creates it and starts the thread writing
+    /// to it. The frames of the sessions recorded to an existing file get appended to it.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let (sender, receiver) = unbounded::<RecordedFrame>();
+        let writer = thread::Builder::new().name("recorder".to_string()).spawn(move || {
+            for frame in receiver {
+                if let Err(e) = write_record(&file, &frame) {
+                    eprintln!("Couldn't record a frame: {}", e);
+                }
+            }
+        })?;
+        Ok(FrameRecorder { sender: Some(sender), writer: Some(writer) })
+    }
+
+    /// Hands the message received at `received` to the writer thread, failing if it stopped
+    pub fn record(&self, received: DateTime<Utc>, message: &Message) -> io::Result<()> {
+        let (frame, sender) = match (RecordedFrame::new(received, message), &self.sender) {
+            (Some(frame), Some(sender)) => (frame, sender),
+            _ => return Ok(()),
+        };
+        sender.send(frame).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the recorder stopped"))
+    }
+}
+
+impl Drop for FrameRecorder {
+    /// Waits for the writer thread to write the frames it got handed
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                eprintln!("The recorder panicked");
+            }
+        }
+    }
+}
+
+/// Reads the frames of a recording in the order they got received. A recording which ends
+/// in the middle of a frame, such as one written by a process which got killed, gets read
+/// up to its last complete frame.
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use finnhub_ws::recorder::read_recording;
+/// assert!(read_recording(Path::new("data/no-such-recording.bin")).is_err());
+/// ```
+pub fn read_recording(path: &Path) -> io::Result<Vec<RecordedFrame>> {
+    let mut frames = Vec::new();
+    for frame in JournalReader::new(BufReader::new(File::open(path)?)) {
+        match frame {
+            Ok(frame) => frames.push(frame),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                eprintln!("Skipping the partial frame at the end of {}", path.display());
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(frames)
+}
+
+/// Returns the time each frame should be played back at after the first one, at the given speed
+///
+/// # Example
+/// ```
+/// use chrono::{Duration, TimeZone, Utc};
+/// use tokio_tungstenite::tungstenite::protocol::Message;
+/// use finnhub_ws::recorder::{offsets, RecordedFrame};
+/// let received = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+/// let frames: Vec<RecordedFrame> = [0, 4].iter()
+///     .map(|s| RecordedFrame::new(received + Duration::seconds(*s), &Message::Ping(vec![])).unwrap())
+///     .collect();
+/// assert_eq!(offsets(&frames, "2x".parse().unwrap()), vec![std::time::Duration::ZERO, std::time::Duration::from_secs(2)]);
+/// ```
+pub fn offsets(frames: &[RecordedFrame], speed: ReplaySpeed) -> Vec<Duration> {
+    let first = frames.first().map(|f| f.received);
+    frames.iter()
+        .map(|f| first.and_then(|first| speed.wait(f.received - first)).unwrap_or_default())
+        .collect()
+}
+
+/// Returns a stream of the messages of the frames, each one yielded at the time it got received
+/// after the first one, at the given speed, counting from the time the stream gets created.
+/// It can be handed to `read_from_stream` along with `discard` to reproduce a session in process.
+pub fn playback(frames: &[RecordedFrame], speed: ReplaySpeed) -> Playback {
+    let start = Instant::now();
+    let schedule: Vec<(Duration, Message)> = offsets(frames, speed).into_iter()
+        .zip(frames.iter().map(|f| f.message()))
+        .collect();
+    stream::iter(schedule).then(move |(offset, message)| async move {
+        // the wait gets calculated from the start every time, so that it doesn't drift
+        time::sleep_until(start + offset).await;
+        Ok(message)
+    }).boxed()
+}
+
+/// Returns a sink which drops the messages sent to it, such as the pongs answering the pings
+/// of a session being played back
+pub fn discard() -> impl Sink<Message, Error = tungstenite::Error> + Unpin {
+    sink::drain().sink_map_err(|never| match never {})
+}
+
+
+#[cfg(test)]
+mod recorder_test {
+    use std::fs::{remove_file, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+    use chrono::{Duration, TimeZone, Utc};
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
+    use crate::recorder::{playback, read_recording, FrameRecorder, RecordedFrame};
+    use crate::replay::ReplaySpeed;
+    use crate::utils::create_dirs;
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Text(r#"{"type":"trade","data":[{"s":"AAPL","p":172.5,"v":1,"t":1658441258376,"c":null}]}"#.to_string()),
+            Message::Binary(vec![0, 159, 146, 150]),
+            Message::Ping(b"alive".to_vec()),
+            Message::Close(Some(CloseFrame { code: CloseCode::Away, reason: "restarting".into() })),
+            Message::Close(None),
+        ]
+    }
+
+    #[test]
+    fn given_the_frames_of_a_session_it_should_read_them_back_byte_for_byte() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/recorder_frames.bin");
+        let _ = remove_file(path);
+        let recorder = FrameRecorder::create(path).unwrap();
+        let received = Utc.ymd(2022, 7, 21).and_hms_micro(22, 7, 38, 376_512);
+        for (i, message) in messages().iter().enumerate() {
+            recorder.record(received + Duration::milliseconds(i as i64), message).unwrap();
+        }
+        // the frames are all written once the recorder is dropped
+        drop(recorder);
+        // the process got killed in the middle of writing a frame
+        OpenOptions::new().append(true).open(path).unwrap().write_all(&[200, 0, 0, 0, 1]).unwrap();
+        let frames = read_recording(path).unwrap();
+        assert_eq!(frames.iter().map(RecordedFrame::message).collect::<Vec<_>>(), messages());
+        assert_eq!(frames[0].received, received);
+        assert_eq!(frames[4].received, received + Duration::milliseconds(4));
+        remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_should_play_the_frames_back_at_the_time_they_got_received() {
+        let received = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+        let frames: Vec<RecordedFrame> = messages().iter().enumerate()
+            .map(|(i, m)| RecordedFrame::new(received + Duration::milliseconds(40 * i as i64), m).unwrap())
+            .collect();
+        let start = tokio::time::Instant::now();
+        let played: Vec<Message> = playback(&frames, ReplaySpeed::Times(2)).map(|m| m.unwrap()).collect().await;
+        assert_eq!(played, messages());
+        assert!(start.elapsed() >= std::time::Duration::from_millis(80));
+    }
+}
@@ -0,0 +1,283 @@
+//! Crash recovery primitives
+//! # recovery
+//!
+//! This contains the integrity pass run on the data files before they get appended to. If the
+//! program gets killed in the middle of a write, the file it was writing to ends with a partial
+//! row, which the next run would append its first row to, leaving a row which can't be parsed.
+//! The pass validates the header and the tail of each file and truncates the corrupt bytes at its
+//! end, saving them to a `.corrupt` file next to it so that nothing gets lost for good.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use serde::de::DeserializeOwned;
+use crate::candlestick::Candlestick;
+use crate::journal;
+use crate::mean::MeanData;
+use crate::RollingData;
+
+/// The headers every csv rolling file starts with. The files written before the volume
+/// and conditions columns were added only have the first four.
+const ROLLING_HEADER: &str = "Symbol,Price,Timestamp,WriteTimestamp";
+
+/// How many bytes from the end of a csv file get looked at for corrupt rows
+const TAIL_LENGTH: u64 = 64 * 1024;
+
+/// `Layout` is what a data file holds, which decides how it gets validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// A csv rolling file, starting with its headers
+    Rolling,
+    /// A bincode journal of trades
+    Journal,
+    /// A csv candlestick file, without headers
+    Candlestick,
+    /// A csv mean file, without headers
+    Mean,
+}
+
+/// `Damage` is what was wrong with a data file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The file didn't start with the headers of its layout, so all of it got removed
+    InvalidHeader,
+    /// The file ended in the middle of a row or record
+    PartialRow,
+    /// The last rows of the file couldn't be parsed
+    InvalidRow,
+}
+
+/// `Repair` describes a data file the integrity pass repaired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Repair {
+    /// The path of the repaired file
+    pub path: PathBuf,
+    /// What was wrong with the file
+    pub damage: Damage,
+    /// The number of bytes removed from the end of the file
+    pub removed: u64,
+    /// The file the removed bytes got appended to
+    pub quarantine: PathBuf,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let damage = match self.damage {
+            Damage::InvalidHeader => "an invalid header",
+            Damage::PartialRow => "a partial row",
+            Damage::InvalidRow => "an invalid row",
+        };
+        write!(f, "Repaired {} which ended with {}: moved its last {} bytes to {}",
+               self.path.display(), damage, self.removed, self.quarantine.display())
+    }
+}
+
+/// Validates the header and the tail of a data file, and truncates the corrupt bytes at its
+/// end if any, after appending them to the quarantine file `{path}.corrupt`. It returns what
+/// was repaired, or None if the file was fine. Compressed files are left alone, as they never
+/// get appended to.
+///
+/// # Arguments
+/// - `path` - the path of the data file
+/// - `layout` - what the data file holds
+///
+/// # Example
+/// ```
+/// use std::fs;
+/// use std::path::Path;
+/// use finnhub_ws::recovery::{repair_file, Damage, Layout};
+/// use finnhub_ws::utils::create_dirs;
+/// let _ = create_dirs("tmp");
+/// fs::write("tmp/repair_doc.csv", "Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions\nAAPL,172.5,1658441258376,1658441270794,1,\nAAPL,17").unwrap();
+/// let repair = repair_file(Path::new("tmp/repair_doc.csv"), Layout::Rolling).unwrap().unwrap();
+/// assert_eq!(repair.damage, Damage::PartialRow);
+/// assert_eq!(fs::read_to_string("tmp/repair_doc.csv.corrupt").unwrap(), "AAPL,17");
+/// assert!(fs::read_to_string("tmp/repair_doc.csv").unwrap().ends_with(",1,\n"));
+/// fs::remove_file("tmp/repair_doc.csv").unwrap();
+/// fs::remove_file("tmp/repair_doc.csv.corrupt").unwrap();
+/// ```
+pub fn repair_file(path: &Path, layout: Layout) -> io::Result<Option<Repair>> {
+    if matches!(path.extension(), Some(extension) if extension == "gz") {
+        return Ok(None);
+    }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let length = file.metadata()?.len();
+    let (valid, damage) = match layout {
+        Layout::Journal => (journal::valid_length(&file, length, TAIL_LENGTH)?, Damage::PartialRow),
+        Layout::Rolling => valid_csv_length::<RollingData>(&mut file, length, true)?,
+        Layout::Candlestick => valid_csv_length::<Candlestick>(&mut file, length, false)?,
+        Layout::Mean => valid_csv_length::<MeanData>(&mut file, length, false)?,
+    };
+    if valid == length {
+        return Ok(None);
+    }
+    let mut quarantine = path.as_os_str().to_owned();
+    quarantine.push(".corrupt");
+    let quarantine = PathBuf::from(quarantine);
+    // the bytes removed can be most of a large file, so they get copied rather than read at once
+    file.seek(SeekFrom::Start(valid))?;
+    io::copy(&mut (&file).take(length - valid), &mut OpenOptions::new().append(true).create(true).open(&quarantine)?)?;
+    file.set_len(valid)?;
+    file.sync_all()?;
+    Ok(Some(Repair {
+        path: path.to_path_buf(),
+        damage,
+        removed: length - valid,
+        quarantine,
+    }))
+}
+
+/// Returns the length in bytes of the valid part of a csv data file, along with what was wrong
+/// with the rest of it. The rows at the end of the file get removed one by one until the last
+/// one is complete and can be parsed as a `T`.
+fn valid_csv_length<T: DeserializeOwned>(file: &mut File, length: u64, has_headers: bool) -> io::Result<(u64, Damage)> {
+    let mut header = String::new();
+    let mut body_start = 0;
+    if has_headers {
+        let mut start = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.take(TAIL_LENGTH).read_to_end(&mut start)?;
+        match start.iter().position(|b| *b == b'\n') {
+            Some(end) if start.starts_with(ROLLING_HEADER.as_bytes()) => {
+                header = String::from_utf8_lossy(&start[..end]).to_string();
+                body_start = end as u64 + 1;
+            }
+            // the file holds nothing but the start of its header, so it was killed while
+            // writing it and the header gets written again once it is empty
+            None if ROLLING_HEADER.as_bytes().starts_with(&start) || start.starts_with(ROLLING_HEADER.as_bytes()) => {
+                return Ok((0, Damage::PartialRow));
+            }
+            _ => return Ok((0, Damage::InvalidHeader)),
+        }
+    }
+    let tail_start = body_start.max(length.saturating_sub(TAIL_LENGTH));
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.read_to_end(&mut tail)?;
+    let mut valid = tail.iter().rposition(|b| *b == b'\n').map_or(0, |end| end + 1);
+    let mut damage = if valid < tail.len() { Some(Damage::PartialRow) } else { None };
+    while valid > 0 {
+        let row_start = tail[..valid - 1].iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        if row_start == 0 && tail_start > body_start {
+            // the row may have started before the tail, so it gets trusted
+            break;
+        }
+        if parses::<T>(&header, &tail[row_start..valid]) {
+            break;
+        }
+        damage.get_or_insert(Damage::InvalidRow);
+        valid = row_start;
+    }
+    Ok((tail_start + valid as u64, damage.unwrap_or(Damage::PartialRow)))
+}
+
+/// Returns true if the row parses as a `T`, with the given headers if the file has any
+fn parses<T: DeserializeOwned>(header: &str, row: &[u8]) -> bool {
+    let mut data = Vec::with_capacity(header.len() + row.len() + 1);
+    if !header.is_empty() {
+        data.extend_from_slice(header.as_bytes());
+        data.push(b'\n');
+    }
+    data.extend_from_slice(row);
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(!header.is_empty())
+        .flexible(true)
+        .from_reader(data.as_slice());
+    matches!(reader.deserialize::<T>().next(), Some(Ok(_)))
+}
+
+
+#[cfg(test)]
+mod recovery_test {
+    use std::fs::{self, remove_file, OpenOptions};
+    use std::io::Write;
+    use std::path::Path;
+    use chrono::{TimeZone, Utc};
+    use serial_test::serial;
+    use crate::candlestick::Candlestick;
+    use crate::journal::write_record;
+    use crate::recovery::{repair_file, Damage, Layout};
+    use crate::utils::create_dirs;
+    use crate::RollingData;
+
+    const HEADER: &str = "Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions\n";
+    const ROW: &str = "AAPL,172.5,1658441258376,1658441270794,1,\n";
+
+    fn remove(path: &str) {
+        let _ = remove_file(path);
+        let _ = remove_file(format!("{}.corrupt", path));
+    }
+
+    #[test]
+    #[serial]
+    fn a_valid_file_should_be_left_alone() {
+        let _ = create_dirs("tmp");
+        fs::write("tmp/recovery_valid.csv", format!("{}{}{}", HEADER, ROW, ROW)).unwrap();
+        assert_eq!(repair_file(Path::new("tmp/recovery_valid.csv"), Layout::Rolling).unwrap(), None);
+        fs::write("tmp/recovery_valid.csv", HEADER).unwrap();
+        assert_eq!(repair_file(Path::new("tmp/recovery_valid.csv"), Layout::Rolling).unwrap(), None);
+        assert!(!Path::new("tmp/recovery_valid.csv.corrupt").exists());
+        remove("tmp/recovery_valid.csv");
+    }
+
+    #[test]
+    #[serial]
+    fn given_rows_which_cant_be_parsed_it_should_remove_them() {
+        let _ = create_dirs("tmp");
+        let corrupt = "AAPL,172.5,165844AAPL,172.5,1658441258376,1658441270794,1,\n";
+        fs::write("tmp/recovery_invalid.csv", format!("{}{}{}", HEADER, ROW, corrupt)).unwrap();
+        let repair = repair_file(Path::new("tmp/recovery_invalid.csv"), Layout::Rolling).unwrap().unwrap();
+        assert_eq!(repair.damage, Damage::InvalidRow);
+        assert_eq!(repair.removed, corrupt.len() as u64);
+        assert_eq!(fs::read_to_string("tmp/recovery_invalid.csv").unwrap(), format!("{}{}", HEADER, ROW));
+        assert_eq!(fs::read_to_string("tmp/recovery_invalid.csv.corrupt").unwrap(), corrupt);
+        remove("tmp/recovery_invalid.csv");
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_partial_header_it_should_empty_the_file() {
+        let _ = create_dirs("tmp");
+        fs::write("tmp/recovery_header.csv", "Symbol,Pri").unwrap();
+        let repair = repair_file(Path::new("tmp/recovery_header.csv"), Layout::Rolling).unwrap().unwrap();
+        assert_eq!(repair.damage, Damage::PartialRow);
+        assert_eq!(fs::read_to_string("tmp/recovery_header.csv").unwrap(), "");
+        fs::write("tmp/recovery_header.csv", "garbage\n").unwrap();
+        let repair = repair_file(Path::new("tmp/recovery_header.csv"), Layout::Rolling).unwrap().unwrap();
+        assert_eq!(repair.damage, Damage::InvalidHeader);
+        assert_eq!(fs::read_to_string("tmp/recovery_header.csv.corrupt").unwrap(), "Symbol,Prigarbage\n");
+        remove("tmp/recovery_header.csv");
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_partial_candlestick_it_should_truncate_it() {
+        let _ = create_dirs("tmp");
+        let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        writer.serialize(Candlestick::default()).unwrap();
+        let row = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        fs::write("tmp/recovery_candle.csv", format!("{}{}", row, &row[..row.len() / 2])).unwrap();
+        let repair = repair_file(Path::new("tmp/recovery_candle.csv"), Layout::Candlestick).unwrap().unwrap();
+        assert_eq!(repair.damage, Damage::PartialRow);
+        assert_eq!(fs::read_to_string("tmp/recovery_candle.csv").unwrap(), row);
+        remove("tmp/recovery_candle.csv");
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_journal_ending_in_a_partial_record_it_should_truncate_it() {
+        let _ = create_dirs("tmp");
+        let date = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376);
+        let record = RollingData { symbol: "AAPL".to_string(), price: 172.5, timestamp: date, write_timestamp: date, volume: 1.0, conditions: vec![] };
+        let mut journal = Vec::new();
+        write_record(&mut journal, &record).unwrap();
+        let length = journal.len();
+        write_record(&mut journal, &record).unwrap();
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open("tmp/recovery_journal.bin").unwrap();
+        file.write_all(&journal[..length + 7]).unwrap();
+        let repair = repair_file(Path::new("tmp/recovery_journal.bin"), Layout::Journal).unwrap().unwrap();
+        assert_eq!(repair.removed, 7);
+        assert_eq!(fs::read("tmp/recovery_journal.bin").unwrap(), journal[..length]);
+        remove("tmp/recovery_journal.bin");
+    }
+}
@@ -0,0 +1,360 @@
+//! Replay primitives
+//! # replay
+//!
+//! This contains the replay of the rolling files already written. Their trades get fed through
+//! `parse_message` and the candlestick and mean workers the way the trades arriving from finnhub
+//! do, so that the candlestick and mean data can be regenerated after a fix, or the pipeline can
+//! be benchmarked without a connection. The trades written at the same time make up a message.
+//! The replay runs on a `ManualClock` which follows the write timestamps of the trades, so the
+//! windows get closed at the times they were closed live, whatever the speed of the replay.
+//! Everything gets written under a separate data directory, so that the files being replayed
+//! don't get appended to.
+//!
+//! # Example
+//! ```
+//! use chrono::Duration;
+//! use finnhub_ws::replay::ReplaySpeed;
+//! let speed: ReplaySpeed = "10x".parse().unwrap();
+//! assert_eq!(speed, ReplaySpeed::Times(10));
+//! assert_eq!(speed.wait(Duration::seconds(60)), Some(std::time::Duration::from_secs(6)));
+//! assert_eq!("max".parse::<ReplaySpeed>().unwrap().wait(Duration::seconds(60)), None);
+//! ```
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use chrono::{DateTime, Duration, Utc};
+use crate::clock::{Clock, ManualClock};
+use crate::error::Result;
+use crate::latency::rolling_format;
+use crate::pipeline::{parse_message, run_worker, send_due_windows, send_signal, sync_stores, wait_for_candlestick, wait_for_mean, write_latency_rows};
+use crate::scheduler::{create_tick_log, MinuteScheduler};
+use crate::stock_handle::{initialize_mapper, Mapper, MapperOptions, StockHandle, WindowSignal};
+use crate::store::{rolling_trades, RollingTrades};
+use crate::utils::create_dirs;
+use crate::{Response, RollingData, TickerInfo};
+
+/// `ReplaySpeed` decides how fast the trades get replayed compared to how they got written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    /// The trades get replayed the given number of times faster than they got written,
+    /// 1x being the speed they arrived at
+    Times(u32),
+    /// The trades get replayed as fast as they can be processed
+    Max,
+}
+
+impl ReplaySpeed {
+    /// Returns how long the replay should have taken by the time `elapsed` passed since the
+    /// first trade got written, or None if it shouldn't wait at all
+    pub fn wait(&self, elapsed: Duration) -> Option<std::time::Duration> {
+        match self {
+            ReplaySpeed::Times(times) => Some(elapsed.to_std().unwrap_or_default() / *times),
+            ReplaySpeed::Max => None,
+        }
+    }
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(ReplaySpeed::Max);
+        }
+        match s.strip_suffix('x').unwrap_or(s).parse::<u32>() {
+            Ok(times) if times > 0 => Ok(ReplaySpeed::Times(times)),
+            _ => Err(format!("invalid replay speed {}, expected a multiple such as 1x or 10x, or max", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReplaySpeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplaySpeed::Times(times) => write!(f, "{}x", times),
+            ReplaySpeed::Max => write!(f, "max"),
+        }
+    }
+}
+
+/// `MergedTrades` merges the trades of several rolling files, each in the order they got
+/// written, into the order they got written across the files. Only the next trade of each
+/// file is held in memory, so that the replay doesn't depend on the size of the files. The
+/// trades written at the same time keep the order of their files.
+pub struct MergedTrades {
+    /// the trades of each file, read as they get merged
+    files: Vec<RollingTrades>,
+    /// the next trade of each file, if it has any left
+    next: Vec<Option<RollingData>>,
+    /// the write timestamp of the next trade of each file, earliest first
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, usize)>>,
+}
+
+impl MergedTrades {
+    /// Given the trades of the files, creates and returns their merge, reading the first
+    /// trade of each file
+    pub fn new(files: Vec<RollingTrades>) -> io::Result<Self> {
+        let mut merged = MergedTrades { next: files.iter().map(|_| None).collect(), files, heap: BinaryHeap::new() };
+        for index in 0..merged.files.len() {
+            merged.advance(index)?;
+        }
+        Ok(merged)
+    }
+
+    /// Returns true if there are no trades left
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    /// Reads the next trade of the file at `index`
+    fn advance(&mut self, index: usize) -> io::Result<()> {
+        if let Some(trade) = self.files[index].next().transpose()? {
+            self.heap.push(Reverse((trade.write_timestamp, index)));
+            self.next[index] = Some(trade);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergedTrades {
+    type Item = io::Result<RollingData>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((_, index)) = self.heap.pop()?;
+        let trade = self.next[index].take()?;
+        Some(self.advance(index).map(|_| trade))
+    }
+}
+
+/// Opens the rolling file, or the rolling files under the directory, which gets walked
+/// recursively, and returns their trades in the order they got written, read as they get
+/// iterated over. Both the csv and bincode files get read, compressed with gzip or not.
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use finnhub_ws::replay::read_trades;
+/// assert!(read_trades(Path::new("data/no-such-dir")).is_err());
+/// ```
+pub fn read_trades(input: &Path) -> io::Result<MergedTrades> {
+    let mut files = Vec::new();
+    open_trades_of(input, &mut files)?;
+    // the files of the stocks got written side by side, so their trades get interleaved
+    MergedTrades::new(files)
+}
+
+/// Opens the rolling file, or the rolling files under the directory, appending their trades to `files`
+fn open_trades_of(path: &Path, files: &mut Vec<RollingTrades>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut paths = fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+        for path in paths.iter().filter(|p| p.is_dir() || rolling_format(p).is_some()) {
+            open_trades_of(path, files)?;
+        }
+        return Ok(());
+    }
+    let format = rolling_format(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} isn't a rolling file", path.display())))?;
+    files.push(rolling_trades(path, format)?);
+    Ok(())
+}
+
+/// `Replayed` is what a replay went through
+#[derive(Debug)]
+pub struct Replayed {
+    /// the stocks the trades got replayed to
+    pub mapper: Arc<Mapper>,
+    /// the number of trades replayed
+    pub trades: u64,
+}
+
+/// `replay` feeds the trades, in the order they got written, to the stocks through `parse_message`,
+/// the trades written at the same time making up a message, and closes their windows on the
+/// candlestick and mean workers of the stocks. It returns the mapper of the stocks along with the
+/// number of trades replayed, once the partial windows following the last trade got written and
+/// the stores got synced. The trades get read as they get replayed, and the first error reading
+/// them stops the replay.
+///
+/// # Arguments
+/// trades: the trades to replay, sorted by their write timestamp, such as the ones of `read_trades`
+/// stocks: the stocks to replay the trades of. The trades of the other symbols get counted by the
+///      mapper as the ones which aren't tracked. Every symbol gets replayed if there are none
+/// options: the settings of the stock handles. Their data directory is where the data get written
+///      to, while their clock gets replaced by one following the write timestamps of the trades
+/// lateness: how long to wait after the end of a minute for its trades to arrive
+/// speed: how fast the trades get replayed compared to how they got written
+pub fn replay(trades: impl IntoIterator<Item = io::Result<RollingData>>, stocks: &[String], options: &MapperOptions, lateness: Duration, speed: ReplaySpeed) -> Result<Replayed> {
+    let mut trades = trades.into_iter().peekable();
+    let start = match trades.peek() {
+        Some(Ok(trade)) => trade.write_timestamp,
+        _ => Utc::now(),
+    };
+    let clock = Arc::new(ManualClock::new(start));
+    // the trades of each message get written at once, as nothing else flushes the stores
+    let options = MapperOptions { clock: clock.clone(), flush_interval: None, ..options.clone() };
+    let scheduler_dir = options.data_dir.join("scheduler");
+    if !create_dirs(&scheduler_dir.to_string_lossy()) {
+        return Err(io::Error::other(format!("couldn't create {}", scheduler_dir.display())).into());
+    }
+    let log = create_tick_log(&options.data_dir).ok_or_else(|| io::Error::other("couldn't create the tick log"))?;
+    let mapper = initialize_mapper(stocks, &options);
+    let mut threads: Vec<_> = mapper.handles().iter().flat_map(start_workers).collect();
+
+    let mut scheduler = MinuteScheduler::new(start, lateness);
+    let began = Instant::now();
+    let mut count = 0;
+    while let Some(first) = trades.next() {
+        let written = first.as_ref().map_or(start, |t| t.write_timestamp);
+        let mut message = vec![first?];
+        while let Some(trade) = trades.next_if(|t| matches!(t, Ok(t) if t.write_timestamp == written)) {
+            message.push(trade?);
+        }
+        count += message.len() as u64;
+        if let Some(wait) = speed.wait(written - start) {
+            // the wait gets calculated from the start every time, so that it doesn't drift
+            if let Some(left) = wait.checked_sub(began.elapsed()) {
+                thread::sleep(left);
+            }
+        }
+        clock.set(written);
+        let deadline = scheduler.deadline();
+        send_due_windows(&mapper, &mut scheduler, &log);
+        if scheduler.deadline() != deadline {
+            write_latency_rows(&mapper);
+        }
+        if stocks.is_empty() {
+            // the stocks get added as their first trade comes up
+            for trade in &message {
+                if mapper.get(&trade.symbol).is_none() {
+                    if let Some(handle) = mapper.add(&trade.symbol) {
+                        threads.extend(start_workers(&handle));
+                    }
+                }
+            }
+        }
+        let resp = Response {
+            transaction_type: "trade",
+            transaction_data: message.iter().map(|t| TickerInfo::new(&t.symbol, t.price, t.volume, &t.timestamp, &t.conditions)).collect(),
+            received: written,
+        };
+        parse_message(&resp, &mapper);
+    }
+
+    send_signal(&mapper, WindowSignal::Shutdown(clock.now().timestamp_millis()));
+    for thread in threads {
+        if thread.join().is_err() {
+            eprintln!("A worker of the replay panicked");
+        }
+    }
+    write_latency_rows(&mapper);
+    sync_stores(&mapper, options.fsync);
+    Ok(Replayed { mapper, trades: count })
+}
+
+/// Starts the candlestick and mean workers of a stock on threads of their own
+fn start_workers(handle: &Arc<StockHandle>) -> [thread::JoinHandle<()>; 2] {
+    let workers: [fn(&StockHandle) -> Result<()>; 2] = [wait_for_candlestick, wait_for_mean];
+    workers.map(|worker| {
+        let handle = Arc::clone(handle);
+        thread::spawn(move || run_worker(&handle, worker))
+    })
+}
+
+
+#[cfg(test)]
+mod replay_test {
+    use std::fs::{read_to_string, remove_dir_all};
+    use std::path::Path;
+    use chrono::{Duration, TimeZone, Utc};
+    use serial_test::serial;
+    use crate::replay::{read_trades, replay, ReplaySpeed};
+    use crate::stock_handle::MapperOptions;
+
+    #[test]
+    fn given_a_speed_it_should_parse_it() {
+        assert_eq!("1x".parse::<ReplaySpeed>().unwrap(), ReplaySpeed::Times(1));
+        assert_eq!("4".parse::<ReplaySpeed>().unwrap(), ReplaySpeed::Times(4));
+        assert!("0x".parse::<ReplaySpeed>().is_err());
+        assert!("fast".parse::<ReplaySpeed>().is_err());
+        assert_eq!(ReplaySpeed::Times(4).to_string(), "4x");
+    }
+
+    #[test]
+    #[serial]
+    fn given_the_files_of_several_stocks_it_should_merge_their_trades_in_the_order_they_got_written() {
+        let input = "tmp/replay_merge/2022-07-21";
+        std::fs::create_dir_all(input).unwrap();
+        let written = |s| Utc.ymd(2022, 7, 21).and_hms(22, 7, s).timestamp_millis();
+        for (symbol, seconds) in [("MOCK:A", [1, 4, 5]), ("MOCK:B", [2, 3, 5])] {
+            let mut data = String::from("Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions\n");
+            for s in seconds {
+                data.push_str(&format!("{},10.0,{},{},1.0,\n", symbol, written(s), written(s)));
+            }
+            std::fs::write(format!("{}/{}.csv", input, symbol.replace(':', "_")), data).unwrap();
+        }
+        let trades: Vec<(String, i64)> = read_trades(Path::new("tmp/replay_merge")).unwrap()
+            .map(|t| t.map(|t| (t.symbol, t.write_timestamp.timestamp())))
+            .collect::<std::io::Result<_>>().unwrap();
+        let at = |s| Utc.ymd(2022, 7, 21).and_hms(22, 7, s).timestamp();
+        assert_eq!(trades, vec![
+            ("MOCK:A".to_string(), at(1)), ("MOCK:B".to_string(), at(2)), ("MOCK:B".to_string(), at(3)),
+            ("MOCK:A".to_string(), at(4)), ("MOCK:A".to_string(), at(5)), ("MOCK:B".to_string(), at(5)),
+        ]);
+        // every symbol gets replayed when no stocks are given
+        let output = Path::new("tmp/replay_merge_output");
+        let options = MapperOptions { data_dir: output.to_path_buf(), ..MapperOptions::default() };
+        let replayed = replay(read_trades(Path::new("tmp/replay_merge")).unwrap(), &[], &options, Duration::seconds(5), ReplaySpeed::Max).unwrap();
+        assert_eq!(replayed.trades, 6);
+        assert_eq!(replayed.mapper.symbols(), vec!["MOCK:A".to_string(), "MOCK:B".to_string()]);
+        remove_dir_all(output).unwrap();
+        remove_dir_all("tmp/replay_merge").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_rolling_file_it_should_regenerate_the_candlesticks_and_means() {
+        let at = |m, s| Utc.ymd(2022, 7, 21).and_hms(22, m, s).timestamp_millis();
+        let input = "tmp/replay_input/2022-07-21";
+        let output = Path::new("tmp/replay_output");
+        let _ = remove_dir_all(output);
+        std::fs::create_dir_all(input).unwrap();
+        let rows = [
+            (10.0, at(7, 10), at(7, 11)),
+            (12.0, at(7, 20), at(7, 21)),
+            (11.0, at(7, 45), at(7, 50)),
+            (20.0, at(8, 15), at(8, 20)),
+        ];
+        let mut data = String::from("Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions\n");
+        for (price, timestamp, written) in rows {
+            data.push_str(&format!("MOCK:REPLAY,{:.1},{},{},1.0,\n", price, timestamp, written));
+        }
+        std::fs::write(format!("{}/MOCK_REPLAY.csv", input), data).unwrap();
+        let trades = read_trades(Path::new("tmp/replay_input")).unwrap();
+        let options = MapperOptions { mean_windows: vec!["1m".parse().unwrap()], data_dir: output.to_path_buf(), ..MapperOptions::default() };
+        let replayed = replay(trades, &["MOCK:REPLAY".to_string()], &options, Duration::seconds(5), ReplaySpeed::Max).unwrap();
+        assert_eq!(replayed.trades, 4);
+        let mapper = replayed.mapper;
+        assert_eq!(mapper.get("MOCK:REPLAY").unwrap().errors.total(), 0);
+        let candlesticks = read_to_string(output.join("candlestick/1m/2022-07-21/MOCK_REPLAY.csv")).unwrap();
+        assert_eq!(candlesticks.lines().skip(1).collect::<Vec<&str>>(), vec![
+            "MOCK:REPLAY,2022-07-21T22:07:00Z,10.0,11.0,12.0,10.0,3,false,3.0,11.0,33.0,false,0,false",
+            "MOCK:REPLAY,2022-07-21T22:08:00Z,20.0,20.0,20.0,20.0,1,true,1.0,20.0,20.0,false,0,false",
+        ]);
+        let means = read_to_string(output.join("mean/2022-07-21/MOCK_REPLAY.csv")).unwrap();
+        assert_eq!(means.lines().next().unwrap(), "MOCK:REPLAY,2022-07-21T22:07:11Z,2022-07-21T22:07:50Z,11.0,3,false,1m");
+        // the trades keep the time they got written at
+        let rolling = read_to_string(output.join("rolling/2022-07-21/MOCK_REPLAY.csv")).unwrap();
+        assert_eq!(rolling.lines().nth(1).unwrap(), "MOCK:REPLAY,10.0,1658441230000,1658441231000,1.0,");
+        let ticks = read_to_string(output.join("scheduler/ticks.csv")).unwrap();
+        assert_eq!(ticks.lines().count(), 2);
+        remove_dir_all(output).unwrap();
+        remove_dir_all("tmp/replay_input").unwrap();
+    }
+}
@@ -0,0 +1,391 @@
+//! File rotation primitives
+//! # rotation
+//!
+//! This contains the files the data of a stock get written to, which are partitioned
+//! by UTC date, such as `data/rolling/2022-07-21/AAPL.csv`, and rotated once they reach
+//! a size threshold, as well as the retention policy which compresses or deletes the old
+//! partitions so that the disk doesn't fill up.
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use flate2::{write::GzEncoder, Compression};
+
+/// The format of the names of the date partitions
+const PARTITION_FORMAT: &str = "%Y-%m-%d";
+
+/// `RotatingFile` is a file of a stock which gets partitioned by the UTC date of the data
+/// written to it. Each date gets its own directory under `dir`, where the file is named after
+/// the stock. Once the file reaches the size threshold, the data go on to a new file with an
+/// increasing number, such as `AAPL.1.csv`.
+///
+/// # Example
+/// ```
+/// use chrono::{TimeZone, Utc};
+/// use finnhub_ws::rotation::RotatingFile;
+/// let mut file = RotatingFile::new("tmp/rotating_file", "AAPL", "csv", None, None);
+/// file.current(Utc.ymd(2022, 7, 21).and_hms(22, 7, 38)).unwrap();
+/// assert_eq!(file.path().unwrap().to_str().unwrap(), "tmp/rotating_file/2022-07-21/AAPL.csv");
+/// std::fs::remove_dir_all("tmp/rotating_file").unwrap();
+/// ```
+#[derive(Debug)]
+pub struct RotatingFile {
+    /// dir: the directory the date partitions get created in
+    dir: PathBuf,
+    /// name: the name of the file in each partition, without its extension
+    name: String,
+    /// extension: the extension of the file
+    extension: &'static str,
+    /// max_size: the size in bytes after which the file gets rotated, if any
+    max_size: Option<u64>,
+    /// header: writes the headers to each new file, if it should have any
+    header: Option<fn(&mut dyn Write) -> io::Result<()>>,
+    /// date: the date of the partition currently open
+    date: Option<NaiveDate>,
+    /// index: the number of the file currently open within the partition
+    index: u32,
+    /// file: the file currently open
+    file: Option<File>,
+    /// empty_size: the size of the file currently open when it holds no data, so that
+    /// the headers alone never make it full
+    empty_size: u64,
+}
+
+impl RotatingFile {
+    /// Given the directory to create the partitions in, the name and extension of the file, the size
+    /// after which it gets rotated and the function writing the headers to each new file if any,
+    /// creates and returns a RotatingFile. No file gets opened until `current` gets called.
+    /// An existing file starting with other headers, such as one written before a column got
+    /// added, never gets appended to: the data go on to the next file instead.
+    pub fn new(dir: impl Into<PathBuf>, name: &str, extension: &'static str, max_size: Option<u64>, header: Option<fn(&mut dyn Write) -> io::Result<()>>) -> Self {
+        RotatingFile {
+            dir: dir.into(),
+            name: name.to_string(),
+            extension,
+            max_size,
+            header,
+            date: None,
+            index: 0,
+            file: None,
+            empty_size: 0,
+        }
+    }
+
+    /// Returns the file the data of the given time should be written to. It switches to the
+    /// partition of the date of `time` if needed, and to a new file if the current one
+    /// reached the size threshold.
+    pub fn current(&mut self, time: DateTime<Utc>) -> io::Result<&File> {
+        let date = time.naive_utc().date();
+        if self.date != Some(date) || self.file.is_none() {
+            self.open(date)?;
+        } else if self.is_full()? {
+            self.index += 1;
+            self.open_index()?;
+        }
+        Ok(self.file.as_ref().unwrap())
+    }
+
+    /// Syncs the data written to the file currently open to the disk, if any file is open
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the path of the file currently open, if any
+    pub fn path(&self) -> Option<PathBuf> {
+        self.date.map(|date| self.path_of(date, self.index))
+    }
+
+    /// Returns the files written to in the partitions of the given date onwards, in the order
+    /// they were written in, including the ones the retention policy compressed
+    pub fn files_since(&self, date: NaiveDate) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for partition in self.partitions()?.into_iter().filter(|partition| *partition >= date) {
+            files.extend(self.partition_files(partition));
+        }
+        Ok(files)
+    }
+
+    /// Returns the files of the most recent partition holding any, in the order they were
+    /// written in, which are the ones the program was writing to if it got killed
+    pub fn latest_files(&self) -> io::Result<Vec<PathBuf>> {
+        let latest = self.partitions()?.into_iter().rev()
+            .map(|partition| self.partition_files(partition))
+            .find(|files| !files.is_empty());
+        Ok(latest.unwrap_or_default())
+    }
+
+    /// Returns the dates of the partitions under the directory, sorted
+    fn partitions(&self) -> io::Result<Vec<NaiveDate>> {
+        let mut dates = Vec::new();
+        if self.dir.is_dir() {
+            for entry in fs::read_dir(&self.dir)? {
+                let name = entry?.file_name();
+                if let Some(date) = name.to_str().and_then(|name| NaiveDate::parse_from_str(name, PARTITION_FORMAT).ok()) {
+                    dates.push(date);
+                }
+            }
+        }
+        dates.sort();
+        Ok(dates)
+    }
+
+    /// Returns the files of the partition of the given date, in the order they were written in,
+    /// with the compressed path of the ones the retention policy compressed
+    fn partition_files(&self, date: NaiveDate) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for index in 0.. {
+            let path = self.path_of(date, index);
+            let mut compressed = path.clone().into_os_string();
+            compressed.push(".gz");
+            let compressed = PathBuf::from(compressed);
+            if path.exists() {
+                files.push(path);
+            } else if compressed.exists() {
+                files.push(compressed);
+            } else {
+                break;
+            }
+        }
+        files
+    }
+
+    /// Opens the last file of the partition of the given date, or the next one if it is full
+    fn open(&mut self, date: NaiveDate) -> io::Result<()> {
+        fs::create_dir_all(self.dir.join(date.format(PARTITION_FORMAT).to_string()))?;
+        let mut index = 0;
+        while self.path_of(date, index + 1).exists() {
+            index += 1;
+        }
+        self.date = Some(date);
+        self.index = index;
+        self.open_index()?;
+        if self.is_full()? {
+            self.index += 1;
+            self.open_index()?;
+        }
+        Ok(())
+    }
+
+    /// Opens the file of the current date and index, writing the headers if it is new, or
+    /// going on to the next index if it starts with other headers.
+    /// The file open before gets synced, which happens at most a few times a day, so that
+    /// a periodic sync never misses the data written to it last.
+    fn open_index(&mut self) -> io::Result<()> {
+        self.sync()?;
+        let mut headers = Vec::new();
+        if let Some(header) = self.header {
+            header(&mut headers)?;
+        }
+        loop {
+            let path = self.path().unwrap();
+            let mut file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .read(true)
+                .open(&path)?;
+            self.empty_size = 0;
+            if self.header.is_some() {
+                if file.metadata()?.len() == 0 {
+                    file.write_all(&headers)?;
+                    self.empty_size = headers.len() as u64;
+                } else if !starts_with(&file, &headers)? {
+                    eprintln!("{} has other columns, writing to the next file", path.display());
+                    self.index += 1;
+                    continue;
+                }
+            }
+            self.file = Some(file);
+            return Ok(());
+        }
+    }
+
+    fn is_full(&self) -> io::Result<bool> {
+        match (self.max_size, &self.file) {
+            (Some(max_size), Some(file)) => {
+                let size = file.metadata()?.len();
+                Ok(size > self.empty_size && size >= max_size)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn path_of(&self, date: NaiveDate, index: u32) -> PathBuf {
+        let name = match index {
+            0 => format!("{}.{}", self.name, self.extension),
+            _ => format!("{}.{}.{}", self.name, index, self.extension),
+        };
+        self.dir.join(date.format(PARTITION_FORMAT).to_string()).join(name)
+    }
+}
+
+/// Returns true if the file starts with the given bytes
+fn starts_with(file: &File, start: &[u8]) -> io::Result<bool> {
+    let mut read = Vec::with_capacity(start.len());
+    file.take(start.len() as u64).read_to_end(&mut read)?;
+    Ok(read == start)
+}
+
+/// `RetentionPolicy` decides what happens to the date partitions as they get older.
+/// The partition of the current date is never touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetentionPolicy {
+    /// The number of days after which the files of a partition get compressed with gzip, if any
+    pub compress_after_days: Option<u32>,
+    /// The number of days after which a partition gets deleted, if any
+    pub retain_days: Option<u32>,
+}
+
+impl RetentionPolicy {
+    /// Applies the policy to every date partition found under `root`, at any depth, such as
+    /// `data/rolling/2022-07-21` or `data/candlestick/1m/2022-07-21`. The errors get logged,
+    /// so that a single file can't keep the rest of the partitions from being handled.
+    pub fn enforce(&self, root: &Path, now: DateTime<Utc>) {
+        if self.compress_after_days.is_none() && self.retain_days.is_none() {
+            return;
+        }
+        let today = now.naive_utc().date();
+        let mut partitions = Vec::new();
+        if let Err(e) = find_partitions(root, &mut partitions) {
+            eprintln!("Couldn't look for partitions under {}: {}", root.display(), e);
+        }
+        for (path, date) in partitions {
+            let age = today - date;
+            if matches!(self.retain_days, Some(days) if age > Duration::days(days as i64)) {
+                if let Err(e) = fs::remove_dir_all(&path) {
+                    eprintln!("Couldn't delete {}: {}", path.display(), e);
+                }
+            } else if matches!(self.compress_after_days, Some(days) if age > Duration::days(days as i64)) {
+                if let Err(e) = compress_partition(&path) {
+                    eprintln!("Couldn't compress {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Adds to `partitions` the directories under `dir` named after a date, along with their date
+fn find_partitions(dir: &Path, partitions: &mut Vec<(PathBuf, NaiveDate)>) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let date = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| NaiveDate::parse_from_str(name, PARTITION_FORMAT).ok());
+        match date {
+            Some(date) => partitions.push((path, date)),
+            None => find_partitions(&path, partitions)?,
+        }
+    }
+    Ok(())
+}
+
+/// Compresses each file of the partition which isn't compressed yet to a `.gz` file
+/// next to it, and deletes the original once the compressed one is complete
+fn compress_partition(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() || path.extension() == Some(OsStr::new("gz")) {
+            continue;
+        }
+        let mut target = path.clone().into_os_string();
+        target.push(".gz");
+        let mut reader = BufReader::new(File::open(&path)?);
+        let mut encoder = GzEncoder::new(BufWriter::new(File::create(&target)?), Compression::default());
+        io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod rotation_test {
+    use std::fs::{self, read_to_string, remove_dir_all, File};
+    use std::io::{self, Read, Write};
+    use std::path::Path;
+    use chrono::{TimeZone, Utc};
+    use flate2::read::GzDecoder;
+    use serial_test::serial;
+    use crate::rotation::{RetentionPolicy, RotatingFile};
+
+    fn header(writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(b"Header\n")
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_switch_partitions_at_utc_midnight() {
+        let mut file = RotatingFile::new("tmp/rotation_midnight", "AAPL", "csv", None, Some(header));
+        writeln!(file.current(Utc.ymd(2022, 7, 21).and_hms(23, 59, 59)).unwrap(), "a").unwrap();
+        writeln!(file.current(Utc.ymd(2022, 7, 22).and_hms(0, 0, 0)).unwrap(), "b").unwrap();
+        // late data of the previous day go to its own partition
+        writeln!(file.current(Utc.ymd(2022, 7, 21).and_hms(23, 59, 59)).unwrap(), "c").unwrap();
+        assert_eq!(read_to_string("tmp/rotation_midnight/2022-07-21/AAPL.csv").unwrap(), "Header\na\nc\n");
+        assert_eq!(read_to_string("tmp/rotation_midnight/2022-07-22/AAPL.csv").unwrap(), "Header\nb\n");
+        remove_dir_all("tmp/rotation_midnight").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_rotate_once_the_size_threshold_is_reached() {
+        let time = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+        let mut file = RotatingFile::new("tmp/rotation_size", "AAPL", "csv", Some(10), Some(header));
+        for line in ["first", "second", "third"] {
+            writeln!(file.current(time).unwrap(), "{}", line).unwrap();
+        }
+        assert_eq!(read_to_string("tmp/rotation_size/2022-07-21/AAPL.csv").unwrap(), "Header\nfirst\n");
+        assert_eq!(read_to_string("tmp/rotation_size/2022-07-21/AAPL.1.csv").unwrap(), "Header\nsecond\n");
+        assert_eq!(read_to_string("tmp/rotation_size/2022-07-21/AAPL.2.csv").unwrap(), "Header\nthird\n");
+        // a restarted program picks up after the last file, as it is full
+        let mut file = RotatingFile::new("tmp/rotation_size", "AAPL", "csv", Some(10), Some(header));
+        file.current(time).unwrap();
+        assert!(file.path().unwrap().ends_with("2022-07-21/AAPL.3.csv"));
+        remove_dir_all("tmp/rotation_size").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn given_a_file_with_other_headers_it_should_go_on_to_the_next_file() {
+        let time = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+        fs::create_dir_all("tmp/rotation_headers/2022-07-21").unwrap();
+        fs::write("tmp/rotation_headers/2022-07-21/AAPL.csv", "Old\nrow\n").unwrap();
+        let mut file = RotatingFile::new("tmp/rotation_headers", "AAPL", "csv", None, Some(header));
+        writeln!(file.current(time).unwrap(), "a").unwrap();
+        assert!(file.path().unwrap().ends_with("2022-07-21/AAPL.1.csv"));
+        assert_eq!(read_to_string("tmp/rotation_headers/2022-07-21/AAPL.csv").unwrap(), "Old\nrow\n");
+        assert_eq!(read_to_string("tmp/rotation_headers/2022-07-21/AAPL.1.csv").unwrap(), "Header\na\n");
+        // the file with the same headers gets appended to after a restart
+        let mut file = RotatingFile::new("tmp/rotation_headers", "AAPL", "csv", None, Some(header));
+        writeln!(file.current(time).unwrap(), "b").unwrap();
+        assert_eq!(read_to_string("tmp/rotation_headers/2022-07-21/AAPL.1.csv").unwrap(), "Header\na\nb\n");
+        remove_dir_all("tmp/rotation_headers").unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn it_should_compress_and_delete_old_partitions() {
+        for dir in ["tmp/retention/rolling/2022-07-01", "tmp/retention/candlestick/1m/2022-07-15", "tmp/retention/rolling/2022-07-21"] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(Path::new(dir).join("AAPL.csv"), "data").unwrap();
+        }
+        let policy = RetentionPolicy { compress_after_days: Some(2), retain_days: Some(10) };
+        policy.enforce(Path::new("tmp/retention"), Utc.ymd(2022, 7, 21).and_hms(12, 0, 0));
+        assert!(!Path::new("tmp/retention/rolling/2022-07-01").exists());
+        assert!(!Path::new("tmp/retention/candlestick/1m/2022-07-15/AAPL.csv").exists());
+        let mut data = String::new();
+        GzDecoder::new(File::open("tmp/retention/candlestick/1m/2022-07-15/AAPL.csv.gz").unwrap()).read_to_string(&mut data).unwrap();
+        assert_eq!(data, "data");
+        assert!(Path::new("tmp/retention/rolling/2022-07-21/AAPL.csv").exists());
+        remove_dir_all("tmp/retention").unwrap();
+    }
+}
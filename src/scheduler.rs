@@ -0,0 +1,197 @@
+//! Window scheduling primitives
+//! # scheduler
+//!
+//! This contains the scheduler deciding when the windows of the candlestick and mean data
+//! get closed. The window of each UTC minute is closed once `lateness` passed since the minute
+//! ended. Each deadline gets calculated from the wall clock rather than by adding a period to
+//! the previous one, so the ticks don't drift off the minute boundaries. A runtime which stalled
+//! past several deadlines gets each missed window emitted in order, so that no minute gets
+//! skipped or repeated, up to an hour of them. The jitter of every tick, the time between its
+//! deadline and when it actually fired, gets written to a dedicated file so that the real-time
+//! behaviour can be analysed later on.
+//!
+//! # Example
+//! ```
+//! use chrono::{Duration, TimeZone, Utc};
+//! use finnhub_ws::scheduler::MinuteScheduler;
+//! let mut scheduler = MinuteScheduler::new(Utc.ymd(2022, 7, 21).and_hms(22, 7, 30), Duration::seconds(5));
+//! assert_eq!(scheduler.deadline(), Utc.ymd(2022, 7, 21).and_hms(22, 8, 5));
+//! // the runtime stalled past the deadlines of two minutes
+//! let ticks = scheduler.due(Utc.ymd(2022, 7, 21).and_hms(22, 9, 6));
+//! let windows: Vec<_> = ticks.iter().map(|t| t.window).collect();
+//! assert_eq!(windows, vec![Utc.ymd(2022, 7, 21).and_hms(22, 8, 0), Utc.ymd(2022, 7, 21).and_hms(22, 9, 0)]);
+//! assert!(ticks[0].caught_up && !ticks[1].caught_up);
+//! ```
+use std::fs::File;
+use std::path::Path;
+use chrono::{DateTime, Duration, DurationRound, Utc, serde::ts_milliseconds};
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::utils::{append_csv, open_log};
+
+/// The number of missed windows which get emitted at most at once, such as after the clock
+/// jumped forward by days. The ones before them get skipped, their trades being closed
+/// along with the first window emitted.
+const MAX_CAUGHT_UP: i64 = 60;
+
+/// `MinuteScheduler` keeps track of the next minute boundary whose window hasn't been
+/// closed yet, so that every minute gets closed exactly once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinuteScheduler {
+    /// lateness: how long after the end of a minute its window gets closed
+    lateness: Duration,
+    /// next: the next minute boundary whose window hasn't been closed yet
+    next: DateTime<Utc>,
+}
+
+impl MinuteScheduler {
+    /// Given the current time and the lateness, creates and returns a scheduler whose first
+    /// window is the first one whose deadline hasn't passed yet
+    pub fn new(now: DateTime<Utc>, lateness: Duration) -> Self {
+        let passed = now - lateness;
+        MinuteScheduler {
+            lateness,
+            next: passed.duration_trunc(Duration::minutes(1)).unwrap_or(passed) + Duration::minutes(1),
+        }
+    }
+
+    /// Returns the time the next window should be closed at
+    pub fn deadline(&self) -> DateTime<Utc> {
+        self.next + self.lateness
+    }
+
+    /// Returns the ticks whose deadline passed by `now`, oldest first, and moves on to the next
+    /// window. There are none if `now` is before the deadline, such as after a wake up which came
+    /// early or the clock having been set back. After a gap longer than `MAX_CAUGHT_UP` minutes,
+    /// only the windows of its last minutes get returned and the gap gets logged once.
+    pub fn due(&mut self, now: DateTime<Utc>) -> Vec<Tick> {
+        if self.deadline() <= now {
+            let due = (now - self.deadline()).num_minutes() + 1;
+            if due > MAX_CAUGHT_UP {
+                let skipped = due - MAX_CAUGHT_UP;
+                eprintln!("Skipped the windows of {} minutes from {} after a gap", skipped, self.next);
+                self.next += Duration::minutes(skipped);
+            }
+        }
+        let mut ticks = Vec::new();
+        while self.deadline() <= now {
+            ticks.push(Tick {
+                window: self.next,
+                scheduled: self.deadline(),
+                fired: now,
+                caught_up: false,
+                jitter_millis: (now - self.deadline()).num_milliseconds(),
+            });
+            self.next += Duration::minutes(1);
+        }
+        // every tick but the last one was missed and got emitted late
+        let missed = ticks.len().saturating_sub(1);
+        ticks.iter_mut().take(missed).for_each(|t| t.caught_up = true);
+        ticks
+    }
+}
+
+/// `Tick` represents the closing of the window of a single minute and holds the
+/// information needed to analyse its timing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tick {
+    /// the minute boundary the window closed at, which the workers get sent
+    #[serde(with = "ts_milliseconds")]
+    pub window: DateTime<Utc>,
+    /// the time the window should have been closed at
+    #[serde(with = "ts_milliseconds")]
+    pub scheduled: DateTime<Utc>,
+    /// the time the window actually got closed at
+    #[serde(with = "ts_milliseconds")]
+    pub fired: DateTime<Utc>,
+    /// whether the tick was missed, so that the window got emitted late along with the next ones
+    pub caught_up: bool,
+    /// the time in milliseconds between the deadline and the tick firing
+    pub jitter_millis: i64,
+}
+
+impl Tick {
+    /// Method used to append the tick to the tick log.
+    /// The headers are written only when the file is empty.
+    pub fn write_to_file(&self, file: &File) -> Result<()> {
+        append_csv(file, [self])
+    }
+}
+
+/// Returns a file descriptor for the file where the ticks get written to.
+/// The file will be located under {data_dir}/scheduler directory and be named ticks.csv
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use finnhub_ws::scheduler::create_tick_log;
+/// use finnhub_ws::utils::create_dirs;
+/// let _ = create_dirs("data/scheduler");
+/// let f = create_tick_log(Path::new("data")).unwrap();
+/// ```
+pub fn create_tick_log(data_dir: &Path) -> Option<File> {
+    open_log(&data_dir.join("scheduler/ticks.csv"))
+}
+
+
+#[cfg(test)]
+mod scheduler_test {
+    use std::fs::{read_to_string, remove_file, File};
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::scheduler::MinuteScheduler;
+    use crate::utils::create_dirs;
+
+    #[test]
+    fn given_a_wake_up_before_the_deadline_it_should_not_emit_a_window() {
+        let start = Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 59, 999);
+        let mut scheduler = MinuteScheduler::new(start, Duration::zero());
+        assert!(scheduler.due(start).is_empty());
+        let ticks = scheduler.due(Utc.ymd(2022, 7, 21).and_hms_milli(22, 8, 0, 12));
+        assert_eq!(ticks.len(), 1);
+        assert_eq!(ticks[0].window, Utc.ymd(2022, 7, 21).and_hms(22, 8, 0));
+        assert_eq!(ticks[0].jitter_millis, 12);
+        // the window of the minute doesn't get emitted again
+        assert!(scheduler.due(Utc.ymd(2022, 7, 21).and_hms(22, 8, 30)).is_empty());
+        assert_eq!(scheduler.deadline(), Utc.ymd(2022, 7, 21).and_hms(22, 9, 0));
+    }
+
+    #[test]
+    fn given_a_clock_set_back_it_should_not_repeat_a_window() {
+        let mut scheduler = MinuteScheduler::new(Utc.ymd(2022, 7, 21).and_hms(22, 7, 30), Duration::seconds(5));
+        assert_eq!(scheduler.due(Utc.ymd(2022, 7, 21).and_hms(22, 8, 5)).len(), 1);
+        assert!(scheduler.due(Utc.ymd(2022, 7, 21).and_hms(22, 6, 0)).is_empty());
+        let ticks = scheduler.due(Utc.ymd(2022, 7, 21).and_hms(22, 9, 5));
+        assert_eq!(ticks.iter().map(|t| t.window).collect::<Vec<_>>(), vec![Utc.ymd(2022, 7, 21).and_hms(22, 9, 0)]);
+    }
+
+    #[test]
+    fn given_a_clock_jump_of_days_it_should_cap_the_windows_caught_up() {
+        let mut scheduler = MinuteScheduler::new(Utc.ymd(2022, 7, 21).and_hms(22, 7, 30), Duration::seconds(5));
+        let ticks = scheduler.due(Utc.ymd(2022, 7, 24).and_hms(22, 9, 5));
+        assert_eq!(ticks.len(), 60);
+        assert_eq!(ticks[0].window, Utc.ymd(2022, 7, 24).and_hms(21, 10, 0));
+        assert_eq!(ticks[59].window, Utc.ymd(2022, 7, 24).and_hms(22, 9, 0));
+        assert!(ticks[0].caught_up && !ticks[59].caught_up);
+        assert_eq!(scheduler.deadline(), Utc.ymd(2022, 7, 24).and_hms(22, 10, 5));
+    }
+
+    #[test]
+    fn it_should_write_the_ticks_with_their_jitter() {
+        let _ = create_dirs("tmp");
+        let path = "tmp/scheduler_ticks.csv";
+        let file = File::create(path).unwrap();
+        let mut scheduler = MinuteScheduler::new(Utc.ymd(2022, 7, 21).and_hms(22, 7, 30), Duration::seconds(5));
+        for tick in scheduler.due(Utc.ymd(2022, 7, 21).and_hms_milli(22, 9, 5, 250)) {
+            tick.write_to_file(&file).unwrap();
+        }
+        let data = read_to_string(path).unwrap();
+        let lines: Vec<&str> = data.lines().collect();
+        assert_eq!(lines, vec![
+            "Window,Scheduled,Fired,CaughtUp,JitterMillis",
+            "1658441280000,1658441285000,1658441345250,true,60250",
+            "1658441340000,1658441345000,1658441345250,false,250",
+        ]);
+        remove_file(path).unwrap();
+    }
+}
@@ -7,318 +7,806 @@
 //!
 //! # Example
 //! ```
-//! use finnhub_ws::stock_handle::initialize_mapper;
-//! let stock_handle = initialize_mapper(&["AAPL".to_string()]);
-//! assert_eq!(stock_handle.len(), 1);
-//! assert_eq!(stock_handle[0].stock_symbol, "AAPL".to_string());
+//! use finnhub_ws::stock_handle::{initialize_mapper, MapperOptions};
+//! let mapper = initialize_mapper(&["AAPL".to_string()], &MapperOptions::default());
+//! assert_eq!(mapper.len(), 1);
+//! assert_eq!(mapper.handles()[0].stock_symbol, "AAPL".to_string());
 //! ```
-use std::fs::{File, OpenOptions};
-use std::io;
-use std::sync::{Arc, Mutex, Once};
-use chrono::{Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{DateTime, Utc};
 use crossbeam_channel::{Receiver, Sender, unbounded};
-use crate::candlestick::Candlestick;
-use crate::TickerInfo;
-use crate::utils::sanitize_string;
+use crate::candlestick::{CandlestickAggregator, LatePolicy};
+use crate::clock::{Clock, SystemClock};
+use crate::condition::ConditionFilter;
+use crate::error::ErrorCounts;
+use crate::interval::Interval;
+use crate::journal::StorageFormat;
+use crate::latency::LatencyTracker;
+use crate::mean::{default_window, RollingMeanAggregator};
+use crate::news::NewsLog;
+use crate::recovery::{repair_file, Layout, Repair};
+use crate::rotation::RotatingFile;
+use crate::store::{open_store, FsyncPolicy, Store};
+use crate::{CSVAble, TickerInfo};
+use crate::utils::{log_open_error, sanitize_string};
+
+/// The directory the data files get written to by default
+pub const DATA_DIR: &str = "data";
+/// The directory of the data directory the rolling files get written to
+const ROLLING_DIR: &str = "rolling";
+/// The directory of the data directory holding a directory of candlestick files for each interval
+const CANDLESTICK_DIR: &str = "candlestick";
+/// The directory of the data directory the mean files get written to
+const MEAN_DIR: &str = "mean";
+
+/// `WindowSignal` is the message sent each minute to the threads calculating
+/// the candlestick and mean data of a stock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowSignal {
+    /// A window closed. It holds the timestamp in seconds of the
+    /// minute the window closed at.
+    Window(i64),
+    /// The program is shutting down. It holds the millisecond timestamp
+    /// of the shutdown, up to which the partial windows should be flushed.
+    Shutdown(i64),
+}
+
+impl WindowSignal {
+    /// Returns true if the program is shutting down
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self, WindowSignal::Shutdown(_))
+    }
+}
+
+/// `MapperOptions` holds the settings which apply to every `StockHandle`
+/// created by `initialize_mapper`.
+#[derive(Debug, Clone)]
+pub struct MapperOptions {
+    /// The intervals for which candlesticks get calculated
+    pub candle_intervals: Vec<Interval>,
+    /// The lengths of the sliding windows the mean price gets calculated over
+    pub mean_windows: Vec<Interval>,
+    /// The filter deciding which trades count toward the candlestick and mean data
+    pub condition_filter: ConditionFilter,
+    /// What happens to the trades which arrive after their candlestick was written
+    pub late_policy: LatePolicy,
+    /// The format the rolling data get written in
+    pub storage_format: StorageFormat,
+    /// The size in bytes after which the files get rotated, besides at UTC midnight
+    pub max_file_size: Option<u64>,
+    /// How often the buffered trades get written. They get written once per message if none
+    pub flush_interval: Option<std::time::Duration>,
+    /// How often the data written get synced to the disk
+    pub fsync: FsyncPolicy,
+    /// Whether the news of the stocks get tracked besides their trades
+    pub news: bool,
+    /// The directory the data files of the stocks get written to, such as data
+    pub data_dir: PathBuf,
+    /// The clock the current time gets read from, such as the write timestamps of the
+    /// trades and the deadlines of the windows
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for MapperOptions {
+    fn default() -> Self {
+        MapperOptions {
+            candle_intervals: vec![Interval::minutes(1).unwrap()],
+            mean_windows: vec![default_window()],
+            condition_filter: ConditionFilter::default(),
+            late_policy: LatePolicy::default(),
+            storage_format: StorageFormat::default(),
+            max_file_size: None,
+            flush_interval: None,
+            fsync: FsyncPolicy::default(),
+            news: false,
+            data_dir: PathBuf::from(DATA_DIR),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl MapperOptions {
+    /// Returns whether the rows of the mean files end with the window they cover. They don't
+    /// when only the default window is configured, so that the mean files keep the columns
+    /// they had before the windows could be configured.
+    pub fn mean_window_column(&self) -> bool {
+        self.mean_windows != [default_window()]
+    }
+}
+
+/// `CandlestickSeries` holds what is needed to calculate the candlesticks
+/// of a stock for one interval.
+#[derive(Debug)]
+pub struct CandlestickSeries {
+    /// The interval each candlestick of the series covers
+    pub interval: Interval,
+    /// The aggregator of the candlesticks of the intervals which weren't written yet.
+    /// It gets updated on every trade, so the rolling file is only ever
+    /// written to while the program runs.
+    pub aggregator: Mutex<CandlestickAggregator>,
+}
 
 /// `StockHandle` holds all the necessary data to manage a stock symbol
-/// such as any open file descriptors for the rolling, mean and candlestick
-/// information, and the channels for the threads to be able to send timestamps
+/// such as the store the rolling, mean and candlestick information get
+/// written to, and the channels for the threads to be able to send timestamps
 /// to calculate mean and candlestick data.
 #[derive(Debug)]
 pub struct StockHandle {
     /// The symbol the stock has in the trade market.
     /// It can also be an exchange like EUR/USD
     pub stock_symbol: String,
-    /// The file descriptor where the trade information
-    /// gets written to as they arrive. This has a mutex so that it can
-    /// be easily passed between threads and avoid data races
-    /// or parsing errors due to file being read while being written
-    pub rolling_file: Mutex<File>,
-    /// The file descriptor where the candlestick information
-    /// gets written to each minute. This has a mutex so that it can
-    /// be easily passed between threads and avoid data races
-    /// or parsing errors due to file being read while being written
-    pub candlestick_file: Mutex<File>,
-    /// The file descriptor where the mean price information
-    /// gets written to each minute. This has a mutex so that it can
-    /// be easily passed between threads and avoid data races
-    /// or parsing errors due to file being read while being written
-    pub mean_file: Mutex<File>,
-    /// The once flag is a synchronization primitive to ensure that
-    /// the headers get written to the file just once and that block
-    /// of code gets run only once during initialization.
-    pub once_flag: Once,
-    /// `stock_channel` holds a tuple of Sender and receiver of i64
-    /// timestamps. This is the primary way of communicating between
+    /// The store where the trades get written to as they arrive, and the
+    /// candlestick and mean information at the end of each minute. It handles
+    /// its own locking, so that it can be easily shared between threads.
+    pub store: Box<dyn Store>,
+    /// The candlestick series of the stock, one for each interval
+    pub candlesticks: Vec<CandlestickSeries>,
+    /// The aggregator of the mean price over each of the mean windows. It holds
+    /// the trades of the longest window in memory and gets updated on every trade.
+    pub mean: Mutex<RollingMeanAggregator>,
+    /// The filter deciding which trades get fed to the aggregators. Every trade
+    /// gets written to the store regardless of its conditions.
+    pub condition_filter: ConditionFilter,
+    /// The file the headlines of the stock get written to, if its news get tracked
+    pub news: Option<NewsLog>,
+    /// The errors the pipeline ran into while handling the stock, such as the writes
+    /// to its store which failed or its workers failing
+    pub errors: ErrorCounts,
+    /// The latencies of the trades of the stock written since the last period
+    pub latency: LatencyTracker,
+    /// Whether a worker of the stock stopped or one of its aggregators got poisoned,
+    /// after which its trades only get written to its store
+    pub failed: AtomicBool,
+    /// `stock_channel` holds a tuple of Sender and receiver of
+    /// window signals. This is the primary way of communicating between
     /// the producing thread and the consumer ones. Each minute a
     /// millis timestamp gets written to the sender and received by
     /// the receiver. Upon receiving the data, the candlestick should
     /// be calculated
-    pub stock_channel: (Sender<i64>, Receiver<i64>),
-    /// `rolling_mean_channel` holds a tuple of Sender and receiver of i64
-    /// timestamps. This is the primary way of communicating between
+    pub stock_channel: (Sender<WindowSignal>, Receiver<WindowSignal>),
+    /// `rolling_mean_channel` holds a tuple of Sender and receiver of
+    /// window signals. This is the primary way of communicating between
     /// the producing thread and the consumer ones. Evey 15 minute a
     /// millis timestamp gets written to the sender and received by
     /// the receiver. Upon receiving the data, the mean price should
     /// be calculated
-    pub rolling_mean_channel: (Sender<i64>, Receiver<i64>)
+    pub rolling_mean_channel: (Sender<WindowSignal>, Receiver<WindowSignal>)
+}
+
+impl StockHandle {
+    /// Marks the stock as failed, so that its trades stop being aggregated and its workers
+    /// only get sent the shutdown signal. It returns true the first time the stock fails.
+    pub fn fail(&self) -> bool {
+        !self.failed.swap(true, Ordering::Relaxed)
+    }
+
+    /// Returns true if the stock failed
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+/// Opens the file of the date of `now`, so that a file which can't be created
+/// gets reported on initialization rather than on the first write
+fn open_rotating_file(mut file: RotatingFile, now: DateTime<Utc>) -> Option<RotatingFile> {
+    match file.current(now) {
+        Ok(_) => Some(file),
+        Err(err) => {
+            log_open_error(&err);
+            None
+        }
+    }
 }
 
 /// Given a string slice containing the stock symbol in the trade market,
-/// it returns a file descriptor if it was successful in opening or creating it.
-/// The file will be located under data/rolling directory and be named as
-/// {sanitized_stock_symbol}.csv
+/// it returns a rotating file if it was successful in opening or creating it.
+/// The file will be located under the {data_dir}/rolling/{date} directory and be named as
+/// {sanitized_stock_symbol}.csv. New files get the csv headers written to them, and a file
+/// written with other columns gets left for the next one.
 ///
 /// # Arguments
+/// `data_dir` - The directory the data files get written to, such as data
 /// `stock` - A string slice containing the stock symbol
+/// `max_size` - The size in bytes after which the file gets rotated, if any
+/// `now` - The current time, whose date decides the file which gets opened
 ///
 /// # Example
 /// ```
+/// use std::path::Path;
+/// use chrono::Utc;
 /// use finnhub_ws::stock_handle::create_rolling_file;
-/// let f = create_rolling_file("TSLA").unwrap();
+/// let f = create_rolling_file(Path::new("data"), "TSLA", None, Utc::now()).unwrap();
 /// ```
-pub fn create_rolling_file(stock: &str) -> Option<File> {
+pub fn create_rolling_file(data_dir: &Path, stock: &str, max_size: Option<u64>, now: DateTime<Utc>) -> Option<RotatingFile> {
     let safe_stock = sanitize_string(stock);
-    match OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .read(true)
-        .open(format!("data/rolling/{}.csv", safe_stock)) {
-        Ok(f) => Some(f),
-        Err(err) => match err.kind() {
-            io::ErrorKind::PermissionDenied => {
-                eprintln!("Cannot create a file due to permission reasons");
-                None
-            }
-            _ => {
-                eprintln!("Couldn't create file");
-                None
-            }
-        }
-    }
+    let header: fn(&mut dyn Write) -> io::Result<()> = |writer| {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(TickerInfo::default().get_headers())?;
+        writer.flush()
+    };
+    open_rotating_file(RotatingFile::new(data_dir.join(ROLLING_DIR), &safe_stock, StorageFormat::Csv.extension(), max_size, Some(header)), now)
 }
 
 /// Given a string slice containing the stock symbol in the trade market,
-/// it returns a file descriptor if it was successful in opening or creating it.
-/// The file will be located under data/candlestick directory and be named as
-/// {sanitized_stock_symbol}.csv
+/// it returns a rotating file if it was successful in opening or creating it.
+/// The file will be located under the {data_dir}/rolling/{date} directory and be named as
+/// {sanitized_stock_symbol}.bin. It holds the trades as a bincode journal.
+///
+/// # Arguments
+/// `data_dir` - The directory the data files get written to, such as data
+/// `stock` - A string slice containing the stock symbol
+/// `max_size` - The size in bytes after which the file gets rotated, if any
+/// `now` - The current time, whose date decides the file which gets opened
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use chrono::Utc;
+/// use finnhub_ws::stock_handle::create_journal_file;
+/// let f = create_journal_file(Path::new("data"), "TSLA", None, Utc::now()).unwrap();
+/// ```
+pub fn create_journal_file(data_dir: &Path, stock: &str, max_size: Option<u64>, now: DateTime<Utc>) -> Option<RotatingFile> {
+    let safe_stock = sanitize_string(stock);
+    open_rotating_file(RotatingFile::new(data_dir.join(ROLLING_DIR), &safe_stock, StorageFormat::Bincode.extension(), max_size, None), now)
+}
 
+/// Given a string slice containing the stock symbol in the trade market and the
+/// interval of the candlesticks, it returns a rotating file if it was successful
+/// in opening or creating it. The file will be located under {data_dir}/candlestick/{interval}/{date}
+/// directory and be named as {sanitized_stock_symbol}.csv
+///
 /// # Arguments
+/// `data_dir` - The directory the data files get written to, such as data
 /// `stock` - A string slice containing the stock symbol
+/// `interval` - The interval each candlestick of the file covers
+/// `max_size` - The size in bytes after which the file gets rotated, if any
+/// `now` - The current time, whose date decides the file which gets opened
 ///
 /// # Example
 /// ```
-/// use finnhub_ws::stock_handle::create_rolling_file;
-/// let f = create_rolling_file("TSLA").unwrap();
+/// use std::path::Path;
+/// use chrono::Utc;
+/// use finnhub_ws::stock_handle::create_candlestick_file;
+/// let f = create_candlestick_file(Path::new("data"), "TSLA", &"5m".parse().unwrap(), None, Utc::now()).unwrap();
+/// ```
+pub fn create_candlestick_file(data_dir: &Path, stock: &str, interval: &Interval, max_size: Option<u64>, now: DateTime<Utc>) -> Option<RotatingFile> {
+    let safe_stock = sanitize_string(stock);
+    open_rotating_file(RotatingFile::new(data_dir.join(CANDLESTICK_DIR).join(interval.to_string()), &safe_stock, "csv", max_size, None), now)
+}
+
+/// Given a string slice containing the stock symbol in the trade market,
+/// it returns a rotating file if it was successful in opening or creating it.
+/// The file will be located under {data_dir}/mean/{date} directory and be named as
+/// {sanitized_stock_symbol}.csv
+///
+/// # Arguments
+/// `data_dir` - The directory the data files get written to, such as data
+/// `stock` - A string slice containing the stock symbol
+/// `max_size` - The size in bytes after which the file gets rotated, if any
+/// `now` - The current time, whose date decides the file which gets opened
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use chrono::Utc;
+/// use finnhub_ws::stock_handle::create_mean_file;
+/// let f = create_mean_file(Path::new("data"), "TSLA", None, Utc::now()).unwrap();
 /// ```
-pub fn create_candlestick_file(stock: &str) -> Option<File> {
+pub fn create_mean_file(data_dir: &Path, stock: &str, max_size: Option<u64>, now: DateTime<Utc>) -> Option<RotatingFile> {
     let safe_stock = sanitize_string(stock);
-    match OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .read(true)
-        .open(format!("data/candlestick/{}.csv", safe_stock)) {
-        Ok(f) => Some(f),
-        Err(err) => match err.kind() {
-            io::ErrorKind::PermissionDenied => {
-                eprintln!("Cannot create a file due to permission reasons");
-                None
+    open_rotating_file(RotatingFile::new(data_dir.join(MEAN_DIR), &safe_stock, "csv", max_size, None), now)
+}
+
+/// Runs the integrity pass of the `recovery` module on the files of the stock which the
+/// program was writing to last, the ones of their most recent date, before they get appended
+/// to again. It returns what was repaired. The SQLite database recovers on its own.
+///
+/// # Arguments
+/// `stock` - A string slice containing the stock symbol
+/// `options` - The settings of the stock handles, such as the storage format and the
+/// candlestick intervals
+pub fn repair_stock_files(stock: &str, options: &MapperOptions) -> Vec<Repair> {
+    let safe_stock = sanitize_string(stock);
+    let data_dir = &options.data_dir;
+    let mut files = Vec::new();
+    match options.storage_format {
+        StorageFormat::Csv => files.push((RotatingFile::new(data_dir.join(ROLLING_DIR), &safe_stock, "csv", None, None), Layout::Rolling)),
+        StorageFormat::Bincode => files.push((RotatingFile::new(data_dir.join(ROLLING_DIR), &safe_stock, "bin", None, None), Layout::Journal)),
+        StorageFormat::Sqlite => return Vec::new(),
+    }
+    for interval in &options.candle_intervals {
+        files.push((RotatingFile::new(data_dir.join(CANDLESTICK_DIR).join(interval.to_string()), &safe_stock, "csv", None, None), Layout::Candlestick));
+    }
+    files.push((RotatingFile::new(data_dir.join(MEAN_DIR), &safe_stock, "csv", None, None), Layout::Mean));
+    let mut repairs = Vec::new();
+    for (file, layout) in files {
+        let paths = match file.latest_files() {
+            Ok(paths) => paths,
+            Err(e) => {
+                eprintln!("Couldn't look for the files of {}: {}", stock, e);
+                continue;
             }
-            _ => {
-                eprintln!("Couldn't create file");
-                None
+        };
+        for path in paths {
+            match repair_file(&path, layout) {
+                Ok(Some(repair)) => repairs.push(repair),
+                Ok(None) => {}
+                Err(e) => eprintln!("Couldn't check {}: {}", path.display(), e),
             }
         }
     }
+    repairs
 }
-/// Given a string slice containing the stock symbol in the trade market,
-/// it returns a file descriptor if it was successful in opening or creating it.
-/// The file will be located under data/mean directory and be named as
-/// {sanitized_stock_symbol}.csv
 
+/// Creates the `StockHandle` of a stock. It repairs the files the program was writing
+/// to if it got killed, reporting what was repaired, opens the store of the stock in the
+/// storage format of the settings, along with its news file if the news get tracked, and
+/// creates the channels. It returns None if the store can't be opened, which gets reported,
+/// while a stock whose news file can't be opened only gets its trades tracked.
+///
 /// # Arguments
-/// `stock` - A string slice containing the stock symbol
+/// `stock` : a string slice containing the stock symbol
+/// `options` : the settings of the stock handles, such as the candlestick intervals
+/// and the mean windows.
+pub fn create_handle(stock: &str, options: &MapperOptions) -> Option<StockHandle> {
+    for repair in repair_stock_files(stock, options) {
+        eprintln!("{}", repair);
+    }
+    let candlesticks = options.candle_intervals.iter().map(|interval| CandlestickSeries {
+        interval: *interval,
+        aggregator: Mutex::new(CandlestickAggregator::new(stock, *interval, options.late_policy)),
+    }).collect();
+    let store = match open_store(stock, options) {
+        Some(store) => store,
+        None => {
+            eprintln!("Not tracking {}: couldn't open its store", stock);
+            return None;
+        }
+    };
+    let news = match options.news {
+        true => NewsLog::open(&options.data_dir, stock).map_err(|e| eprintln!("Not tracking the news of {}: {}", stock, e)).ok(),
+        false => None,
+    };
+    Some(StockHandle{
+        stock_symbol: stock.to_string(),
+        store,
+        candlesticks,
+        mean: Mutex::new(RollingMeanAggregator::new(stock, &options.mean_windows)),
+        condition_filter: options.condition_filter.clone(),
+        news,
+        errors: ErrorCounts::default(),
+        latency: LatencyTracker::default(),
+        failed: AtomicBool::new(false),
+        stock_channel: unbounded(),
+        rolling_mean_channel: unbounded()
+    })
+}
+
+/// `Handles` holds the handles of the stocks being tracked in the order they were added,
+/// indexed by their symbol and by their sanitized name, so that the trades get routed
+/// to their stock without scanning every handle. It dereferences to the slice of handles.
+#[derive(Debug, Default, Clone)]
+pub struct Handles {
+    /// The handles of the stocks, in the order they were added
+    handles: Vec<Arc<StockHandle>>,
+    /// The position of each handle by the symbol of its stock
+    by_symbol: HashMap<String, usize>,
+    /// The position of each handle by the sanitized symbol its files are named after
+    by_name: HashMap<String, usize>,
+}
+
+impl Handles {
+    /// Returns the position of the handle of the stock with the given symbol
+    pub fn position(&self, symbol: &str) -> Option<usize> {
+        self.by_symbol.get(symbol).copied()
+    }
+
+    /// Returns the handle of the stock with the given symbol
+    pub fn get(&self, symbol: &str) -> Option<&Arc<StockHandle>> {
+        self.position(symbol).map(|i| &self.handles[i])
+    }
+
+    /// Returns the handle of the stock whose files are named after the given sanitized symbol
+    pub fn get_by_name(&self, name: &str) -> Option<&Arc<StockHandle>> {
+        self.by_name.get(name).map(|&i| &self.handles[i])
+    }
+
+    /// Appends the handle and indexes it
+    fn push(&mut self, handle: Arc<StockHandle>) {
+        self.by_symbol.insert(handle.stock_symbol.clone(), self.handles.len());
+        self.by_name.insert(sanitize_string(&handle.stock_symbol), self.handles.len());
+        self.handles.push(handle);
+    }
+
+    /// Removes the handle of the stock with the given symbol, reindexing the ones after it
+    fn remove(&mut self, symbol: &str) -> Option<Arc<StockHandle>> {
+        let index = self.by_symbol.remove(symbol)?;
+        self.by_name.remove(&sanitize_string(symbol));
+        let handle = self.handles.remove(index);
+        for position in self.by_symbol.values_mut().chain(self.by_name.values_mut()) {
+            if *position > index {
+                *position -= 1;
+            }
+        }
+        Some(handle)
+    }
+}
+
+impl Deref for Handles {
+    type Target = [Arc<StockHandle>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.handles
+    }
+}
+
+/// `Mapper` holds the `StockHandle`s of the stocks being tracked. Stocks can be added
+/// and retired while the program runs, so the handles are reference counted, letting
+/// the workers of a retired stock finish with its handle after it got removed. The
+/// trades of the symbols which aren't tracked get counted by symbol.
 ///
 /// # Example
 /// ```
-/// use finnhub_ws::stock_handle::create_rolling_file;
-/// let f = create_rolling_file("TSLA").unwrap();
+/// use finnhub_ws::stock_handle::{Mapper, MapperOptions};
+/// let mapper = Mapper::new(MapperOptions::default());
+/// mapper.add("BINANCE:XRPUSDT").unwrap();
+/// assert_eq!(mapper.symbols(), vec!["BINANCE:XRPUSDT".to_string()]);
+/// assert_eq!(mapper.handles().get_by_name("BINANCE_XRPUSDT").unwrap().stock_symbol, "BINANCE:XRPUSDT");
+/// assert!(mapper.remove("BINANCE:XRPUSDT").is_some());
+/// assert!(mapper.is_empty());
 /// ```
-pub fn create_mean_file(stock: &str) -> Option<File> {
-    let safe_stock = sanitize_string(stock);
-    match OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .read(true)
-        .open(format!("data/mean/{}.csv", safe_stock)) {
-        Ok(f) => Some(f),
-        Err(err) => match err.kind() {
-            io::ErrorKind::PermissionDenied => {
-                eprintln!("Cannot create a file due to permission reasons");
-                None
+#[derive(Debug)]
+pub struct Mapper {
+    /// The settings the stock handles get created with
+    options: MapperOptions,
+    /// The handles of the stocks being tracked
+    handles: RwLock<Handles>,
+    /// The number of trades received for each symbol which isn't tracked
+    unknown_trades: Mutex<BTreeMap<String, u64>>,
+    /// The names the files of the stocks being added get named after, reserved until
+    /// their handles get created so that the files of a stock only get opened once
+    adding: Mutex<BTreeSet<String>>,
+}
+
+impl Mapper {
+    /// Given the settings of the stock handles, creates and returns a mapper without any stocks
+    pub fn new(options: MapperOptions) -> Self {
+        Mapper {
+            options,
+            handles: RwLock::new(Handles::default()),
+            unknown_trades: Mutex::new(BTreeMap::new()),
+            adding: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Returns the settings the stock handles get created with
+    pub fn options(&self) -> &MapperOptions {
+        &self.options
+    }
+
+    /// Returns the clock the current time gets read from
+    pub fn clock(&self) -> &dyn Clock {
+        self.options.clock.as_ref()
+    }
+
+    /// Returns the handles of the stocks being tracked. The stocks can't be added or
+    /// retired while the guard is held, so it should be dropped as soon as possible,
+    /// cloning the handles needed for longer. The handles get inserted and removed at
+    /// once, so they are still consistent if a thread panicked while holding the lock.
+    pub fn handles(&self) -> RwLockReadGuard<'_, Handles> {
+        self.handles.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the symbols of the stocks being tracked, in the order they were added
+    pub fn symbols(&self) -> Vec<String> {
+        self.handles().iter().map(|h| h.stock_symbol.clone()).collect()
+    }
+
+    /// Returns the handle of the stock with the given symbol, if it is being tracked
+    pub fn get(&self, symbol: &str) -> Option<Arc<StockHandle>> {
+        self.handles().get(symbol).cloned()
+    }
+
+    /// Returns the number of stocks being tracked
+    pub fn len(&self) -> usize {
+        self.handles().len()
+    }
+
+    /// Returns true if no stocks are being tracked
+    pub fn is_empty(&self) -> bool {
+        self.handles().is_empty()
+    }
+
+    /// Creates the handle of a stock with `create_handle` and starts tracking it, returning
+    /// the handle. It returns None if the stock is tracked or being added already, another
+    /// stock tracked writes to the same files, or its store can't be opened. The name of its
+    /// files gets reserved before they get opened and repaired, which happens without holding
+    /// the lock of the handles.
+    pub fn add(&self, symbol: &str) -> Option<Arc<StockHandle>> {
+        let name = sanitize_string(symbol);
+        {
+            let handles = self.handles.write().unwrap_or_else(|e| e.into_inner());
+            let mut adding = self.adding.lock().unwrap_or_else(|e| e.into_inner());
+            if !self.can_add(&handles, symbol) || !adding.insert(name.clone()) {
+                return None;
             }
-            _ => {
-                eprintln!("Couldn't create file");
-                None
+        }
+        let handle = create_handle(symbol, &self.options).map(Arc::new);
+        let mut handles = self.handles.write().unwrap_or_else(|e| e.into_inner());
+        self.adding.lock().unwrap_or_else(|e| e.into_inner()).remove(&name);
+        let handle = handle?;
+        handles.push(Arc::clone(&handle));
+        Some(handle)
+    }
+
+    /// Returns true if neither the symbol nor the name its files get named after are taken
+    fn can_add(&self, handles: &Handles, symbol: &str) -> bool {
+        if handles.get(symbol).is_some() {
+            return false;
+        }
+        match handles.get_by_name(&sanitize_string(symbol)) {
+            Some(other) => {
+                eprintln!("Not tracking {}: its files would be shared with {}", symbol, other.stock_symbol);
+                false
             }
+            None => true,
+        }
+    }
+
+    /// Stops tracking the stock with the given symbol and returns its handle, if it was tracked.
+    /// Its workers keep running until they get sent a `WindowSignal::Shutdown`.
+    pub fn remove(&self, symbol: &str) -> Option<Arc<StockHandle>> {
+        self.handles.write().unwrap_or_else(|e| e.into_inner()).remove(symbol)
+    }
+
+    /// Counts a trade received for a symbol which isn't tracked. The first one of each
+    /// symbol gets reported, so that a stream sending other symbols gets noticed.
+    pub fn record_unknown(&self, symbol: &str) {
+        let mut unknown = self.unknown_trades.lock().unwrap_or_else(|e| e.into_inner());
+        let count = unknown.entry(symbol.to_string()).or_insert(0);
+        if *count == 0 {
+            eprintln!("Received a trade of {}, which isn't tracked", symbol);
         }
+        *count += 1;
+    }
+
+    /// Returns the number of trades received for each symbol which isn't tracked, by symbol
+    pub fn unknown_trades(&self) -> BTreeMap<String, u64> {
+        self.unknown_trades.lock().unwrap_or_else(|e| e.into_inner()).clone()
     }
 }
 
-/// Given an array of strings containing the stocks to track, it returns
-/// an atomically reference counted vector of `StockHandle`s. It creates
-/// the necessary files and wraps them around a mutex, creates the channels
-/// and runs once the writing of headers to those files.
+/// Given an array of strings containing the stocks to track, it returns an
+/// atomically reference counted `Mapper` holding a `StockHandle` for each of them,
+/// created with `create_handle`. A stock whose store can't be opened gets reported
+/// and left out, so that the others still get tracked.
 ///
 /// # Arguments
 /// `stocks` : reference of array of strings containing the stocks being tracked.
+/// `options` : the settings which apply to every stock, such as the candlestick intervals
+/// and the mean windows.
 ///
 /// # Example
 /// ```
-/// use finnhub_ws::stock_handle::initialize_mapper;
-/// let mapper = initialize_mapper(&["AAPL".to_string(), "BINANCE:BTCUSDT".to_string()]);
+/// use finnhub_ws::stock_handle::{initialize_mapper, MapperOptions};
+/// let mapper = initialize_mapper(&["AAPL".to_string(), "BINANCE:BTCUSDT".to_string()], &MapperOptions::default());
 /// assert_eq!(mapper.len(), 2);
 /// ```
-pub fn initialize_mapper(stocks: &[String])-> Arc<Vec<StockHandle>>{
-    let mut mapper = Vec::with_capacity(stocks.len());
+pub fn initialize_mapper(stocks: &[String], options: &MapperOptions) -> Arc<Mapper> {
+    let mapper = Mapper::new(options.clone());
     stocks.iter().for_each(|x| {
-        let rolling = create_rolling_file(x.as_str()).unwrap();
-        let candlestick = create_candlestick_file(x.as_str()).unwrap();
-        let mean = create_mean_file(x.as_str()).unwrap();
-        let res = StockHandle{
-            stock_symbol: x.to_string(),
-            rolling_file: Mutex::new(rolling),
-            candlestick_file: Mutex::new(candlestick),
-            mean_file: Mutex::new(mean),
-            once_flag: Once::new(),
-            stock_channel: unbounded(),
-            rolling_mean_channel: unbounded()
-        };
-        res.once_flag.call_once(||{
-            let t = TickerInfo::default();
-            let rf = res.rolling_file.lock().unwrap();
-            if t.check_file_empty(&rf) {
-                t.write_headers(&rf);
-            }
-            drop(rf);
-            let cf = res.candlestick_file.lock().unwrap();
-            let c = Candlestick{
-                open_price: 0.0,
-                close_price: 0.0,
-                highest_price: 0.0,
-                lowest_price: 0.0,
-                total_transactions: 0,
-                minute_of_hour: Utc::now(),
-                stock_symbol: "".parse().unwrap()
-            };
-            c.write_to_file(&cf);
-            drop(cf);
-        });
-        mapper.push(res);
+        mapper.add(x);
     });
     Arc::new(mapper)
 }
 
 
 #[cfg(test)]
-mod stock_handle_test {
-    use std::fs::remove_file;
-    use std::ops::Deref;
-    use std::sync::Arc;
-    use crate::stock_handle::{create_candlestick_file, create_mean_file, create_rolling_file, initialize_mapper, StockHandle};
-    use crate::utils::{create_dirs, sanitize_string};
+pub(crate) mod stock_handle_test {
+    use std::fs::{remove_file, File, OpenOptions};
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use chrono::Utc;
+    use crate::journal::{JournalReader, StorageFormat};
+    use crate::{RollingData, TickerInfo};
+    use std::sync::{Arc, Barrier};
+    use crate::stock_handle::{create_candlestick_file, create_mean_file, create_rolling_file, initialize_mapper, repair_stock_files, Mapper, MapperOptions, StockHandle, WindowSignal, DATA_DIR};
+    use crate::utils::sanitize_string;
 
     #[test]
     fn given_a_stock_symbol_it_should_create_rolling_file() {
-        let _ = create_dirs("data/rolling");
         let stock_name = "rolling";
-        let f = create_rolling_file(stock_name).unwrap();
-        drop(f);
-        let file_exists = std::fs::metadata("data/rolling/rolling.csv").unwrap();
-        assert_eq!(file_exists.is_file(), true);
-        remove_file("data/rolling/rolling.csv").unwrap();
+        let f = create_rolling_file(Path::new(DATA_DIR), stock_name, None, Utc::now()).unwrap();
+        let path = f.path().unwrap();
+        assert!(path.starts_with(format!("data/rolling/{}", Utc::now().format("%Y-%m-%d"))));
+        assert!(path.ends_with("rolling.csv"));
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(data.starts_with("Symbol,Price,Timestamp"));
+        remove_file(path).unwrap();
     }
 
     #[test]
     fn given_a_stock_symbol_it_should_create_candlestick_file() {
-        let _ = create_dirs("data/candlestick");
         let stock_name = "candlestick";
-        let f = create_candlestick_file(stock_name).unwrap();
-        drop(f);
-        let file_exists = std::fs::metadata("data/candlestick/candlestick.csv").unwrap();
-        assert_eq!(file_exists.is_file(), true);
-        remove_file("data/candlestick/candlestick.csv").unwrap();
+        let f = create_candlestick_file(Path::new(DATA_DIR), stock_name, &"1h".parse().unwrap(), None, Utc::now()).unwrap();
+        let path = f.path().unwrap();
+        assert!(path.starts_with("data/candlestick/1h"));
+        assert!(path.ends_with("candlestick.csv"));
+        assert!(std::fs::metadata(&path).unwrap().is_file());
+        remove_file(path).unwrap();
     }
 
     #[test]
     fn given_a_stock_symbol_it_should_create_mean_file() {
-        let _ = create_dirs("data/mean");
         let stock_name = "mean";
-        let f = create_mean_file(stock_name).unwrap();
-        drop(f);
-        let file_exists = std::fs::metadata("data/mean/mean.csv").unwrap();
-        assert_eq!(file_exists.is_file(), true);
-        remove_file("data/mean/mean.csv").unwrap();
+        let f = create_mean_file(Path::new(DATA_DIR), stock_name, None, Utc::now()).unwrap();
+        let path = f.path().unwrap();
+        assert!(path.starts_with("data/mean"));
+        assert!(path.ends_with("mean.csv"));
+        assert!(std::fs::metadata(&path).unwrap().is_file());
+        remove_file(path).unwrap();
+    }
+
+    /// Returns the paths of the files of the current date a stock handle got created with,
+    /// with the given extension for the rolling file
+    pub(crate) fn handle_files(handle: &StockHandle, extension: &str) -> Vec<PathBuf> {
+        let date = Utc::now().format("%Y-%m-%d");
+        let safe_stock = sanitize_string(&handle.stock_symbol);
+        let mut files = vec![PathBuf::from(format!("data/rolling/{}/{}.{}", date, safe_stock, extension))];
+        for series in &handle.candlesticks {
+            files.push(PathBuf::from(format!("data/candlestick/{}/{}/{}.csv", series.interval, date, safe_stock)));
+        }
+        files.push(PathBuf::from(format!("data/mean/{}/{}.csv", date, safe_stock)));
+        files
+    }
+
+    /// Removes the csv files of the current date a stock handle got created with
+    pub(crate) fn remove_handle_files(handle: &StockHandle) {
+        handle_files(handle, "csv").into_iter().for_each(|p| remove_file(p).unwrap());
     }
 
     #[test]
     fn given_an_array_of_stocks_it_should_create_the_mapper_files() {
-        for dir in ["data/rolling", "data/mean", "data/candlestick"] {
-            let _ = create_dirs(dir);
-        }
         let stocks = vec!["abc".to_string(), "def".to_string(), "ghi".to_string()];
-        let mapper = initialize_mapper(&stocks);
+        let mapper = initialize_mapper(&stocks, &MapperOptions::default());
         assert_eq!(mapper.len(), 3);
-        assert_eq!(std::fs::metadata("data/rolling").unwrap().is_dir(), true);
-        for stock in stocks {
-            assert_eq!(std::fs::metadata(format!("data/rolling/{}.csv", sanitize_string(&stock) )).unwrap().is_file(), true);
-            assert_eq!(std::fs::metadata(format!("data/candlestick/{}.csv",sanitize_string(&stock) )).unwrap().is_file(), true);
-            assert_eq!(std::fs::metadata(format!("data/mean/{}.csv", sanitize_string(&stock) )).unwrap().is_file(), true);
-            remove_file(format!("data/rolling/{}.csv", sanitize_string(&stock))).unwrap();
-            remove_file(format!("data/candlestick/{}.csv", sanitize_string(&stock))).unwrap();
-            remove_file(format!("data/mean/{}.csv", sanitize_string(&stock))).unwrap();
+        assert!(std::fs::metadata("data/rolling").unwrap().is_dir());
+        for handle in mapper.handles().iter() {
+            for path in handle_files(handle, "csv") {
+                assert!(std::fs::metadata(&path).unwrap().is_file());
+                remove_file(path).unwrap();
+            }
         }
     }
 
     #[test]
     fn given_a_stock_symbol_it_should_create_the_mapper_channels(){
-        for dir in ["data/rolling", "data/mean", "data/candlestick"] {
-            let _ = create_dirs(dir);
-        }
         let stocks = vec!["jkl".to_string()];
-        let mapper = initialize_mapper(&stocks);
-        let handles: &Vec<StockHandle> = mapper.deref();
-        for handle in handles {
+        let mapper = initialize_mapper(&stocks, &MapperOptions::default());
+        for handle in mapper.handles().iter() {
             let (tx,rx) = &handle.stock_channel;
-            tx.send(1234).unwrap();
-            assert_eq!(rx.recv().unwrap(), 1234);
-            remove_file(format!("data/rolling/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
-            remove_file(format!("data/candlestick/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
-            remove_file(format!("data/mean/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
+            tx.send(WindowSignal::Window(1234)).unwrap();
+            assert_eq!(rx.recv().unwrap(), WindowSignal::Window(1234));
+            remove_handle_files(handle);
         }
     }
 
     #[test]
     fn given_a_stock_symbol_it_should_create_the_mapper_mean_channels(){
-        for dir in ["data/rolling", "data/mean", "data/candlestick"] {
-            let _ = create_dirs(dir);
-        }
         let stocks = vec!["mno".to_string()];
-        let mapper = initialize_mapper(&stocks);
-        let handles: &Vec<StockHandle> = mapper.deref();
-        for handle in handles {
+        let mapper = initialize_mapper(&stocks, &MapperOptions::default());
+        for handle in mapper.handles().iter() {
             let (tx,rx) = &handle.rolling_mean_channel;
-            tx.send(1234).unwrap();
-            assert_eq!(rx.recv().unwrap(), 1234);
-            remove_file(format!("data/rolling/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
-            remove_file(format!("data/candlestick/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
-            remove_file(format!("data/mean/{}.csv", sanitize_string(&handle.stock_symbol))).unwrap();
+            tx.send(WindowSignal::Window(1234)).unwrap();
+            assert_eq!(rx.recv().unwrap(), WindowSignal::Window(1234));
+            remove_handle_files(handle);
         }
     }
 
-}
+    #[test]
+    fn given_the_bincode_format_it_should_write_the_trades_to_a_journal(){
+        let options = MapperOptions { storage_format: StorageFormat::Bincode, ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["pqr".to_string()], &options);
+        let ticker = TickerInfo::new("pqr", 172.5, 2.0, &Utc::now(), &["1".to_string()]);
+        mapper.handles()[0].store.append_trade(&RollingData::from(&ticker)).unwrap();
+        let files = handle_files(&mapper.handles()[0], "bin");
+        let records: Vec<RollingData> = JournalReader::new(File::open(&files[0]).unwrap()).map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].volume, 2.0);
+        assert_eq!(records[0].conditions, vec!["1".to_string()]);
+        assert!(std::fs::metadata(files[0].with_extension("csv")).is_err());
+        files.into_iter().for_each(|p| remove_file(p).unwrap());
+    }
+
+    #[test]
+    fn given_a_file_ending_in_a_partial_row_it_should_be_repaired_on_initialization(){
+        let options = MapperOptions::default();
+        let mapper = initialize_mapper(&["vwx".to_string()], &options);
+        let ticker = TickerInfo::new("vwx", 172.5, 2.0, &Utc::now(), &[]);
+        mapper.handles()[0].store.append_trade(&RollingData::from(&ticker)).unwrap();
+        let files = handle_files(&mapper.handles()[0], "csv");
+        drop(mapper);
+        // the process got killed in the middle of a row
+        OpenOptions::new().append(true).open(&files[0]).unwrap().write_all(b"vwx,172.").unwrap();
+        let repairs = repair_stock_files("vwx", &options);
+        assert_eq!(repairs.len(), 1);
+        assert_eq!(repairs[0].path, files[0]);
+        assert_eq!(repairs[0].removed, 8);
+        let mapper = initialize_mapper(&["vwx".to_string()], &options);
+        mapper.handles()[0].store.append_trade(&RollingData::from(&ticker)).unwrap();
+        mapper.handles()[0].store.flush().unwrap();
+        let since = Utc::now() - chrono::Duration::minutes(1);
+        assert_eq!(mapper.handles()[0].store.trades_between(since, Utc::now()).unwrap().len(), 2);
+        remove_file(&repairs[0].quarantine).unwrap();
+        files.into_iter().for_each(|p| remove_file(p).unwrap());
+    }
 
+    #[test]
+    fn given_a_size_threshold_it_should_rotate_the_rolling_file(){
+        let options = MapperOptions { max_file_size: Some(1), ..MapperOptions::default() };
+        let mapper = initialize_mapper(&["stu".to_string()], &options);
+        let ticker = TickerInfo::new("stu", 172.5, 2.0, &Utc::now(), &[]);
+        mapper.handles()[0].store.append_trade(&RollingData::from(&ticker)).unwrap();
+        mapper.handles()[0].store.append_trade(&RollingData::from(&ticker)).unwrap();
+        let files = handle_files(&mapper.handles()[0], "csv");
+        let rotated = files[0].with_extension("1.csv");
+        // each file gets its own headers and one of the trades
+        for path in [&files[0], &rotated] {
+            let data = std::fs::read_to_string(path).unwrap();
+            assert!(data.starts_with("Symbol,"));
+            assert_eq!(data.lines().count(), 2);
+        }
+        remove_file(rotated).unwrap();
+        files.into_iter().for_each(|p| remove_file(p).unwrap());
+    }
+
+    #[test]
+    fn it_should_route_by_symbol_and_name_after_a_stock_got_removed(){
+        let stocks = vec!["vwx".to_string(), "EX:YZ".to_string(), "EX:AB".to_string()];
+        let mapper = initialize_mapper(&stocks, &MapperOptions::default());
+        let removed = mapper.remove("vwx").unwrap();
+        let handles = mapper.handles();
+        assert_eq!(handles.position("EX:AB"), Some(1));
+        assert_eq!(handles.get("EX:YZ").unwrap().stock_symbol, "EX:YZ");
+        assert_eq!(handles.get_by_name("EX_AB").unwrap().stock_symbol, "EX:AB");
+        assert!(handles.get("vwx").is_none());
+        assert!(handles.get_by_name("vwx").is_none());
+        drop(handles);
+        // a symbol writing to the files of a tracked stock doesn't get added
+        assert!(mapper.add("EX_YZ").is_none());
+        mapper.record_unknown("vwx");
+        mapper.record_unknown("vwx");
+        assert_eq!(mapper.unknown_trades().get("vwx"), Some(&2));
+        for handle in mapper.handles().iter().chain(std::iter::once(&removed)) {
+            remove_handle_files(handle);
+        }
+    }
+
+    #[test]
+    fn given_two_concurrent_adds_of_a_stock_it_should_only_create_its_files_once() {
+        let options = MapperOptions { data_dir: "tmp/mapper_concurrent".into(), ..MapperOptions::default() };
+        let mapper = Mapper::new(options);
+        // a stock being added doesn't get its files opened by another call
+        mapper.adding.lock().unwrap().insert("EX_CC".to_string());
+        assert!(mapper.add("EX:CC").is_none());
+        assert!(!Path::new("tmp/mapper_concurrent").exists());
+        mapper.adding.lock().unwrap().clear();
+        let barrier = Barrier::new(2);
+        let added: Vec<Option<Arc<StockHandle>>> = std::thread::scope(|scope| {
+            let adds: Vec<_> = (0..2).map(|_| scope.spawn(|| {
+                barrier.wait();
+                mapper.add("EX:CC")
+            })).collect();
+            adds.into_iter().map(|add| add.join().unwrap()).collect()
+        });
+        assert_eq!(added.iter().filter(|h| h.is_some()).count(), 1);
+        assert_eq!(mapper.symbols(), vec!["EX:CC".to_string()]);
+        assert!(mapper.adding.lock().unwrap().is_empty());
+        std::fs::remove_dir_all("tmp/mapper_concurrent").unwrap();
+    }
+}
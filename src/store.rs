@@ -0,0 +1,803 @@
+//! Storage backend primitives
+//! # store
+//!
+//! This contains the `Store` trait, which is everything the program needs from the place the
+//! data of a stock get persisted to: appending the trades, candlesticks and mean data as they
+//! are calculated and querying the trades of a time range back. The trades get buffered and
+//! written once per websocket message, or once per flush interval, instead of once per trade,
+//! and the `FsyncPolicy` decides how often the data get synced to the disk. There are two backends:
+//! - `FileStore`, which writes the date partitioned csv files, with the trades optionally
+//!   written to a bincode journal instead
+//! - `SqliteStore`, which writes to the tables of an embedded SQLite database, indexed
+//!   by symbol and time
+//!
+//! # Example
+//! ```
+//! use chrono::{Duration, TimeZone, Utc};
+//! use finnhub_ws::stock_handle::MapperOptions;
+//! use finnhub_ws::store::open_store;
+//! use finnhub_ws::RollingData;
+//! let store = open_store("STORE:DOC", &MapperOptions::default()).unwrap();
+//! // the timestamps get written in milliseconds
+//! let now = Utc.timestamp_millis(Utc::now().timestamp_millis());
+//! let trade = RollingData {
+//!     symbol: "STORE:DOC".to_string(),
+//!     price: 172.5,
+//!     timestamp: now,
+//!     write_timestamp: now,
+//!     volume: 1.0,
+//!     conditions: vec![],
+//! };
+//! store.append_trade(&trade).unwrap();
+//! store.flush().unwrap();
+//! let trades = store.trades_between(now, now + Duration::seconds(1)).unwrap();
+//! assert_eq!(trades, vec![trade]);
+//! ```
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use rusqlite::{params, Connection};
+use crate::candlestick::Candlestick;
+use crate::condition;
+use crate::error::Error;
+use crate::interval::Interval;
+use crate::journal::{self, JournalReader, StorageFormat};
+use crate::mean::MeanData;
+use crate::rotation::RotatingFile;
+use crate::stock_handle::{create_candlestick_file, create_journal_file, create_mean_file, create_rolling_file, MapperOptions};
+use crate::RollingData;
+
+/// The name of the database under the data directory the `SqliteStore` of every stock writes to
+pub const DATABASE_NAME: &str = "finnhub.sqlite";
+
+/// `FsyncPolicy` decides how often the data written get synced to the disk, trading the
+/// latency of the writes for the data which can be lost on a power failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// The data never get synced explicitly, apart from the files getting rotated, so the
+    /// operating system decides when
+    #[default]
+    Never,
+    /// The data get synced periodically, so at most the data of one period can be lost
+    Interval,
+    /// The data get synced every time they get written, which is the slowest
+    Always,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(FsyncPolicy::Never),
+            "interval" => Ok(FsyncPolicy::Interval),
+            "always" => Ok(FsyncPolicy::Always),
+            _ => Err(format!("invalid fsync policy {}, expected never, interval or always", s)),
+        }
+    }
+}
+
+impl fmt::Display for FsyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsyncPolicy::Never => write!(f, "never"),
+            FsyncPolicy::Interval => write!(f, "interval"),
+            FsyncPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// `Store` is where the data of a single stock get persisted to. The methods take a shared
+/// reference, so that the thread reading the websocket and the ones calculating the candlestick
+/// and mean data can write to the store of a stock at the same time.
+pub trait Store: Send + Sync + fmt::Debug {
+    /// Appends the trades of the stock which arrived in a single message. They get written
+    /// right away, unless the store was opened with a flush interval, in which case they stay
+    /// buffered until the next `flush`.
+    fn append_trades(&self, trades: &[RollingData]) -> io::Result<()>;
+    /// Appends a single trade of the stock, the same way as `append_trades`
+    fn append_trade(&self, trade: &RollingData) -> io::Result<()> {
+        self.append_trades(std::slice::from_ref(trade))
+    }
+    /// Writes the buffered trades, syncing them to the disk under the `Always` fsync policy
+    fn flush(&self) -> io::Result<()>;
+    /// Writes the buffered trades and syncs everything written so far to the disk
+    fn sync(&self) -> io::Result<()>;
+    /// Appends a candlestick of the stock for the given interval
+    fn append_candle(&self, interval: Interval, candle: &Candlestick) -> io::Result<()>;
+    /// Appends the mean data of the stock for one of its mean windows
+    fn append_mean(&self, mean: &MeanData) -> io::Result<()>;
+    /// Returns the trades of the stock with an exchange timestamp between `start` (inclusive)
+    /// and `end` (exclusive), in the order they were written in
+    fn trades_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> io::Result<Vec<RollingData>>;
+}
+
+/// Given a string slice containing the stock symbol in the trade market and the settings of
+/// the stock handles, it returns the store of the storage format of the settings if it was
+/// successful in opening or creating it.
+///
+/// # Arguments
+/// `stock` - A string slice containing the stock symbol
+/// `options` - The settings of the stock handles, such as the storage format and the
+/// candlestick intervals
+pub fn open_store(stock: &str, options: &MapperOptions) -> Option<Box<dyn Store>> {
+    match options.storage_format {
+        StorageFormat::Csv | StorageFormat::Bincode => {
+            FileStore::open(stock, options).map(|store| Box::new(store) as Box<dyn Store>)
+        }
+        StorageFormat::Sqlite => {
+            let path = options.data_dir.join(DATABASE_NAME);
+            match SqliteStore::open(&path, stock, options) {
+                Ok(store) => Some(Box::new(store)),
+                Err(e) => {
+                    eprintln!("Couldn't open the database {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// `FileStore` writes the data of a stock to the date partitioned files under the data
+/// directory of the settings, data by default, the layout the program always had:
+/// - the trades to {data_dir}/rolling/{date}/{symbol}.csv, or {symbol}.bin for the bincode journal
+/// - the candlesticks to {data_dir}/candlestick/{interval}/{date}/{symbol}.csv
+/// - the mean data to {data_dir}/mean/{date}/{symbol}.csv
+///
+/// Each file has a mutex so that it can be written from different threads, and the trades
+/// can be read back without parsing errors due to the file being read while being written.
+#[derive(Debug)]
+pub struct FileStore {
+    /// format: the format of the rolling files, either csv or bincode
+    format: StorageFormat,
+    /// fsync: how often the files get synced to the disk
+    fsync: FsyncPolicy,
+    /// rolling: the buffered writer of the file the trades get written to as they arrive,
+    /// partitioned by the date they get written on
+    rolling: Mutex<TradeWriter>,
+    /// candlesticks: the file of each candlestick interval, partitioned by the date of the
+    /// candlesticks
+    candlesticks: Vec<(Interval, Mutex<RotatingFile>)>,
+    /// mean: the file of the mean data, partitioned by the date of the last trade of their window
+    mean: Mutex<RotatingFile>,
+    /// mean_window: whether the rows of the mean file end with the window they cover
+    mean_window: bool,
+}
+
+impl FileStore {
+    /// Given a string slice containing the stock symbol and the settings of the stock handles,
+    /// it opens or creates the files of the stock and writes a placeholder row to each
+    /// candlestick file, the way the candlestick files always started.
+    pub fn open(stock: &str, options: &MapperOptions) -> Option<Self> {
+        let now = options.clock.now();
+        let data_dir = options.data_dir.as_path();
+        let rolling = match options.storage_format {
+            StorageFormat::Bincode => create_journal_file(data_dir, stock, options.max_file_size, now)?,
+            _ => create_rolling_file(data_dir, stock, options.max_file_size, now)?,
+        };
+        let mut candlesticks = Vec::with_capacity(options.candle_intervals.len());
+        for interval in &options.candle_intervals {
+            let mut file = create_candlestick_file(data_dir, stock, interval, options.max_file_size, now)?;
+            let placeholder = Candlestick { minute_of_hour: now, ..Candlestick::default() };
+            if let Err(e) = file.current(placeholder.minute_of_hour).map_err(Error::from).and_then(|f| placeholder.write_to_file(f)) {
+                eprintln!("Couldn't write the placeholder candlestick of {}: {}", stock, e);
+            }
+            candlesticks.push((*interval, Mutex::new(file)));
+        }
+        Some(FileStore {
+            format: options.storage_format,
+            fsync: options.fsync,
+            rolling: Mutex::new(TradeWriter {
+                file: rolling,
+                format: options.storage_format,
+                batched: options.flush_interval.is_some(),
+                buffer: Vec::new(),
+                time: None,
+            }),
+            candlesticks,
+            mean: Mutex::new(create_mean_file(data_dir, stock, options.max_file_size, now)?),
+            mean_window: options.mean_window_column(),
+        })
+    }
+
+    /// Reads the trades of a rolling file, compressed or not, with an exchange timestamp
+    /// between `start` and `end` into `records`
+    fn read_trades(&self, path: &Path, start: DateTime<Utc>, end: DateTime<Utc>, records: &mut Vec<RollingData>) -> io::Result<()> {
+        read_rolling_file(path, self.format, |record| {
+            if record.timestamp >= start && record.timestamp < end {
+                records.push(record);
+            }
+        })
+    }
+}
+
+/// The trades of a rolling file, in the order they got written
+pub type RollingTrades = Box<dyn Iterator<Item = io::Result<RollingData>>>;
+
+/// Opens a rolling file written in the given format, compressed with gzip or not, and
+/// returns its trades in the order they got written, read as they get iterated over.
+/// The csv rows which can't be read get skipped.
+pub fn rolling_trades(path: &Path, format: StorageFormat) -> io::Result<RollingTrades> {
+    let file = File::open(path)?;
+    let reader: Box<dyn Read> = match path.extension() == Some(OsStr::new("gz")) {
+        true => Box::new(GzDecoder::new(file)),
+        false => Box::new(file),
+    };
+    let reader = BufReader::new(reader);
+    match format {
+        StorageFormat::Bincode => Ok(Box::new(JournalReader::new(reader))),
+        _ => {
+            let path = path.to_path_buf();
+            // rolling files written before the volume column was added have one field less
+            let reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+            Ok(Box::new(reader.into_deserialize().filter_map(move |record| match record {
+                Ok(record) => Some(Ok(record)),
+                Err(e) => {
+                    eprintln!("Skipping an invalid row of {}: {}", path.display(), e);
+                    None
+                }
+            })))
+        }
+    }
+}
+
+/// Reads the trades of a rolling file written in the given format, compressed with gzip
+/// or not, passing each of them to `keep` in the order they got written. The csv rows
+/// which can't be read get skipped.
+pub fn read_rolling_file(path: &Path, format: StorageFormat, mut keep: impl FnMut(RollingData)) -> io::Result<()> {
+    for record in rolling_trades(path, format)? {
+        keep(record?);
+    }
+    Ok(())
+}
+
+impl Store for FileStore {
+    fn append_trades(&self, trades: &[RollingData]) -> io::Result<()> {
+        let mut rf = lock(&self.rolling)?;
+        for trade in trades {
+            rf.push(trade)?;
+        }
+        if !rf.batched && rf.write()? && self.fsync == FsyncPolicy::Always {
+            rf.file.sync()?;
+        }
+        Ok(())
+    }
+
+    fn append_candle(&self, interval: Interval, candle: &Candlestick) -> io::Result<()> {
+        let (_, file) = self.candlesticks.iter()
+            .find(|(i, _)| *i == interval)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no candlestick file for {}", interval)))?;
+        let mut cf = lock(file)?;
+        candle.write_to_file(cf.current(candle.minute_of_hour)?)?;
+        if self.fsync == FsyncPolicy::Always {
+            cf.sync()?;
+        }
+        Ok(())
+    }
+
+    fn append_mean(&self, mean: &MeanData) -> io::Result<()> {
+        let mut mf = lock(&self.mean)?;
+        mean.write_to_file(mf.current(mean.end_time)?, self.mean_window)?;
+        if self.fsync == FsyncPolicy::Always {
+            mf.sync()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut rf = lock(&self.rolling)?;
+        if rf.write()? && self.fsync == FsyncPolicy::Always {
+            rf.file.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let mut rf = lock(&self.rolling)?;
+        rf.write()?;
+        rf.file.sync()?;
+        drop(rf);
+        for (_, file) in &self.candlesticks {
+            lock(file)?.sync()?;
+        }
+        lock(&self.mean)?.sync()
+    }
+
+    fn trades_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> io::Result<Vec<RollingData>> {
+        // the files are partitioned by the date the trades got written on, which is
+        // a bit after their exchange timestamp, unless they crossed midnight on the way
+        let since = (start - Duration::days(1)).naive_utc().date();
+        let mut rf = lock(&self.rolling)?;
+        rf.write()?;
+        let mut records = Vec::new();
+        for path in rf.file.files_since(since)? {
+            self.read_trades(&path, start, end, &mut records)?;
+        }
+        Ok(records)
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        if let Err(e) = lock(&self.rolling).and_then(|mut rf| rf.write()) {
+            eprintln!("Couldn't write the buffered trades: {}", e);
+        }
+    }
+}
+
+/// `TradeWriter` buffers the trades of a stock in the format of its rolling file, so that
+/// all the trades of a message, or of a flush interval, get written with a single call
+#[derive(Debug)]
+struct TradeWriter {
+    /// file: the rolling file of the stock
+    file: RotatingFile,
+    /// format: the format of the rolling file, either csv or bincode
+    format: StorageFormat,
+    /// batched: whether the trades stay buffered across messages until the store gets flushed
+    batched: bool,
+    /// buffer: the serialized trades which weren't written to the file yet
+    buffer: Vec<u8>,
+    /// time: the write timestamp of the first buffered trade, which decides the file the
+    /// buffer gets written to
+    time: Option<DateTime<Utc>>,
+}
+
+impl TradeWriter {
+    /// Serializes the trade to the buffer. The buffer gets written first if the trade
+    /// belongs to the partition of another date.
+    fn push(&mut self, trade: &RollingData) -> io::Result<()> {
+        if matches!(self.time, Some(time) if time.date() != trade.write_timestamp.date()) {
+            self.write()?;
+        }
+        self.time.get_or_insert(trade.write_timestamp);
+        match self.format {
+            StorageFormat::Bincode => journal::write_record(&mut self.buffer, trade),
+            _ => {
+                let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(&mut self.buffer);
+                writer.serialize(trade)?;
+                writer.flush()
+            }
+        }
+    }
+
+    /// Writes the buffer to the file with a single call, returning whether there was anything
+    /// to write. The buffer gets emptied even if the write fails, so that a file which can't be
+    /// written to doesn't make it grow without bounds.
+    fn write(&mut self) -> io::Result<bool> {
+        let time = match self.time.take() {
+            Some(time) => time,
+            None => return Ok(false),
+        };
+        let result = self.file.current(time).and_then(|mut file| file.write_all(&self.buffer));
+        self.buffer.clear();
+        result.map(|_| true)
+    }
+}
+
+/// `SqliteStore` writes the data of a stock to the `trades`, `candlesticks` and `means` tables
+/// of an SQLite database, which can be shared by the stores of every stock. Each table is indexed
+/// by symbol and time, so that the data of a stock for a time range can be queried without
+/// scanning the files of every day. The timestamps are stored in milliseconds.
+#[derive(Debug)]
+pub struct SqliteStore {
+    /// symbol: the symbol of the stock the store writes the data of
+    symbol: String,
+    /// batched: whether the trades stay buffered across messages until the store gets flushed
+    batched: bool,
+    /// pending: the trades which weren't inserted yet
+    pending: Mutex<Vec<RollingData>>,
+    /// connection: the connection to the database, which can only be used by a single thread at a time
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Given the path of the database, the stock symbol and the settings of the stock handles,
+    /// it opens or creates the database along with its tables and returns the store of the stock.
+    /// The fsync policy sets how often SQLite syncs the database: never with `Never`, at its
+    /// checkpoints and on `sync` with `Interval`, and on every transaction with `Always`.
+    ///
+    /// # Example
+    /// ```
+    /// use std::path::Path;
+    /// use finnhub_ws::stock_handle::MapperOptions;
+    /// use finnhub_ws::store::SqliteStore;
+    /// use finnhub_ws::utils::create_dirs;
+    /// let _ = create_dirs("tmp");
+    /// let store = SqliteStore::open(Path::new("tmp/sqlite_doc.sqlite"), "AAPL", &MapperOptions::default()).unwrap();
+    /// std::fs::remove_file("tmp/sqlite_doc.sqlite").unwrap();
+    /// ```
+    pub fn open(path: &Path, symbol: &str, options: &MapperOptions) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        // the stores of the other stocks write to the same database from their own threads
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        let synchronous = match options.fsync {
+            FsyncPolicy::Never => "OFF",
+            FsyncPolicy::Interval => "NORMAL",
+            FsyncPolicy::Always => "FULL",
+        };
+        connection.pragma_update(None, "synchronous", synchronous)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS trades (
+                symbol TEXT NOT NULL,
+                price REAL NOT NULL,
+                timestamp INTEGER NOT NULL,
+                write_timestamp INTEGER NOT NULL,
+                volume REAL NOT NULL,
+                conditions TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS trades_symbol_timestamp ON trades (symbol, timestamp);
+            CREATE TABLE IF NOT EXISTS candlesticks (
+                symbol TEXT NOT NULL,
+                interval TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                open REAL NOT NULL,
+                close REAL NOT NULL,
+                high REAL NOT NULL,
+                low REAL NOT NULL,
+                transactions INTEGER NOT NULL,
+                partial INTEGER NOT NULL,
+                volume REAL NOT NULL,
+                vwap REAL NOT NULL,
+                turnover REAL NOT NULL,
+                corrected INTEGER NOT NULL,
+                late_trades INTEGER NOT NULL,
+                news INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS candlesticks_symbol_timestamp ON candlesticks (symbol, timestamp);
+            CREATE TABLE IF NOT EXISTS means (
+                symbol TEXT NOT NULL,
+                window TEXT NOT NULL,
+                start_time INTEGER NOT NULL,
+                end_time INTEGER NOT NULL,
+                mean_price REAL NOT NULL,
+                transactions INTEGER NOT NULL,
+                partial INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS means_symbol_end_time ON means (symbol, end_time);",
+        )?;
+        add_missing_column(&connection, "candlesticks", "news", "INTEGER NOT NULL DEFAULT 0")?;
+        Ok(SqliteStore {
+            symbol: symbol.to_string(),
+            batched: options.flush_interval.is_some(),
+            pending: Mutex::new(Vec::new()),
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.insert_pending() {
+            eprintln!("Couldn't insert the buffered trades: {}", e);
+        }
+    }
+}
+
+/// Adds the column to the table of a database created before the column existed
+fn add_missing_column(connection: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = statement.query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<String>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        connection.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))?;
+    }
+    Ok(())
+}
+
+/// Locks a file or the connection of a store, failing if a thread panicked while holding it,
+/// so that the stock whose worker panicked fails on its own instead of taking the others down
+fn lock<T>(mutex: &Mutex<T>) -> io::Result<MutexGuard<'_, T>> {
+    mutex.lock().map_err(|_| io::Error::other("a thread panicked while writing to the store"))
+}
+
+/// Turns the errors of the database to the io errors the rest of the stores return
+fn database_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl SqliteStore {
+    /// Inserts the pending trades in a single transaction
+    fn insert_pending(&self) -> io::Result<()> {
+        let mut pending = lock(&self.pending)?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let trades: Vec<RollingData> = pending.drain(..).collect();
+        let mut connection = lock(&self.connection)?;
+        let transaction = connection.transaction().map_err(database_error)?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO trades (symbol, price, timestamp, write_timestamp, volume, conditions)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+            ).map_err(database_error)?;
+            for trade in &trades {
+                statement.execute(params![
+                    trade.symbol,
+                    trade.price,
+                    trade.timestamp.timestamp_millis(),
+                    trade.write_timestamp.timestamp_millis(),
+                    trade.volume,
+                    condition::join_codes(&trade.conditions),
+                ]).map_err(database_error)?;
+            }
+        }
+        transaction.commit().map_err(database_error)
+    }
+}
+
+impl Store for SqliteStore {
+    fn append_trades(&self, trades: &[RollingData]) -> io::Result<()> {
+        lock(&self.pending)?.extend_from_slice(trades);
+        match self.batched {
+            true => Ok(()),
+            false => self.insert_pending(),
+        }
+    }
+
+    fn append_candle(&self, interval: Interval, candle: &Candlestick) -> io::Result<()> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection.prepare_cached(
+            "INSERT INTO candlesticks (symbol, interval, timestamp, open, close, high, low, transactions,
+                partial, volume, vwap, turnover, corrected, late_trades, news)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)"
+        ).map_err(database_error)?;
+        statement.execute(params![
+            candle.stock_symbol,
+            interval.to_string(),
+            candle.minute_of_hour.timestamp_millis(),
+            candle.open_price,
+            candle.close_price,
+            candle.highest_price,
+            candle.lowest_price,
+            candle.total_transactions as i64,
+            candle.partial,
+            candle.volume,
+            candle.vwap,
+            candle.turnover,
+            candle.corrected,
+            candle.late_trades as i64,
+            candle.news,
+        ]).map_err(database_error)?;
+        Ok(())
+    }
+
+    fn append_mean(&self, mean: &MeanData) -> io::Result<()> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection.prepare_cached(
+            "INSERT INTO means (symbol, window, start_time, end_time, mean_price, transactions, partial)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+        ).map_err(database_error)?;
+        statement.execute(params![
+            mean.symbol,
+            mean.window.to_string(),
+            mean.start_time.timestamp_millis(),
+            mean.end_time.timestamp_millis(),
+            mean.mean_price,
+            mean.transactions as i64,
+            mean.partial,
+        ]).map_err(database_error)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.insert_pending()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.insert_pending()?;
+        // the checkpoint syncs the write-ahead log to the database file
+        lock(&self.connection)?
+            .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))
+            .map_err(database_error)
+    }
+
+    fn trades_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> io::Result<Vec<RollingData>> {
+        self.insert_pending()?;
+        let connection = lock(&self.connection)?;
+        let mut statement = connection.prepare_cached(
+            "SELECT symbol, price, timestamp, write_timestamp, volume, conditions FROM trades
+             WHERE symbol = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY rowid"
+        ).map_err(database_error)?;
+        let rows = statement.query_map(params![self.symbol, start.timestamp_millis(), end.timestamp_millis()], |row| {
+            Ok(RollingData {
+                symbol: row.get(0)?,
+                price: row.get(1)?,
+                timestamp: Utc.timestamp_millis(row.get(2)?),
+                write_timestamp: Utc.timestamp_millis(row.get(3)?),
+                volume: row.get(4)?,
+                conditions: condition::split_codes(&row.get::<_, String>(5)?),
+            })
+        }).map_err(database_error)?;
+        rows.collect::<rusqlite::Result<Vec<RollingData>>>().map_err(database_error)
+    }
+}
+
+
+#[cfg(test)]
+mod store_test {
+    use std::fs::{self, remove_file, File};
+    use std::io::Write;
+    use std::path::Path;
+    use chrono::{Duration, TimeZone, Utc};
+    use flate2::{write::GzEncoder, Compression};
+    use serial_test::serial;
+    use crate::candlestick::Candlestick;
+    use crate::journal::StorageFormat;
+    use crate::stock_handle::MapperOptions;
+    use crate::store::{FileStore, FsyncPolicy, SqliteStore, Store};
+    use crate::utils::create_dirs;
+    use crate::RollingData;
+
+    /// Returns three trades of the last minutes, with timestamps in milliseconds like the stores keep them
+    fn trades(symbol: &str) -> Vec<RollingData> {
+        let now = Utc.timestamp_millis(Utc::now().timestamp_millis());
+        (0..3).map(|i| RollingData {
+            symbol: symbol.to_string(),
+            price: 172.5 + i as f64,
+            timestamp: now - Duration::minutes(10 - i),
+            write_timestamp: now,
+            volume: 1.0 + i as f64,
+            conditions: (0..i).map(|c| c.to_string()).collect(),
+        }).collect()
+    }
+
+    /// Returns the path of the file of the current date of a stock under the given directory
+    fn today(dir: &str, name: &str) -> String {
+        format!("{}/{}/{}", dir, Utc::now().format("%Y-%m-%d"), name)
+    }
+
+    fn query_back(store: &dyn Store, symbol: &str) {
+        let trades = trades(symbol);
+        trades.iter().for_each(|t| store.append_trade(t).unwrap());
+        assert_eq!(store.trades_between(trades[0].timestamp, trades[2].timestamp + Duration::milliseconds(1)).unwrap(), trades);
+        assert_eq!(store.trades_between(trades[1].timestamp, trades[2].timestamp).unwrap(), trades[1..2].to_vec());
+        assert!(store.trades_between(trades[2].timestamp + Duration::seconds(1), Utc::now() + Duration::minutes(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_csv_store_should_query_back_the_trades_it_wrote() {
+        let store = FileStore::open("STORE:CSV", &MapperOptions::default()).unwrap();
+        query_back(&store, "STORE:CSV");
+        remove_file(today("data/rolling", "STORE_CSV.csv")).unwrap();
+        remove_file(today("data/candlestick/1m", "STORE_CSV.csv")).unwrap();
+        remove_file(today("data/mean", "STORE_CSV.csv")).unwrap();
+    }
+
+    #[test]
+    fn the_bincode_store_should_query_back_the_trades_it_wrote() {
+        let options = MapperOptions { storage_format: StorageFormat::Bincode, ..MapperOptions::default() };
+        let store = FileStore::open("STORE:BIN", &options).unwrap();
+        query_back(&store, "STORE:BIN");
+        remove_file(today("data/rolling", "STORE_BIN.bin")).unwrap();
+        remove_file(today("data/candlestick/1m", "STORE_BIN.csv")).unwrap();
+        remove_file(today("data/mean", "STORE_BIN.csv")).unwrap();
+    }
+
+    #[test]
+    fn the_file_store_should_query_the_compressed_partitions_too() {
+        let date = Utc.ymd(2022, 7, 21).and_hms(22, 7, 38);
+        let _ = create_dirs("data/rolling/2022-07-21");
+        let old = RollingData {
+            symbol: "STORE:GZ".to_string(),
+            price: 23061.05,
+            timestamp: date,
+            write_timestamp: date,
+            volume: 0.0,
+            conditions: vec![],
+        };
+        let mut encoder = GzEncoder::new(File::create("data/rolling/2022-07-21/STORE_GZ.csv.gz").unwrap(), Compression::default());
+        encoder.write_all(b"Symbol,Price,Timestamp,WriteTimestamp,Volume,Conditions\nSTORE:GZ,23061.05,1658441258000,1658441258000,0,\n").unwrap();
+        encoder.finish().unwrap();
+        let store = FileStore::open("STORE:GZ", &MapperOptions::default()).unwrap();
+        assert_eq!(store.trades_between(date, date + Duration::seconds(1)).unwrap(), vec![old]);
+        remove_file("data/rolling/2022-07-21/STORE_GZ.csv.gz").unwrap();
+        remove_file(today("data/rolling", "STORE_GZ.csv")).unwrap();
+        remove_file(today("data/candlestick/1m", "STORE_GZ.csv")).unwrap();
+        remove_file(today("data/mean", "STORE_GZ.csv")).unwrap();
+    }
+
+    #[test]
+    fn given_a_poisoned_file_the_file_store_should_fail_instead_of_panicking() {
+        let options = MapperOptions { data_dir: "tmp/store_poisoned".into(), ..MapperOptions::default() };
+        let store = FileStore::open("STORE:POISON", &options).unwrap();
+        let _ = std::thread::scope(|scope| scope.spawn(|| {
+            let _mean = store.mean.lock().unwrap();
+            panic!("a worker panicked while writing the mean");
+        }).join());
+        assert!(store.sync().is_err());
+        store.append_trades(&trades("STORE:POISON")).unwrap();
+        drop(store);
+        fs::remove_dir_all("tmp/store_poisoned").unwrap();
+    }
+
+    #[test]
+    fn it_should_parse_the_fsync_policies() {
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert_eq!("interval".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Interval);
+        assert_eq!("always".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Always);
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+        assert_eq!(FsyncPolicy::Interval.to_string(), "interval");
+    }
+
+    #[test]
+    fn given_a_flush_interval_the_file_store_should_buffer_the_trades_until_flushed() {
+        let options = MapperOptions { flush_interval: Some(std::time::Duration::from_secs(1)), fsync: FsyncPolicy::Always, ..MapperOptions::default() };
+        let store = FileStore::open("STORE:BUF", &options).unwrap();
+        let path = today("data/rolling", "STORE_BUF.csv");
+        let trades = trades("STORE:BUF");
+        store.append_trades(&trades[..2]).unwrap();
+        store.append_trade(&trades[2]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        store.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+        // dropping the store writes what is still buffered
+        store.append_trade(&trades[0]).unwrap();
+        drop(store);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 5);
+        remove_file(path).unwrap();
+        remove_file(today("data/candlestick/1m", "STORE_BUF.csv")).unwrap();
+        remove_file(today("data/mean", "STORE_BUF.csv")).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn the_sqlite_store_should_query_back_the_trades_of_its_stock() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/store.sqlite");
+        let store = SqliteStore::open(path, "STORE:SQL", &MapperOptions::default()).unwrap();
+        let other = SqliteStore::open(path, "STORE:OTHER", &MapperOptions::default()).unwrap();
+        trades("STORE:OTHER").iter().for_each(|t| other.append_trade(t).unwrap());
+        query_back(&store, "STORE:SQL");
+        let candle = Candlestick { stock_symbol: "STORE:SQL".to_string(), total_transactions: 3, news: true, ..Candlestick::default() };
+        store.append_candle("1m".parse().unwrap(), &candle).unwrap();
+        drop(store);
+        drop(other);
+        let connection = rusqlite::Connection::open(path).unwrap();
+        let (interval, transactions, news): (String, i64, bool) = connection.query_row(
+            "SELECT interval, transactions, news FROM candlesticks WHERE symbol = 'STORE:SQL'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).unwrap();
+        assert_eq!((interval.as_str(), transactions, news), ("1m", 3, true));
+        drop(connection);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("tmp/store.sqlite{}", suffix));
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn the_sqlite_store_should_add_the_news_column_to_an_older_database() {
+        let _ = create_dirs("tmp");
+        let path = Path::new("tmp/store_old.sqlite");
+        let connection = rusqlite::Connection::open(path).unwrap();
+        connection.execute_batch(
+            "CREATE TABLE candlesticks (symbol TEXT NOT NULL, interval TEXT NOT NULL, timestamp INTEGER NOT NULL,
+                open REAL NOT NULL, close REAL NOT NULL, high REAL NOT NULL, low REAL NOT NULL, transactions INTEGER NOT NULL,
+                partial INTEGER NOT NULL, volume REAL NOT NULL, vwap REAL NOT NULL, turnover REAL NOT NULL,
+                corrected INTEGER NOT NULL, late_trades INTEGER NOT NULL);
+             INSERT INTO candlesticks VALUES ('STORE:OLD', '1m', 0, 1, 1, 1, 1, 1, 0, 1, 1, 1, 0, 0);"
+        ).unwrap();
+        drop(connection);
+        let store = SqliteStore::open(path, "STORE:OLD", &MapperOptions::default()).unwrap();
+        store.append_candle("1m".parse().unwrap(), &Candlestick { stock_symbol: "STORE:OLD".to_string(), news: true, ..Candlestick::default() }).unwrap();
+        drop(store);
+        let connection = rusqlite::Connection::open(path).unwrap();
+        let news: Vec<bool> = connection.prepare("SELECT news FROM candlesticks ORDER BY rowid").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .map(|news| news.unwrap())
+            .collect();
+        assert_eq!(news, vec![false, true]);
+        drop(connection);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("tmp/store_old.sqlite{}", suffix));
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, File, OpenOptions};
 use std::io;
 use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use regex::Regex;
+use serde::Serialize;
+use crate::error::Result;
 use crate::{RollingData};
 
 /// Given a string containing special characters, it will return the original
@@ -53,9 +56,59 @@ pub fn create_dirs(p: &str) -> bool {
     }
 }
 
-/// Given a file, this returns whether it is empty or not by reading it to the end.
-/// If the buffer is empty, it returns true, and false otherwise. An error may occur
-/// if the file doesn't contain valid UTF-8 characters
+/// Given the path of a file, it opens it for appending and reading, creating it
+/// if it doesn't exist
+///
+/// # Arguments
+/// - `path` - The path of the file to open
+pub fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .read(true)
+        .open(path)
+}
+
+/// Logs why a file couldn't be opened or created
+pub fn log_open_error(err: &io::Error) {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => eprintln!("Cannot create a file due to permission reasons"),
+        _ => eprintln!("Couldn't create file"),
+    }
+}
+
+/// Given the path of a log file, such as the tick log, it opens it for appending and
+/// reading, creating it if it doesn't exist. It returns None if it can't, after logging why.
+///
+/// # Example
+/// ```
+/// use std::path::Path;
+/// use finnhub_ws::utils::open_log;
+/// assert!(open_log(Path::new("data/no-such-dir/log.csv")).is_none());
+/// ```
+pub fn open_log(path: &Path) -> Option<File> {
+    open_append(path).map_err(|err| log_open_error(&err)).ok()
+}
+
+/// Appends the rows to the csv file. The headers are written only when the file is empty,
+/// so that they appear once however many times the file gets appended to.
+///
+/// # Arguments
+/// - `file` - The csv file to append the rows to, opened for appending
+/// - `rows` - The rows to append
+pub fn append_csv<T: Serialize>(file: &File, rows: impl IntoIterator<Item = T>) -> Result<()> {
+    let empty = file.metadata()?.len() == 0;
+    let mut writer = csv::WriterBuilder::new().has_headers(empty).from_writer(file);
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Given a file, this returns whether it is empty or not by reading from it.
+/// If nothing can be read, it returns true, and false otherwise. The error of
+/// the read gets returned if it fails
 ///
 /// # Arguments
 /// `f` - A file descriptor pointing to the file to be tested
@@ -72,16 +125,12 @@ pub fn create_dirs(p: &str) -> bool {
 ///     .create(true)
 ///     .read(true)
 ///     .open("tmp/file").unwrap();
-/// let is_empty = is_file_empty(f);
+/// let is_empty = is_file_empty(f).unwrap();
 /// assert_eq!(is_empty, true);
 /// std::fs::remove_file("tmp/file").unwrap();
 /// ```
-pub fn is_file_empty(f: File) -> bool {
-    let mut reader = BufReader::new(f);
-    let mut data = Vec::new();
-    let l = reader.read_to_end(&mut data).expect("stream did not contain valid UTF-8");
-    println!("{:?} {:?}", data, l);
-    !!data.is_empty()
+pub fn is_file_empty(mut f: File) -> io::Result<bool> {
+    Ok(f.read(&mut [0u8; 1])? == 0)
 }
 
 /// Given a file, a timestamp and a delta of time, it returns all matching records from the file.
@@ -90,7 +139,7 @@ pub fn is_file_empty(f: File) -> bool {
 ///
 /// # Arguments
 /// - `file` - A mutable reference to a file from which the records should be obtained. The mutability here
-///            is necessary to seek back to the start of the file
+///   is necessary to seek back to the start of the file
 /// - `time` - A datetime timestamp given in milliseconds
 /// - `l` - The delta of time between the two timestamps to search
 ///
@@ -113,26 +162,50 @@ pub fn is_file_empty(f: File) -> bool {
 /// BINANCE:BTCUSDT,23061.04,1658441258362,1658441270795").unwrap();
 /// f.sync_all().unwrap();///
 /// let mut items: Vec<finnhub_ws::RollingData> = Vec::with_capacity(10);
-/// find_items(&mut f, 1658441330, 1, &mut items);
+/// find_items(&mut f, 1658441330, 1, &mut items).unwrap();
 /// assert_eq!(items, vec![///
-///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794) },
-///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.16, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794) },
-///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.04, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 795) },
+///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794), volume: 0.0, conditions: vec![] },
+///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.16, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794), volume: 0.0, conditions: vec![] },
+///     finnhub_ws::RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.04, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 795), volume: 0.0, conditions: vec![] },
 /// ]);
 /// std::fs::remove_file("tmp/find_items.csv").unwrap();
 /// ```
-pub fn find_items(file: &mut File, time: i64, l: i64, records: &mut Vec<RollingData>) {
+pub fn find_items(file: &mut File, time: i64, l: i64, records: &mut Vec<RollingData>) -> io::Result<()> {
     let datetime_max: DateTime<Utc> = DateTime::from_utc(NaiveDateTime::from_timestamp(time, 0), Utc);
     let datetime_min: DateTime<Utc> = datetime_max - chrono::Duration::minutes(l);
-    file.seek(SeekFrom::Start(0)).unwrap();
+    find_items_between(file, datetime_min, datetime_max, records)
+}
+
+/// Given a file and two datetimes, it returns all records from the file that were written
+/// to it between `start` (inclusive) and `end` (exclusive). The rows which can't be parsed
+/// get skipped.
+///
+/// # Arguments
+/// - `file` - A mutable reference to a file from which the records should be obtained. The mutability here
+///   is necessary to seek back to the start of the file
+/// - `start` - The earliest write datetime of the records to return
+/// - `end` - The datetime the records should have been written before
+///
+/// It fails if the file can't be seeked back to its start.
+pub fn find_items_between(file: &mut File, start: DateTime<Utc>, end: DateTime<Utc>, records: &mut Vec<RollingData>) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
     let buf = BufReader::new(file);
-    let mut reader = csv::ReaderBuilder::new().from_reader(buf);
+    // rolling files written before the volume column was added have one field less
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(buf);
     for record in reader.deserialize(){
-        let record: RollingData = record.unwrap();
-        if record.write_timestamp.ge(&datetime_min) && record.write_timestamp.lt(&datetime_max){
+        // a row left partial by a crash shouldn't keep the rest of the file from being read
+        let record: RollingData = match record {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("Skipping an invalid row: {}", e);
+                continue;
+            }
+        };
+        if record.write_timestamp.ge(&start) && record.write_timestamp.lt(&end){
             records.push(record);
         }
     }
+    Ok(())
 }
 
 
@@ -140,7 +213,7 @@ pub fn find_items(file: &mut File, time: i64, l: i64, records: &mut Vec<RollingD
 mod utils_test {
     use crate::utils::{create_dirs, find_items, is_file_empty, sanitize_string};
     use std::fs::{File, OpenOptions, remove_dir_all, remove_file};
-    use std::io::{Read, Write};
+    use std::io::Write;
     use chrono::{TimeZone, Utc};
     use serial_test::serial;
     use crate::RollingData;
@@ -148,7 +221,6 @@ mod utils_test {
 
     fn create_file(s: &str) -> File {
         OpenOptions::new()
-            .write(true)
             .append(true)
             .create(true)
             .read(true)
@@ -179,7 +251,7 @@ mod utils_test {
     fn check_create_dirs() {
         let path = "./tmp/data";
         let got = create_dirs(path);
-        assert_eq!(got, true);
+        assert!(got);
         remove_dir_all(path).unwrap();
     }
 
@@ -187,7 +259,7 @@ mod utils_test {
     fn try_create_dir_in_root() {
         let path = "/finnhub-ws";
         let got = create_dirs(path);
-        assert_eq!(got, false)
+        assert!(!got)
     }
 
     #[test]
@@ -200,8 +272,8 @@ mod utils_test {
         // TODO: implement the test in a sane manner.
         drop(file);
         let file = create_file(file_name);
-        let got = is_file_empty(file);
-        assert_eq!(got, false);
+        let got = is_file_empty(file).unwrap();
+        assert!(!got);
         remove_file(file_name).unwrap();
         remove_dir_all("test").unwrap();
     }
@@ -212,14 +284,14 @@ mod utils_test {
         let file_name = "test/empty.txt";
         create_dirs("test");
         let file = create_file(file_name);
-        let got = is_file_empty(file);
-        assert_eq!(got, true);
+        let got = is_file_empty(file).unwrap();
+        assert!(got);
         remove_file(file_name).unwrap();
         remove_dir_all("test").unwrap();
     }
 
     fn write_mock_data_to_file(f: &mut File) {
-        f.write(b"Symbol,Price,Timestamp,WriteTimestamp
+        f.write_all(b"Symbol,Price,Timestamp,WriteTimestamp
 BINANCE:BTCUSDT,23061.05,1658441258376,1658441270794
 BINANCE:BTCUSDT,23060.16,1658441258197,1658441270794
 BINANCE:BTCUSDT,23061.04,1658441258362,1658441270795
@@ -238,22 +310,39 @@ BINANCE:BTCUSDT,23061.79,1658441258466,1658441271009").unwrap();
         let mut file = create_file(file_name);
         write_mock_data_to_file(&mut file);
         let mut got: Vec<RollingData> = Vec::with_capacity(10);
-        find_items(&mut file, 1658441330, 1, &mut got);
+        find_items(&mut file, 1658441330, 1, &mut got).unwrap();
         let expected = vec![
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.16, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.04, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 795) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.88, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 330), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 797) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 797) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.89, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 340), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 814) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23058.59, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 404), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 51, 8) },
-            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.79, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 466), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 51, 9) },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.16, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 197), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.04, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 795), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.88, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 330), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 797), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.05, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 362), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 797), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23060.89, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 340), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 814), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23058.59, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 404), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 51, 8), volume: 0.0, conditions: vec![] },
+            RollingData { symbol: "BINANCE:BTCUSDT".parse().unwrap(), price: 23061.79, timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 466), write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 51, 9), volume: 0.0, conditions: vec![] },
         ];
         assert_eq!(got, expected);
         remove_file(file_name).unwrap();
         remove_dir_all("test").unwrap();
     }
 
+    #[test]
+    #[serial]
+    fn given_a_partial_row_expect_it_to_be_skipped() {
+        let file_name = "test/test_partial.csv";
+        create_dirs("test");
+        let mut file = create_file(file_name);
+        file.write_all(b"Symbol,Price,Timestamp,WriteTimestamp
+BINANCE:BTCUSDT,23061.05,16584BINANCE:BTCUSDT,23060.16,1658441258197,1658441270794
+BINANCE:BTCUSDT,23061.04,1658441258362,1658441270795").unwrap();
+        let mut got: Vec<RollingData> = Vec::with_capacity(10);
+        find_items(&mut file, 1658441330, 1, &mut got).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].price, 23061.04);
+        remove_file(file_name).unwrap();
+        remove_dir_all("test").unwrap();
+    }
+
     #[test]
     #[serial]
     fn given_empty_file_expect_empty_vec() {
@@ -261,7 +350,7 @@ BINANCE:BTCUSDT,23061.79,1658441258466,1658441271009").unwrap();
         create_dirs("test");
         let mut file = create_file(file_name);
         let mut got: Vec<RollingData> = Vec::with_capacity(10);
-        find_items(&mut file, 1658441258, 1, &mut got);
+        find_items(&mut file, 1658441258, 1, &mut got).unwrap();
         let expected = vec![];
         assert_eq!(got, expected);
         remove_file(file_name).unwrap();
@@ -276,7 +365,7 @@ BINANCE:BTCUSDT,23061.79,1658441258466,1658441271009").unwrap();
         let mut file = create_file(file_name);
         write_mock_data_to_file(&mut file);
         let mut got: Vec<RollingData> = Vec::with_capacity(10);
-        find_items(&mut file, 1658860842, 1, &mut got);
+        find_items(&mut file, 1658860842, 1, &mut got).unwrap();
         let expected = vec![];
         assert_eq!(got, expected);
         remove_file(file_name).unwrap();
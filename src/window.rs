@@ -0,0 +1,147 @@
+//! Trade window primitives
+//! # window
+//!
+//! This contains the in-memory window of the most recent trades of a stock.
+//! The candlestick and mean data get calculated from it, so the rolling file
+//! never has to be read back while the program runs.
+use std::collections::VecDeque;
+use chrono::{DateTime, Duration, Utc};
+use crate::RollingData;
+
+/// `TradeWindow` keeps the trades of a stock which were written during the last `span`
+/// in insertion order. Older trades get evicted as newer ones arrive, so the memory it
+/// needs is bounded by the trade rate instead of growing during the day.
+///
+/// # Example
+/// ```
+/// use chrono::{Duration, TimeZone, Utc};
+/// use finnhub_ws::{window::TradeWindow, RollingData};
+/// let mut window = TradeWindow::new(Duration::minutes(15));
+/// window.push(RollingData{
+///     price: 172.5,
+///     symbol: "AAPL".parse().unwrap(),
+///     timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 38, 376),
+///     write_timestamp: Utc.ymd(2022, 7, 21).and_hms_milli(22, 7, 50, 794),
+///     volume: 0.5,
+///     conditions: vec![]
+/// });
+/// let mut items = Vec::new();
+/// window.between(Utc.ymd(2022, 7, 21).and_hms(22, 7, 0), Utc.ymd(2022, 7, 21).and_hms(22, 8, 0), &mut items);
+/// assert_eq!(items.len(), 1);
+/// ```
+#[derive(Debug)]
+pub struct TradeWindow {
+    /// span: how far back from the latest trade the window reaches
+    span: Duration,
+    /// trades: the trades in the window ordered by their write timestamp
+    trades: VecDeque<RollingData>,
+}
+
+impl TradeWindow {
+    /// Given the span of time the window should cover, creates and returns an empty TradeWindow
+    pub fn new(span: Duration) -> Self {
+        TradeWindow {
+            span,
+            trades: VecDeque::with_capacity(1000),
+        }
+    }
+
+    /// Appends a trade to the window and evicts the trades that fell out of it
+    pub fn push(&mut self, trade: RollingData) {
+        let oldest = trade.write_timestamp - self.span;
+        self.trades.push_back(trade);
+        self.evict_before(oldest);
+    }
+
+    /// Evicts all the trades written before the given datetime
+    pub fn evict_before(&mut self, time: DateTime<Utc>) {
+        while self.pop_before(time).is_some() {}
+    }
+
+    /// Removes and returns the oldest trade if it was written before the given datetime
+    pub fn pop_before(&mut self, time: DateTime<Utc>) -> Option<RollingData> {
+        match self.trades.front() {
+            Some(trade) if trade.write_timestamp < time => self.trades.pop_front(),
+            _ => None,
+        }
+    }
+
+    /// Returns an iterator over the trades in the window from the oldest to the newest
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &RollingData> {
+        self.trades.iter()
+    }
+
+    /// Copies to `records` the trades written between `start` (inclusive) and `end` (exclusive)
+    pub fn between(&self, start: DateTime<Utc>, end: DateTime<Utc>, records: &mut Vec<RollingData>) {
+        records.extend(self.trades.iter()
+            .filter(|t| t.write_timestamp >= start && t.write_timestamp < end)
+            .cloned());
+    }
+
+    /// Returns the trade at the given position, counting from the oldest one
+    pub fn get(&self, index: usize) -> Option<&RollingData> {
+        self.trades.get(index)
+    }
+
+    /// Returns the number of trades in the window
+    pub fn len(&self) -> usize {
+        self.trades.len()
+    }
+
+    /// Returns true if there are no trades in the window
+    pub fn is_empty(&self) -> bool {
+        self.trades.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod window_test {
+    use chrono::{Duration, TimeZone, Utc};
+    use crate::window::TradeWindow;
+    use crate::RollingData;
+
+    fn trade(price: f64, minute: u32, second: u32) -> RollingData {
+        RollingData {
+            symbol: "BINANCE:BTCUSDT".parse().unwrap(),
+            price,
+            timestamp: Utc.ymd(2022, 7, 21).and_hms(22, minute, second),
+            write_timestamp: Utc.ymd(2022, 7, 21).and_hms(22, minute, second),
+            volume: 1.0,
+            conditions: vec![],
+        }
+    }
+
+    #[test]
+    fn given_trades_older_than_the_span_it_should_evict_them() {
+        let mut window = TradeWindow::new(Duration::minutes(15));
+        window.push(trade(1.0, 0, 0));
+        window.push(trade(2.0, 10, 0));
+        assert_eq!(window.len(), 2);
+        window.push(trade(3.0, 16, 0));
+        assert_eq!(window.len(), 2);
+        let mut items = Vec::new();
+        window.between(Utc.ymd(2022, 7, 21).and_hms(22, 0, 0), Utc.ymd(2022, 7, 21).and_hms(23, 0, 0), &mut items);
+        assert_eq!(items, vec![trade(2.0, 10, 0), trade(3.0, 16, 0)]);
+    }
+
+    #[test]
+    fn given_a_range_it_should_exclude_its_end() {
+        let mut window = TradeWindow::new(Duration::minutes(15));
+        window.push(trade(1.0, 7, 0));
+        window.push(trade(2.0, 7, 59));
+        window.push(trade(3.0, 8, 0));
+        let mut items = Vec::new();
+        window.between(Utc.ymd(2022, 7, 21).and_hms(22, 7, 0), Utc.ymd(2022, 7, 21).and_hms(22, 8, 0), &mut items);
+        assert_eq!(items, vec![trade(1.0, 7, 0), trade(2.0, 7, 59)]);
+    }
+
+    #[test]
+    fn given_an_empty_window_it_should_return_nothing() {
+        let window = TradeWindow::new(Duration::minutes(1));
+        let mut items = Vec::new();
+        window.between(Utc.ymd(2022, 7, 21).and_hms(22, 7, 0), Utc.ymd(2022, 7, 21).and_hms(22, 8, 0), &mut items);
+        assert!(window.is_empty());
+        assert!(items.is_empty());
+    }
+}